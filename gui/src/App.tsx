@@ -42,7 +42,7 @@ import DefaultsPanel, { type DefaultsPanelProps } from "./components/DefaultsPan
 import useState from "react-usestateref";
 import { useEffect } from "react";
 import { Validator } from "ip-num";
-import { InsertIntoSortedMap, isLoopBack, CaseInsensitiveSort, hexEncode, hexDecode } from "./ts/utils.ts";
+import { InsertIntoSortedMap, isLoopBack, CaseInsensitiveSort, hexEncode, hexDecode, AssignRuleIds } from "./ts/utils.ts";
 import {
   InitTextDialogState,
   TextDialogDefaultProps,
@@ -1288,6 +1288,7 @@ function App() {
   function Config2json(): Configuration {
     let filtertosave: CustomRulesSaveType = { filtertables: [], dragpos: refCustomRules.current.dragpos };
     refCustomRules.current.rules.forEach(function (table, chain) {
+      AssignRuleIds(table.rules);
       let tabletosave: FilterTableSaveType = {
         chain: chain,
         policy: table.defaultpolicy,
@@ -1298,6 +1299,7 @@ function App() {
     });
     let dnattosave: NatRulesSaveType = { nattables: [], dragpos: refDNatRules.current.dragpos };
     refDNatRules.current.rules.forEach(function (table, chain) {
+      AssignRuleIds(table.rules);
       let tabletosave: NatTableSaveType = {
         chain: chain,
         policy: table.defaultpolicy,
@@ -1308,6 +1310,7 @@ function App() {
     });
     let snattosave: NatRulesSaveType = { nattables: [], dragpos: refSNatRules.current.dragpos };
     refSNatRules.current.rules.forEach(function (table, chain) {
+      AssignRuleIds(table.rules);
       let tabletosave: NatTableSaveType = {
         chain: chain,
         policy: table.defaultpolicy,
@@ -21,6 +21,7 @@ export interface FilterRule {
   action: string;
   comment: string;
   active: boolean;
+  id?: string;
 }
 
 export interface FilterTableProps {
@@ -22,6 +22,7 @@ export interface NatRule {
   translatedservice: string;
   comment: string;
   active: boolean;
+  id?: string;
 }
 
 export interface NatTableProps {
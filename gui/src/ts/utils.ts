@@ -28,6 +28,31 @@ export function InsertIntoMap<T>(map: Map<string, T>, key: string, entry: T): vo
   }
 }
 
+// ids the webserver keeps, as in assign_rule_ids of the webserver
+function validRuleId(id: string | undefined): id is string {
+  return id !== undefined && /^[A-Za-z0-9_-]{1,32}$/.test(id);
+}
+
+// give rules without an id, with an invalid one or with the id of an earlier rule the next
+// free number of their chain, the id stays with the rule when other rules are inserted or
+// removed
+export function AssignRuleIds(rules: { id?: string }[]): void {
+  let next = 1;
+  for (const rule of rules) {
+    if (rule.id !== undefined && /^[0-9]+$/.test(rule.id)) {
+      next = Math.max(next, Number(rule.id) + 1);
+    }
+  }
+  const seen = new Set<string>();
+  for (const rule of rules) {
+    if (!validRuleId(rule.id) || seen.has(rule.id)) {
+      rule.id = String(next);
+      next++;
+    }
+    seen.add(rule.id);
+  }
+}
+
 export function isLoopBack(ip: string): boolean {
   if (ip.includes(":")) {
     // IPv6 check
@@ -0,0 +1,348 @@
+use crate::executor::Executor;
+use crate::{confirm, files, history, ruleset, sandbox, trace};
+use hex::ToHex;
+use openssl::sha::sha256;
+use protocol::{CHECK_FILE, ConntrackTuple, HelperStatus, Request, Response};
+use std::io::Write;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+const CONNTRACK: &str = "conntrack";
+
+// Files and programs the commands work with, and the executor that runs the programs
+#[derive(Clone)]
+pub struct Context {
+    pub nft: String,
+    // file the script to install is written to before it is checked
+    pub test: String,
+    // the installed ruleset, loaded by the reload command
+    pub conf: String,
+    pub reload: String,
+    // this program, it runs the verification sandbox as a separate process
+    pub program: String,
+    pub executor: Arc<dyn Executor>,
+}
+
+// Check the arguments of a conntrack delete command before passing them on
+fn conntrack_delete_args(tuple: &ConntrackTuple) -> Result<Vec<String>, String> {
+    let protocol = tuple.protocol.as_str();
+    if !["tcp", "udp", "icmp", "icmpv6"].contains(&protocol) {
+        return Err(format!("Invalid protocol {}", protocol));
+    }
+    let src = tuple
+        .src
+        .parse::<IpAddr>()
+        .map_err(|_e| format!("Invalid address {}", tuple.src))?;
+    let dst = tuple
+        .dst
+        .parse::<IpAddr>()
+        .map_err(|_e| format!("Invalid address {}", tuple.dst))?;
+    let family = if src.is_ipv6() { "ipv6" } else { "ipv4" };
+    let mut ctargs: Vec<String> = vec![
+        String::from("-D"),
+        String::from("-f"),
+        String::from(family),
+        String::from("-p"),
+        String::from(protocol),
+        String::from("-s"),
+        src.to_string(),
+        String::from("-d"),
+        dst.to_string(),
+    ];
+    for (flag, port) in [("--sport", tuple.sport), ("--dport", tuple.dport)] {
+        if let Some(p) = port {
+            ctargs.push(String::from(flag));
+            ctargs.push(p.to_string());
+        }
+    }
+    return Ok(ctargs);
+}
+
+// Delete connection tracking entries, conntrack fails for an entry that ended in the
+// meantime. Returns the number of deleted entries.
+fn conntrack_delete(ctx: &Context, ctargs: Vec<Vec<String>>) -> usize {
+    let mut count = 0;
+    for entry in ctargs.iter() {
+        let args: Vec<&str> = entry.iter().map(String::as_str).collect();
+        if run(ctx, CONNTRACK, &args).is_ok() {
+            count += 1;
+        }
+    }
+    return count;
+}
+
+// Run a program with the executor of the context, input is written to its standard
+// input. Returns the standard output and error output, a failing exit status returns
+// the exit code and error output.
+pub fn run_input(
+    ctx: &Context,
+    program: &str,
+    args: &[&str],
+    input: Option<&str>,
+) -> Result<(String, String), Response> {
+    match ctx.executor.run(program, args, input) {
+        Ok(output) if output.succeeded() => return Ok((output.stdout, output.stderr)),
+        Ok(output) => {
+            return Err(Response::Failed {
+                exit_code: output.exit_code,
+                stderr: output.stderr,
+            });
+        }
+        Err(e) => {
+            return Err(Response::Error {
+                message: format!("Could not run {}: {}", program, e),
+            });
+        }
+    }
+}
+
+// Run a command and return its standard output
+pub fn run(ctx: &Context, program: &str, args: &[&str]) -> Result<String, Response> {
+    let (stdout, _stderr) = run_input(ctx, program, args, None)?;
+    return Ok(stdout);
+}
+
+// Run nft -c -f <script> to check the syntax of a script, returns the warnings
+fn nft_check(ctx: &Context, script: &str) -> Result<String, Response> {
+    let (_stdout, stderr) = run_input(ctx, &ctx.nft, &["-c", "-f", script], None)?;
+    return Ok(stderr);
+}
+
+// Check the syntax of a script before it is installed, warnings are logged
+pub fn check_file(ctx: &Context, script: &str) -> Result<(), Response> {
+    let warnings = nft_check(ctx, script)?;
+    // warnings do not prevent the install
+    if warnings.len() > 0 {
+        eprintln!("{}", warnings);
+    }
+    return Ok(());
+}
+
+// Check a script in a private temporary file, nothing is installed. The warnings are
+// returned as text. Messages refer to the script as CHECK_FILE instead of the
+// temporary file.
+fn check_script(ctx: &Context, script: &str) -> Response {
+    let mut file = match tempfile::Builder::new()
+        .prefix("nftablesbuilder-check")
+        .suffix(".nft")
+        .tempfile()
+    {
+        Ok(f) => f,
+        Err(e) => {
+            return Response::Error {
+                message: format!("Could not create a temporary file: {}", e),
+            };
+        }
+    };
+    if let Err(e) = file.write_all(script.as_bytes()) {
+        return Response::Error {
+            message: format!("Could not write a temporary file: {}", e),
+        };
+    }
+    let path = file.path().to_string_lossy().to_string();
+    match nft_check(ctx, &path) {
+        Ok(warnings) => {
+            return Response::Text {
+                text: warnings.replace(&path, CHECK_FILE),
+            };
+        }
+        Err(Response::Failed { exit_code, stderr }) => {
+            return Response::Failed {
+                exit_code: exit_code,
+                stderr: stderr.replace(&path, CHECK_FILE),
+            };
+        }
+        Err(r) => return r,
+    }
+}
+
+// Write the script to the test file, check it, copy it to the conf file and reload
+// the nftables rules
+pub fn install(ctx: &Context, script: &str) -> Response {
+    if let Err(e) = files::write_atomic(&ctx.test, script.as_bytes()) {
+        return Response::Error { message: e };
+    }
+    if let Err(r) = check_file(ctx, &ctx.test) {
+        return r;
+    }
+    // syntax is correct, copy the test file to the conf file and reload
+    return copy_and_reload(ctx, &ctx.test);
+}
+
+// Copy a script to the conf file and reload the nftables rules
+pub fn copy_and_reload(ctx: &Context, script: &str) -> Response {
+    if let Err(e) = files::copy_atomic(script, &ctx.conf) {
+        return Response::Error { message: e };
+    }
+    // split the reload command into command and args, honouring shell quoting
+    let restart_args = match shell_words::split(&ctx.reload) {
+        Ok(args) if args.len() > 0 => args,
+        _ => {
+            return Response::Error {
+                message: String::from("Invalid reload command"),
+            };
+        }
+    };
+    let args: Vec<&str> = restart_args[1..].iter().map(String::as_str).collect();
+    match run(ctx, &restart_args[0], &args) {
+        Ok(_output) => return Response::Ok,
+        Err(r) => return r,
+    }
+}
+
+fn status(
+    ctx: &Context,
+    pending: &Arc<Mutex<confirm::Pending>>,
+    trace: &Arc<Mutex<trace::Trace>>,
+) -> HelperStatus {
+    return HelperStatus {
+        version: protocol::VERSION,
+        confirm_pending: confirm::is_pending(pending),
+        trace_running: trace::is_running(trace),
+        generation: history::latest(ctx),
+        conf_sha256: std::fs::read(&ctx.conf)
+            .ok()
+            .map(|script| sha256(&script).encode_hex::<String>()),
+    };
+}
+
+fn text_response(result: Result<String, String>) -> Response {
+    match result {
+        Ok(text) => return Response::Text { text: text },
+        Err(message) => return Response::Error { message: message },
+    }
+}
+
+fn result_response<T>(result: Result<T, Response>, ok: impl FnOnce(T) -> Response) -> Response {
+    match result {
+        Ok(value) => return ok(value),
+        Err(r) => return r,
+    }
+}
+
+// Execute an authenticated request
+pub fn handle(
+    request: Request,
+    ctx: &Context,
+    pending: &Arc<Mutex<confirm::Pending>>,
+    trace: &Arc<Mutex<trace::Trace>>,
+) -> Response {
+    match request {
+        Request::Check { script } => return check_script(ctx, &script),
+        Request::Install { script, user, name } => {
+            let result = install(ctx, &script);
+            if result.is_ok() {
+                history::archive_installed(ctx, &user, &name);
+            }
+            return result;
+        }
+        Request::InstallConfirm {
+            script,
+            seconds,
+            user,
+            name,
+        } => return confirm::install(pending, ctx, &script, seconds, &user, &name),
+        Request::Confirm => return confirm::confirm(pending, ctx),
+        Request::Rollback { user } => {
+            // an unconfirmed install is rolled back first
+            if let Some(result) = confirm::rollback_now(pending, ctx) {
+                return result;
+            }
+            return history::rollback_previous(ctx, &user);
+        }
+        Request::Verify {
+            script,
+            interfaces,
+            probes,
+        } => return sandbox::verify(ctx, &script, &interfaces, &probes),
+        Request::HistoryList => return text_response(Ok(history::list(ctx))),
+        Request::HistoryShow { generation } => {
+            return text_response(history::show(ctx, generation));
+        }
+        Request::HistoryRollback { generation, user } => {
+            return history::rollback(ctx, generation, &user);
+        }
+        Request::ListRuleset => {
+            // the active ruleset as json, including rule handles and comments
+            return result_response(
+                run(ctx, &ctx.nft, &["-j", "-a", "list", "ruleset"]),
+                |text| Response::Text { text },
+            );
+        }
+        Request::InstalledScript => {
+            return text_response(
+                std::fs::read_to_string(&ctx.conf)
+                    .map_err(|e| format!("Could not read {}: {}", ctx.conf, e)),
+            );
+        }
+        Request::ListCounters => {
+            return result_response(ruleset::list_counters(ctx), |counters| Response::Counters {
+                counters,
+            });
+        }
+        Request::ResetCounters { family, table } => {
+            return result_response(ruleset::reset_counters(ctx, &family, &table), |()| {
+                Response::Ok
+            });
+        }
+        Request::ListSetElements { family, table, set } => {
+            return result_response(
+                ruleset::list_set_elements(ctx, &family, &table, &set),
+                |elements| Response::SetElements { elements },
+            );
+        }
+        Request::AddSetElements {
+            family,
+            table,
+            set,
+            elements,
+        } => {
+            let result = ruleset::modify_set_elements(ctx, "add", &family, &table, &set, &elements);
+            return result_response(result, |()| Response::Ok);
+        }
+        Request::DeleteSetElements {
+            family,
+            table,
+            set,
+            elements,
+        } => {
+            let result =
+                ruleset::modify_set_elements(ctx, "delete", &family, &table, &set, &elements);
+            return result_response(result, |()| Response::Ok);
+        }
+        Request::TraceStart { seconds, trace: m } => {
+            return match trace::start(trace, ctx, seconds, &m) {
+                Ok(()) => Response::Ok,
+                Err(e) => Response::Error { message: e },
+            };
+        }
+        Request::TraceRead => return text_response(Ok(trace::read(trace))),
+        Request::TraceStop => {
+            return match trace::stop(trace, ctx) {
+                Ok(()) => Response::Ok,
+                Err(e) => Response::Error { message: e },
+            };
+        }
+        Request::ConntrackList => {
+            // the connection tracking table including the address family
+            return result_response(run(ctx, CONNTRACK, &["-L", "-o", "extended"]), |text| {
+                Response::Text { text }
+            });
+        }
+        Request::ConntrackDelete { entries } => {
+            // nothing is deleted unless all entries are valid
+            let ctargs = match entries.iter().map(conntrack_delete_args).collect() {
+                Ok(a) => a,
+                Err(e) => return Response::Error { message: e },
+            };
+            return Response::Deleted {
+                count: conntrack_delete(ctx, ctargs),
+            };
+        }
+        Request::Status => {
+            return Response::Status {
+                status: status(ctx, pending, trace),
+            };
+        }
+    }
+}
@@ -0,0 +1,129 @@
+use crate::commands::{Context, copy_and_reload, install as install_script};
+use crate::files::{copy_atomic, write_atomic};
+use crate::history::archive_installed;
+use protocol::Response;
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+const MAX_CONFIRM_SECONDS: u64 = 3600;
+
+// What a rollback installs when there was no conf file before the install
+const EMPTY_RULESET: &str = "flush ruleset\n";
+
+// An install that is rolled back unless it is confirmed in time
+#[derive(Default)]
+pub struct Pending {
+    active: bool,
+    // incremented on every install so an old timer does not roll back a newer install
+    generation: u64,
+}
+
+// The conf file as it was before the first unconfirmed install
+fn previous_path(ctx: &Context) -> String {
+    return format!("{}.previous", ctx.conf);
+}
+
+// Restore the previous conf file and reload it
+fn rollback(pending: &mut Pending, ctx: &Context) -> Response {
+    let previous = previous_path(ctx);
+    let result = copy_and_reload(ctx, &previous);
+    if result.is_ok() {
+        let _ = fs::remove_file(&previous);
+        archive_installed(ctx, "", "rollback of unconfirmed install");
+    }
+    pending.active = false;
+    return result;
+}
+
+// Install a script and restore the previous ruleset unless a confirm
+// request is received within the given time
+pub fn install(
+    pending: &Arc<Mutex<Pending>>,
+    ctx: &Context,
+    script: &str,
+    seconds: u64,
+    user: &str,
+    name: &str,
+) -> Response {
+    if seconds == 0 || seconds > MAX_CONFIRM_SECONDS {
+        return Response::Error {
+            message: format!("Confirm time must be 1 to {} seconds", MAX_CONFIRM_SECONDS),
+        };
+    }
+    let mut guard = pending.lock().unwrap();
+
+    // keep the last confirmed ruleset when an unconfirmed install is replaced
+    let previous = previous_path(ctx);
+    if !guard.active {
+        let kept = match Path::new(&ctx.conf).exists() {
+            true => copy_atomic(&ctx.conf, &previous),
+            false => write_atomic(&previous, EMPTY_RULESET.as_bytes()),
+        };
+        if let Err(e) = kept {
+            return Response::Error {
+                message: format!("Could not keep the previous ruleset: {}", e),
+            };
+        }
+    }
+    let result = install_script(ctx, script);
+    if !result.is_ok() {
+        if !guard.active {
+            let _ = fs::remove_file(&previous);
+        }
+        return result;
+    }
+    archive_installed(ctx, user, name);
+    guard.active = true;
+    guard.generation += 1;
+
+    let generation = guard.generation;
+    let timer_pending = Arc::clone(pending);
+    let timer_ctx = ctx.clone();
+    thread::spawn(move || {
+        thread::sleep(Duration::from_secs(seconds));
+        let mut guard = timer_pending.lock().unwrap();
+        if guard.active && guard.generation == generation {
+            let result = rollback(&mut guard, &timer_ctx);
+            eprintln!("Install was not confirmed, rollback: {}", result);
+        }
+    });
+    return result;
+}
+
+// Keep the installed ruleset
+pub fn confirm(pending: &Arc<Mutex<Pending>>, ctx: &Context) -> Response {
+    let mut guard = pending.lock().unwrap();
+    if !guard.active {
+        return Response::Error {
+            message: String::from("No install is waiting for confirmation"),
+        };
+    }
+    guard.active = false;
+    let _ = fs::remove_file(previous_path(ctx));
+    return Response::Ok;
+}
+
+// Roll back an unconfirmed install right away, None when no install is waiting
+pub fn rollback_now(pending: &Arc<Mutex<Pending>>, ctx: &Context) -> Option<Response> {
+    let mut guard = pending.lock().unwrap();
+    if !guard.active {
+        return None;
+    }
+    return Some(rollback(&mut guard, ctx));
+}
+
+pub fn is_pending(pending: &Arc<Mutex<Pending>>) -> bool {
+    return pending.lock().unwrap().active;
+}
+
+// Roll back an unconfirmed install when nftablesbuilder stops, the timer would not run anymore
+pub fn shutdown(pending: &Arc<Mutex<Pending>>, ctx: &Context) {
+    let mut guard = pending.lock().unwrap();
+    if guard.active {
+        let result = rollback(&mut guard, ctx);
+        eprintln!("Install was not confirmed, rollback: {}", result);
+    }
+}
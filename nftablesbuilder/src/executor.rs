@@ -0,0 +1,226 @@
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+// Result of a program that ran to completion
+#[derive(Clone, Debug, Default)]
+pub struct CommandOutput {
+    // None when the program was killed by a signal
+    pub exit_code: Option<i32>,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl CommandOutput {
+    pub fn success(stdout: &str) -> CommandOutput {
+        return CommandOutput {
+            exit_code: Some(0),
+            stdout: String::from(stdout),
+            stderr: String::new(),
+        };
+    }
+
+    pub fn failure(exit_code: i32, stderr: &str) -> CommandOutput {
+        return CommandOutput {
+            exit_code: Some(exit_code),
+            stdout: String::new(),
+            stderr: String::from(stderr),
+        };
+    }
+
+    pub fn succeeded(&self) -> bool {
+        return self.exit_code == Some(0);
+    }
+}
+
+// Output stream of a line printed by a spawned program
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+// Receives the lines of a spawned program as they are printed
+pub type LineSink = Arc<dyn Fn(Stream, String) + Send + Sync>;
+
+// A program that was started with spawn and keeps running until it is stopped
+pub trait Process: Send {
+    // Stop the program and wait for it
+    fn stop(&mut self);
+}
+
+// Runs the external programs of the commands: nft, conntrack and the reload command
+pub trait Executor: Send + Sync {
+    // Run a program to completion, input is written to its standard input
+    fn run(&self, program: &str, args: &[&str], input: Option<&str>) -> io::Result<CommandOutput>;
+
+    // Start a program that keeps running, like nft monitor, every line it prints is
+    // passed to sink
+    fn spawn(&self, program: &str, args: &[&str], sink: LineSink) -> io::Result<Box<dyn Process>>;
+}
+
+struct SystemProcess {
+    child: Child,
+}
+
+impl Process for SystemProcess {
+    fn stop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+// Pass the lines of a program output to sink until the program closes it
+fn forward_lines(output: impl Read + Send + 'static, stream: Stream, sink: LineSink) {
+    thread::spawn(move || {
+        for line in BufReader::new(output).lines() {
+            match line {
+                Ok(l) => sink(stream, l),
+                Err(_e) => break,
+            }
+        }
+    });
+}
+
+// Executor that runs the programs
+pub struct SystemExecutor;
+
+impl Executor for SystemExecutor {
+    fn run(&self, program: &str, args: &[&str], input: Option<&str>) -> io::Result<CommandOutput> {
+        let mut child = Command::new(program)
+            .args(args)
+            .stdin(match input {
+                Some(_i) => Stdio::piped(),
+                None => Stdio::null(),
+            })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        // the input is written from a thread, a program that writes a lot of output before
+        // it has read all of its input would otherwise block on the full output pipe
+        let writer = match (child.stdin.take(), input) {
+            (Some(mut stdin), Some(text)) => {
+                let text = String::from(text);
+                Some(thread::spawn(move || stdin.write_all(text.as_bytes())))
+            }
+            _ => None,
+        };
+        let output = child.wait_with_output()?;
+        if let Some(writer) = writer {
+            writer
+                .join()
+                .map_err(|_e| io::Error::other("Writing the program input failed"))??;
+        }
+        return Ok(CommandOutput {
+            exit_code: output.status.code(),
+            stdout: String::from_utf8_lossy(&output.stdout).to_string(),
+            stderr: String::from_utf8_lossy(&output.stderr).to_string(),
+        });
+    }
+
+    fn spawn(&self, program: &str, args: &[&str], sink: LineSink) -> io::Result<Box<dyn Process>> {
+        let mut child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        if let Some(stdout) = child.stdout.take() {
+            forward_lines(stdout, Stream::Stdout, Arc::clone(&sink));
+        }
+        if let Some(stderr) = child.stderr.take() {
+            forward_lines(stderr, Stream::Stderr, sink);
+        }
+        return Ok(Box::new(SystemProcess { child: child }));
+    }
+}
+
+// A command as it was given to the RecordingExecutor
+#[derive(Clone, Debug, PartialEq)]
+pub struct Call {
+    // program followed by its arguments
+    pub command: Vec<String>,
+    pub input: Option<String>,
+}
+
+// Executor for tests that records the commands instead of running them. Commands
+// that start with a configured prefix get its output, all others succeed without output.
+// Spawned programs print the lines of their output right away and then keep running
+// until they are stopped.
+#[derive(Default)]
+pub struct RecordingExecutor {
+    calls: Mutex<Vec<Call>>,
+    outputs: Mutex<Vec<(Vec<String>, CommandOutput)>>,
+    stopped: Arc<Mutex<usize>>,
+}
+
+struct RecordedProcess {
+    stopped: Arc<Mutex<usize>>,
+}
+
+impl Process for RecordedProcess {
+    fn stop(&mut self) {
+        *self.stopped.lock().unwrap() += 1;
+    }
+}
+
+impl RecordingExecutor {
+    pub fn new() -> RecordingExecutor {
+        return RecordingExecutor::default();
+    }
+
+    // Answer commands starting with prefix (program and arguments) with output,
+    // the first matching prefix is used
+    pub fn respond(&self, prefix: &[&str], output: CommandOutput) {
+        let prefix = prefix.iter().map(|p| String::from(*p)).collect();
+        self.outputs.lock().unwrap().push((prefix, output));
+    }
+
+    pub fn calls(&self) -> Vec<Call> {
+        return self.calls.lock().unwrap().clone();
+    }
+
+    // Number of spawned programs that were stopped
+    pub fn stopped(&self) -> usize {
+        return *self.stopped.lock().unwrap();
+    }
+
+    // Record a command and find its output
+    fn record(&self, program: &str, args: &[&str], input: Option<&str>) -> CommandOutput {
+        let mut command = vec![String::from(program)];
+        command.extend(args.iter().map(|a| String::from(*a)));
+        let output = self
+            .outputs
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(prefix, _output)| command.starts_with(prefix))
+            .map(|(_prefix, output)| output.clone())
+            .unwrap_or(CommandOutput::success(""));
+        self.calls.lock().unwrap().push(Call {
+            command: command,
+            input: input.map(String::from),
+        });
+        return output;
+    }
+}
+
+impl Executor for RecordingExecutor {
+    fn run(&self, program: &str, args: &[&str], input: Option<&str>) -> io::Result<CommandOutput> {
+        return Ok(self.record(program, args, input));
+    }
+
+    fn spawn(&self, program: &str, args: &[&str], sink: LineSink) -> io::Result<Box<dyn Process>> {
+        let output = self.record(program, args, None);
+        for line in output.stdout.lines() {
+            sink(Stream::Stdout, String::from(line));
+        }
+        for line in output.stderr.lines() {
+            sink(Stream::Stderr, String::from(line));
+        }
+        return Ok(Box::new(RecordedProcess {
+            stopped: Arc::clone(&self.stopped),
+        }));
+    }
+}
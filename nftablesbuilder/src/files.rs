@@ -0,0 +1,49 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+// Write a file atomically: the data is written and synced to a temporary file in
+// the same directory, which then replaces the target. A crash leaves either the
+// old or the new file, never a partly written one.
+pub fn write_atomic(path: &str, data: &[u8]) -> Result<(), String> {
+    let target = Path::new(path);
+    let dir = match target.parent() {
+        Some(d) if d.as_os_str().len() > 0 => d,
+        _ => Path::new("."),
+    };
+    let name = target
+        .file_name()
+        .ok_or(format!("Invalid file name {}", path))?
+        .to_string_lossy();
+    let temp = dir.join(format!(".{}.tmp", name));
+
+    let result = (|| -> std::io::Result<()> {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&temp)?;
+        // keep the permissions of the file that is replaced
+        if let Ok(meta) = fs::metadata(target) {
+            file.set_permissions(fs::Permissions::from_mode(meta.permissions().mode()))?;
+        }
+        file.write_all(data)?;
+        file.sync_all()?;
+        fs::rename(&temp, target)?;
+        // make the rename itself durable
+        File::open(dir)?.sync_all()?;
+        return Ok(());
+    })();
+    if let Err(e) = result {
+        let _ = fs::remove_file(&temp);
+        return Err(format!("Could not write {}: {}", path, e));
+    }
+    return Ok(());
+}
+
+// Copy a file atomically, see write_atomic
+pub fn copy_atomic(from: &str, to: &str) -> Result<(), String> {
+    let data = fs::read(from).map_err(|e| format!("Could not read {}: {}", from, e))?;
+    return write_atomic(to, &data);
+}
@@ -0,0 +1,145 @@
+use crate::commands::{Context, check_file, copy_and_reload};
+use crate::files::write_atomic;
+use hex::ToHex;
+use openssl::sha::sha256;
+use protocol::Response;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Number of installed rulesets that are kept
+const HISTORY_RETENTION: usize = 20;
+
+// Metadata of an installed ruleset, stored next to the script as <generation>.json
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Generation {
+    pub generation: u64,
+    // unix time of the install
+    pub timestamp: u64,
+    pub user: String,
+    pub name: String,
+    pub sha256: String,
+}
+
+fn history_dir(ctx: &Context) -> PathBuf {
+    return PathBuf::from(format!("{}.history", ctx.conf));
+}
+
+fn script_path(ctx: &Context, generation: u64) -> PathBuf {
+    return history_dir(ctx).join(format!("{}.nft", generation));
+}
+
+fn meta_path(ctx: &Context, generation: u64) -> PathBuf {
+    return history_dir(ctx).join(format!("{}.json", generation));
+}
+
+// All archived generations, oldest first
+fn generations(ctx: &Context) -> Vec<Generation> {
+    let mut result: Vec<Generation> = vec![];
+    if let Ok(entries) = fs::read_dir(history_dir(ctx)) {
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.extension().map(|e| e == "json").unwrap_or(false)
+                && let Ok(text) = fs::read_to_string(&path)
+                && let Ok(generation) = serde_json::from_str::<Generation>(&text)
+            {
+                result.push(generation);
+            }
+        }
+    }
+    result.sort_by_key(|g| g.generation);
+    return result;
+}
+
+// Archive the conf file that was just installed as a new generation
+pub fn archive(ctx: &Context, user: &str, name: &str) -> Result<u64, String> {
+    let script = fs::read(&ctx.conf).map_err(|e| format!("Could not read {}: {}", ctx.conf, e))?;
+    let dir = history_dir(ctx);
+    fs::create_dir_all(&dir).map_err(|e| format!("Could not create {}: {}", dir.display(), e))?;
+
+    let mut existing = generations(ctx);
+    let generation = existing.last().map(|g| g.generation + 1).unwrap_or(1);
+    let meta = Generation {
+        generation: generation,
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0),
+        user: String::from(user),
+        name: String::from(name),
+        sha256: sha256(&script).encode_hex::<String>(),
+    };
+    let script_file = script_path(ctx, generation).to_string_lossy().to_string();
+    let meta_file = meta_path(ctx, generation).to_string_lossy().to_string();
+    write_atomic(&script_file, &script)?;
+    write_atomic(&meta_file, serde_json::to_string(&meta).unwrap().as_bytes())?;
+
+    // remove the oldest generations beyond the retention limit
+    existing.push(meta);
+    while existing.len() > HISTORY_RETENTION {
+        let old = existing.remove(0);
+        let _ = fs::remove_file(script_path(ctx, old.generation));
+        let _ = fs::remove_file(meta_path(ctx, old.generation));
+    }
+    return Ok(generation);
+}
+
+// Archive after an install, a failing archive does not undo the install
+pub fn archive_installed(ctx: &Context, user: &str, name: &str) {
+    if let Err(e) = archive(ctx, user, name) {
+        eprintln!("{}", e);
+    }
+}
+
+// The archived generations as json
+pub fn list(ctx: &Context) -> String {
+    return serde_json::to_string(&generations(ctx)).unwrap();
+}
+
+fn check_generation(ctx: &Context, generation: u64) -> Result<(), String> {
+    if !script_path(ctx, generation).exists() {
+        return Err(format!("Generation {} does not exist", generation));
+    }
+    return Ok(());
+}
+
+// The archived script of a generation
+pub fn show(ctx: &Context, generation: u64) -> Result<String, String> {
+    check_generation(ctx, generation)?;
+    return fs::read_to_string(script_path(ctx, generation))
+        .map_err(|e| format!("Could not read generation {}: {}", generation, e));
+}
+
+// The last archived generation
+pub fn latest(ctx: &Context) -> Option<u64> {
+    return generations(ctx).last().map(|g| g.generation);
+}
+
+// Check and install an archived script
+pub fn rollback(ctx: &Context, generation: u64, user: &str) -> Response {
+    if let Err(e) = check_generation(ctx, generation) {
+        return Response::Error { message: e };
+    }
+    let path = script_path(ctx, generation);
+    let path = path.to_string_lossy().to_string();
+    if let Err(r) = check_file(ctx, &path) {
+        return r;
+    }
+    let result = copy_and_reload(ctx, &path);
+    if result.is_ok() {
+        archive_installed(ctx, user, &format!("rollback to generation {}", generation));
+    }
+    return result;
+}
+
+// Install the generation before the last one
+pub fn rollback_previous(ctx: &Context, user: &str) -> Response {
+    let existing = generations(ctx);
+    if existing.len() < 2 {
+        return Response::Error {
+            message: String::from("There is no previous generation to roll back to"),
+        };
+    }
+    return rollback(ctx, existing[existing.len() - 2].generation, user);
+}
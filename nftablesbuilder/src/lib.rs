@@ -0,0 +1,18 @@
+// The commands nftablesbuilder executes for the webserver. The programs they run go
+// through an Executor, so they can be tested without root with a RecordingExecutor.
+
+// explicit returns, len() == 0 and field: value are the style of this crate
+#![allow(
+    clippy::needless_return,
+    clippy::len_zero,
+    clippy::redundant_field_names
+)]
+
+pub mod commands;
+pub mod confirm;
+pub mod executor;
+pub mod files;
+pub mod history;
+pub mod ruleset;
+pub mod sandbox;
+pub mod trace;
@@ -1,44 +1,194 @@
-use hex::{FromHex, ToHex};
-use openssl::rand::rand_priv_bytes;
-use openssl::symm::{Cipher, decrypt};
+// explicit returns, len() == 0 and field: value are the style of this crate
+#![allow(
+    clippy::needless_return,
+    clippy::len_zero,
+    clippy::redundant_field_names
+)]
+
+use hex::ToHex;
+use nftablesbuilder::commands::{Context, handle};
+use nftablesbuilder::executor::SystemExecutor;
+use nftablesbuilder::{confirm, sandbox, trace};
+use protocol::{Channel, REQUEST, RESPONSE, Request, SOCKET_PATH};
 use settings::{Settings, get_settings};
 use signal_hook::flag;
-use std::io::{BufRead, BufReader, Write};
-use std::process::{Command, Stdio};
-use std::sync::Arc;
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::process::{Child, Command, Stdio};
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
-fn main() {
-    let cipher = Cipher::aes_256_cbc();
+mod privsep;
+mod socket;
+
+// How often blocking waits check for a SIGTERM and for the webserver exiting
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+const WEBSERVER_STOP_TIMEOUT: Duration = Duration::from_secs(10);
+const RESTART_DELAY_MIN: Duration = Duration::from_secs(1);
+const RESTART_DELAY_MAX: Duration = Duration::from_secs(30);
 
-    // Generate a key and iv
-    let mut key = [0; 32];
-    rand_priv_bytes(&mut key).unwrap();
-    let mut iv = [0; 32];
-    rand_priv_bytes(&mut iv).unwrap();
+// Ask the webserver to stop and kill it when it does not stop in time
+fn stop_webserver(child: &mut Child) {
+    unsafe {
+        libc::kill(child.id() as libc::pid_t, libc::SIGTERM);
+    }
+    let deadline = Instant::now() + WEBSERVER_STOP_TIMEOUT;
+    while Instant::now() < deadline {
+        if let Ok(Some(status)) = child.try_wait() {
+            eprintln!("Webserver exited: {}", status);
+            return;
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+    let _ = child.kill();
+    if let Ok(status) = child.wait() {
+        eprintln!("Webserver was killed: {}", status);
+    }
+}
 
-    let hexkey = key.encode_hex::<String>();
-    let hexiv = iv.encode_hex::<String>();
+// Execute the requests of one webserver connection until it closes, the webserver
+// exits or a SIGTERM is received
+fn serve_connection(
+    stream: UnixStream,
+    channel: &mut Channel,
+    child: &mut Child,
+    term: &Arc<AtomicBool>,
+    ctx: &Context,
+    pending: &Arc<Mutex<confirm::Pending>>,
+    trace: &Arc<Mutex<trace::Trace>>,
+) -> Result<(), String> {
+    stream.set_nonblocking(false).map_err(|e| e.to_string())?;
+    // wake up regularly to check for a SIGTERM
+    stream
+        .set_read_timeout(Some(POLL_INTERVAL))
+        .map_err(|e| e.to_string())?;
+    let mut writer = stream.try_clone().map_err(|e| e.to_string())?;
+    let mut reader = BufReader::new(stream);
+    let mut line: Vec<u8> = vec![];
+    while !term.load(Ordering::Relaxed) {
+        // a timeout keeps the part of the line that was read so far
+        match reader.read_until(b'\n', &mut line) {
+            Ok(0) => return Ok(()),
+            Ok(_) if line.ends_with(b"\n") => {}
+            Ok(_) => return Ok(()),
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                if let Ok(Some(_status)) = child.try_wait() {
+                    return Ok(());
+                }
+                continue;
+            }
+            Err(e) => return Err(e.to_string()),
+        }
+        // only authenticated requests are executed, anything else ends the session
+        let text = String::from_utf8_lossy(&line).to_string();
+        line.clear();
+        let request: Request = channel
+            .open(REQUEST, &text)
+            .map_err(|e| format!("Rejected request: {}", e))?;
+        let response = handle(request, ctx, pending, trace);
+        let frame = format!("{}\n", channel.seal(RESPONSE, &response)?);
+        writer
+            .write_all(frame.as_bytes())
+            .map_err(|e| e.to_string())?;
+        channel.mark_sent();
+    }
+    return Ok(());
+}
 
-    let hexkeyline = format!("{}\n", hexkey);
-    let hexivline = format!("{}\n", hexiv);
+// Start the webserver and serve its connection until it exits or a SIGTERM is received
+fn run_webserver(
+    listener: &UnixListener,
+    privsep: &privsep::Privsep,
+    term: &Arc<AtomicBool>,
+    settings: &Settings,
+    ctx: &Context,
+    pending: &Arc<Mutex<confirm::Pending>>,
+    trace: &Arc<Mutex<trace::Trace>>,
+) -> Result<(), String> {
+    // every webserver process gets a new key for its encrypted channel
+    let key = Channel::generate_key()?;
+    let mut channel = Channel::new(&key)?;
+    let hexkeyline = format!("{}\n", key.encode_hex::<String>());
 
-    let settings: Settings = get_settings();
+    // the private key of https is readable by root only, the webserver gets the
+    // certificate and the key after the key of the channel
+    let certificate = std::fs::read(&settings.files.tlscert)
+        .map_err(|e| format!("Could not read {}: {}", settings.files.tlscert, e))?;
+    let tlskey = std::fs::read(&settings.files.tlskey)
+        .map_err(|e| format!("Could not read {}: {}", settings.files.tlskey, e))?;
+    let tlslines = format!(
+        "{}\n{}\n",
+        certificate.encode_hex::<String>(),
+        tlskey.encode_hex::<String>()
+    );
 
-    // Spawn the webserver process
-    let mut child = Command::new(settings.files.webserver.clone())
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
+    let mut command = Command::new(settings.files.webserver.clone());
+    command.stdin(Stdio::piped());
+    privsep::apply(&mut command, privsep);
+    let mut child = command
         .spawn()
-        .expect("failed to execute process");
+        .map_err(|e| format!("Could not start {}: {}", settings.files.webserver, e))?;
 
-    // Get handles to the child's stdin and stdout
-    let child_stdin = child.stdin.as_mut().unwrap();
-    let mut child_stdout = BufReader::new(child.stdout.as_mut().unwrap());
+    // Send the keys to the child process, its stdin is closed afterwards
+    if let Some(mut child_stdin) = child.stdin.take() {
+        let sent = child_stdin
+            .write_all(hexkeyline.as_bytes())
+            .and_then(|_| child_stdin.write_all(tlslines.as_bytes()));
+        if let Err(e) = sent {
+            stop_webserver(&mut child);
+            return Err(format!("Could not send the key to the webserver: {}", e));
+        }
+    }
 
-    // Send the key and iv to the child process
-    child_stdin.write_all(hexkeyline.as_bytes()).unwrap();
-    child_stdin.write_all(hexivline.as_bytes()).unwrap();
+    // the webserver connects once, its requests are served until it exits
+    while !term.load(Ordering::Relaxed) {
+        if let Ok(Some(status)) = child.try_wait() {
+            return Err(format!("Webserver exited: {}", status));
+        }
+        let stream = match listener.accept() {
+            Ok((s, _addr)) => s,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                thread::sleep(POLL_INTERVAL);
+                continue;
+            }
+            Err(e) => {
+                stop_webserver(&mut child);
+                return Err(format!("Could not accept a connection: {}", e));
+            }
+        };
+        // only the webserver that was just started may connect
+        match socket::peer_credentials(&stream) {
+            Ok(peer) if peer.pid == child.id() && peer.uid == privsep.uid => {}
+            Ok(peer) => {
+                eprintln!(
+                    "Rejected connection from pid {} uid {} gid {}",
+                    peer.pid, peer.uid, peer.gid
+                );
+                continue;
+            }
+            Err(e) => {
+                eprintln!("Rejected connection: {}", e);
+                continue;
+            }
+        }
+        let result = serve_connection(stream, &mut channel, &mut child, term, ctx, pending, trace);
+        // the key is only used for one connection, a new one needs a new webserver
+        stop_webserver(&mut child);
+        return result;
+    }
+    stop_webserver(&mut child);
+    return Ok(());
+}
+
+fn main() {
+    // the verification sandbox is a separate process of this program
+    if std::env::args().nth(1).as_deref() == Some("sandbox") {
+        std::process::exit(sandbox::main());
+    }
+
+    let settings: Settings = get_settings();
 
     let term = Arc::new(AtomicBool::new(false));
 
@@ -46,73 +196,76 @@ fn main() {
     // when the program receives a SIGTERM kill signal
     flag::register(signal_hook::consts::SIGTERM, Arc::clone(&term)).unwrap();
 
-    // Check the initialization response from the child process
-    let mut line = String::new();
-    child_stdout.read_line(&mut line).expect("read_line failed");
-    if line != "OK\n" {
-        eprintln!("Webserver failed to initialize: {}", line);
+    // credentials and confinement of the webserver
+    let privsep = match privsep::from_env(settings.connection.port) {
+        Ok(p) => p,
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    };
+
+    let listener = match socket::listen(SOCKET_PATH, privsep.uid, privsep.gid) {
+        Ok(l) => l,
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    };
+    if let Err(e) = privsep::check_socket(&privsep, SOCKET_PATH) {
+        eprintln!("{}", e);
         return;
     }
+    if let Err(e) = privsep::check_savepath(&privsep, &settings.paths.savepath) {
+        eprintln!("{}", e);
+        return;
+    }
+
+    // the programs of the commands are run directly
+    let ctx = Context {
+        nft: settings.files.nft.clone(),
+        test: settings.files.test.clone(),
+        conf: settings.files.conf.clone(),
+        reload: settings.commands.reload.clone(),
+        program: String::from("/proc/self/exe"),
+        executor: Arc::new(SystemExecutor),
+    };
+
+    // install waiting for confirmation
+    let pending = Arc::new(Mutex::new(confirm::Pending::default()));
 
-    // Do work until the term variable becomes true
+    // state of a running packet trace
+    let trace = Arc::new(Mutex::new(trace::Trace::default()));
+
+    // Restart the webserver until the term variable becomes true, with an increasing
+    // delay when it keeps failing shortly after the start
+    let mut failures: u32 = 0;
     while !term.load(Ordering::Relaxed) {
-        line.clear();
-        // Read a line from the child's stdout
-        child_stdout.read_line(&mut line).expect("read_line failed");
-        {
-            let encrypted_data = <Vec<u8>>::from_hex(&line.trim()).unwrap();
-            let decrypted_data = decrypt(cipher, &key, Some(&iv), &encrypted_data).unwrap();
-
-            // interpret the decrypted data as a string command
-            let command = String::from_utf8(decrypted_data).unwrap();
-            if command == String::from("install") {
-                // run nft -c -f <test file> to check the syntax of the script
-                let mut output = Command::new(settings.files.nft.clone())
-                    .args(["-c", "-f", settings.files.test.as_str()])
-                    .stdout(Stdio::piped())
-                    .output()
-                    .unwrap();
-
-                // extract the raw bytes that we captured and interpret them as a string
-                let _stdout = String::from_utf8(output.stdout).unwrap();
-                let mut stderr = String::from_utf8(output.stderr).unwrap();
-
-                if stderr.len() == 0 {
-                    // syntax is correct, copy the test file to the conf file
-                    output = Command::new("cp")
-                        .args([settings.files.test.as_str(), settings.files.conf.as_str()])
-                        .stdout(Stdio::piped())
-                        .output()
-                        .unwrap();
-                    stderr = String::from_utf8(output.stderr).unwrap();
-                    // if the copy was successful, reload the nftables rules
-                    if stderr.len() == 0 {
-                        // split the reload command into command and args
-                        let restart_args: Vec<String> = settings
-                            .commands
-                            .reload
-                            .split(" ")
-                            .map(str::to_string)
-                            .collect();
-                        if restart_args.len() == 0 {
-                            stderr = String::from("Invalid reload command");
-                        } else {
-                            output = Command::new(restart_args[0].clone())
-                                .args(restart_args[1..].iter())
-                                .stdout(Stdio::piped())
-                                .output()
-                                .unwrap();
-                            stderr = String::from_utf8(output.stderr).unwrap();
-                        }
-                        if stderr.len() == 0 {
-                            stderr = String::from("OK");
-                        }
-                    }
-                }
-                // send the stderr (or "OK") back to the child process
-                let hexstr = format!("{}\n", stderr.encode_hex::<String>());
-                child_stdin.write_all(hexstr.as_bytes()).unwrap();
-            }
+        let started = Instant::now();
+        if let Err(e) = run_webserver(
+            &listener, &privsep, &term, &settings, &ctx, &pending, &trace,
+        ) {
+            eprintln!("{}", e);
+        }
+        if term.load(Ordering::Relaxed) {
+            break;
+        }
+        if started.elapsed() > RESTART_DELAY_MAX {
+            failures = 0;
+        }
+        let delay = RESTART_DELAY_MIN
+            .saturating_mul(1 << failures.min(5))
+            .min(RESTART_DELAY_MAX);
+        failures += 1;
+        eprintln!("Restarting webserver in {} seconds", delay.as_secs());
+        let restart = Instant::now() + delay;
+        while Instant::now() < restart && !term.load(Ordering::Relaxed) {
+            thread::sleep(POLL_INTERVAL);
         }
     }
+
+    // do not leave trace rules behind
+    let _ = trace::stop(&trace, &ctx);
+    confirm::shutdown(&pending, &ctx);
+    let _ = std::fs::remove_file(SOCKET_PATH);
 }
@@ -0,0 +1,345 @@
+use seccompiler::{BpfProgram, SeccompAction, SeccompFilter, TargetArch};
+use std::collections::BTreeMap;
+use std::env;
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+// The webserver is started with the credentials and confinement configured in the
+// environment of nftablesbuilder, for example with Environment= in its systemd unit:
+//   NFTABLESBUILDER_WEBSERVER_USER        user the webserver runs as, nobody by default
+//   NFTABLESBUILDER_WEBSERVER_GROUP       group, the primary group of the user by default
+//   NFTABLESBUILDER_WEBSERVER_CHROOT      directory the webserver is confined to, it has
+//                                         to contain the webserver and its files, and the
+//                                         socket directory has to be bind mounted into it
+//   NFTABLESBUILDER_WEBSERVER_NAMESPACES  "1" for new mount, ipc and uts namespaces
+// The webserver keeps the users, the saved configurations, their git repository and the
+// impact records in the save directory, which has to belong to its user and group, for
+// example after chown -R nobody:nogroup on it. nftablesbuilder refuses to start when
+// anything in it belongs to someone else. The certificate and the private key of https
+// are read by nftablesbuilder and handed to the webserver, they can stay readable by root
+// only. The webserver never runs as root. It loses every capability it could regain, except
+// the one to listen on a port below 1024 when it is configured to, and runs with a
+// seccomp filter.
+const USER_VAR: &str = "NFTABLESBUILDER_WEBSERVER_USER";
+const GROUP_VAR: &str = "NFTABLESBUILDER_WEBSERVER_GROUP";
+const CHROOT_VAR: &str = "NFTABLESBUILDER_WEBSERVER_CHROOT";
+const NAMESPACES_VAR: &str = "NFTABLESBUILDER_WEBSERVER_NAMESPACES";
+
+// User of the webserver when none is configured
+const DEFAULT_USER: &str = "nobody";
+
+// Highest capability number when the kernel does not tell
+const DEFAULT_CAP_LAST_CAP: i32 = 40;
+
+// System calls the webserver never needs, they fail with EPERM
+const DENIED_SYSCALLS: [i64; 27] = [
+    libc::SYS_mount,
+    libc::SYS_umount2,
+    libc::SYS_pivot_root,
+    libc::SYS_chroot,
+    libc::SYS_unshare,
+    libc::SYS_setns,
+    libc::SYS_ptrace,
+    libc::SYS_process_vm_readv,
+    libc::SYS_process_vm_writev,
+    libc::SYS_kexec_load,
+    libc::SYS_init_module,
+    libc::SYS_finit_module,
+    libc::SYS_delete_module,
+    libc::SYS_reboot,
+    libc::SYS_swapon,
+    libc::SYS_swapoff,
+    libc::SYS_bpf,
+    libc::SYS_perf_event_open,
+    libc::SYS_keyctl,
+    libc::SYS_add_key,
+    libc::SYS_request_key,
+    libc::SYS_userfaultfd,
+    libc::SYS_open_by_handle_at,
+    libc::SYS_acct,
+    libc::SYS_settimeofday,
+    libc::SYS_clock_settime,
+    libc::SYS_syslog,
+];
+
+// Arguments of the capset system call
+#[repr(C)]
+struct CapHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+#[repr(C)]
+struct CapData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x20080522;
+const CAP_NET_BIND_SERVICE: libc::c_int = 10;
+
+#[derive(Clone)]
+pub struct Privsep {
+    pub uid: u32,
+    pub gid: u32,
+    chroot: Option<CString>,
+    namespaces: bool,
+    // the webserver listens on a port below 1024
+    bind_service: bool,
+    cap_last_cap: i32,
+    seccomp: BpfProgram,
+}
+
+fn lookup_user(name: &str) -> Result<(u32, u32), String> {
+    let cname = CString::new(name).map_err(|_e| format!("Invalid user {}", name))?;
+    let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buffer = vec![0 as libc::c_char; 16384];
+    let mut result: *mut libc::passwd = std::ptr::null_mut();
+    let rc = unsafe {
+        libc::getpwnam_r(
+            cname.as_ptr(),
+            &mut pwd,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut result,
+        )
+    };
+    if rc != 0 || result.is_null() {
+        return Err(format!("Unknown user {}", name));
+    }
+    return Ok((pwd.pw_uid, pwd.pw_gid));
+}
+
+fn lookup_group(name: &str) -> Result<u32, String> {
+    let cname = CString::new(name).map_err(|_e| format!("Invalid group {}", name))?;
+    let mut grp: libc::group = unsafe { std::mem::zeroed() };
+    let mut buffer = vec![0 as libc::c_char; 16384];
+    let mut result: *mut libc::group = std::ptr::null_mut();
+    let rc = unsafe {
+        libc::getgrnam_r(
+            cname.as_ptr(),
+            &mut grp,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut result,
+        )
+    };
+    if rc != 0 || result.is_null() {
+        return Err(format!("Unknown group {}", name));
+    }
+    return Ok(grp.gr_gid);
+}
+
+fn seccomp_filter() -> Result<BpfProgram, String> {
+    let arch = TargetArch::try_from(env::consts::ARCH).map_err(|e| e.to_string())?;
+    let rules = DENIED_SYSCALLS
+        .iter()
+        .map(|s| (*s, vec![]))
+        .collect::<BTreeMap<_, _>>();
+    let filter = SeccompFilter::new(
+        rules,
+        SeccompAction::Allow,
+        SeccompAction::Errno(libc::EPERM as u32),
+        arch,
+    )
+    .map_err(|e| e.to_string())?;
+    return BpfProgram::try_from(filter).map_err(|e| e.to_string());
+}
+
+fn variable(name: &str) -> Option<String> {
+    return env::var(name).ok().filter(|v| v.len() > 0);
+}
+
+// Read the configuration of the webserver process from the environment, port is the
+// port the webserver listens on
+pub fn from_env(port: u16) -> Result<Privsep, String> {
+    let user = variable(USER_VAR).unwrap_or(String::from(DEFAULT_USER));
+    let (uid, gid) = lookup_user(&user)?;
+    if uid == 0 {
+        return Err(format!("{} must be an unprivileged user", USER_VAR));
+    }
+    let gid = match variable(GROUP_VAR) {
+        Some(group) => lookup_group(&group)?,
+        None => gid,
+    };
+    if gid == 0 {
+        return Err(format!("{} must be an unprivileged group", GROUP_VAR));
+    }
+    let chroot = match variable(CHROOT_VAR) {
+        Some(dir) => Some(CString::new(dir).map_err(|_e| format!("Invalid {}", CHROOT_VAR))?),
+        None => None,
+    };
+    let cap_last_cap = fs::read_to_string("/proc/sys/kernel/cap_last_cap")
+        .ok()
+        .and_then(|s| s.trim().parse().ok())
+        .unwrap_or(DEFAULT_CAP_LAST_CAP);
+    return Ok(Privsep {
+        uid: uid,
+        gid: gid,
+        chroot: chroot,
+        namespaces: variable(NAMESPACES_VAR).as_deref() == Some("1"),
+        bind_service: port < 1024,
+        cap_last_cap: cap_last_cap,
+        seccomp: seccomp_filter()?,
+    });
+}
+
+// The webserver connects to the socket after it is confined, so in a chroot the directory
+// of the socket has to be the directory outside of it, for example by a bind mount
+pub fn check_socket(privsep: &Privsep, socket: &str) -> Result<(), String> {
+    let Some(chroot) = &privsep.chroot else {
+        return Ok(());
+    };
+    let chroot = chroot.to_string_lossy();
+    let dir = Path::new(socket).parent().unwrap_or(Path::new("/"));
+    let inside = Path::new(&*chroot).join(dir.strip_prefix("/").unwrap_or(dir));
+    let outside_meta =
+        fs::metadata(dir).map_err(|e| format!("Could not read {}: {}", dir.display(), e))?;
+    let same = fs::metadata(&inside)
+        .map(|m| m.dev() == outside_meta.dev() && m.ino() == outside_meta.ino())
+        .unwrap_or(false);
+    if !same {
+        return Err(format!(
+            "The webserver cannot reach {} from {} {}, bind mount {} on {}",
+            socket,
+            CHROOT_VAR,
+            chroot,
+            dir.display(),
+            inside.display()
+        ));
+    }
+    return Ok(());
+}
+
+// First file or directory in path that does not belong to uid, symbolic links are not
+// followed
+fn foreign_file(path: &Path, uid: u32) -> Result<Option<PathBuf>, String> {
+    let meta = fs::symlink_metadata(path)
+        .map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+    if meta.uid() != uid {
+        return Ok(Some(path.to_path_buf()));
+    }
+    if meta.is_dir() {
+        let entries =
+            fs::read_dir(path).map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+        for entry in entries {
+            let entry = entry.map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+            if let Some(foreign) = foreign_file(&entry.path(), uid)? {
+                return Ok(Some(foreign));
+            }
+        }
+    }
+    return Ok(None);
+}
+
+// The webserver reads and writes everything in the save directory, as seen from its chroot
+pub fn check_savepath(privsep: &Privsep, savepath: &str) -> Result<(), String> {
+    let dir = Path::new(savepath);
+    let dir = match &privsep.chroot {
+        Some(chroot) => {
+            Path::new(&*chroot.to_string_lossy()).join(dir.strip_prefix("/").unwrap_or(dir))
+        }
+        None => dir.to_path_buf(),
+    };
+    if let Some(foreign) = foreign_file(&dir, privsep.uid)? {
+        return Err(format!(
+            "{} does not belong to the webserver user {}, chown -R {}:{} {}",
+            foreign.display(),
+            privsep.uid,
+            privsep.uid,
+            privsep.gid,
+            dir.display()
+        ));
+    }
+    return Ok(());
+}
+
+fn check(rc: libc::c_int) -> io::Result<()> {
+    if rc != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    return Ok(());
+}
+
+// Runs in the forked child before the webserver is executed, so it must not allocate
+fn confine(privsep: &Privsep) -> io::Result<()> {
+    unsafe {
+        if privsep.namespaces {
+            check(libc::unshare(
+                libc::CLONE_NEWNS | libc::CLONE_NEWIPC | libc::CLONE_NEWUTS,
+            ))?;
+        }
+        if let Some(dir) = &privsep.chroot {
+            check(libc::chroot(dir.as_ptr()))?;
+            check(libc::chdir(c"/".as_ptr()))?;
+        }
+        // no capability can be regained, not even by executing a setuid program. A root
+        // webserver gets exactly the capabilities of the bounding set when it is executed.
+        for cap in 0..=privsep.cap_last_cap {
+            if privsep.bind_service && cap == CAP_NET_BIND_SERVICE {
+                continue;
+            }
+            libc::prctl(libc::PR_CAPBSET_DROP, cap, 0, 0, 0);
+        }
+        libc::prctl(
+            libc::PR_CAP_AMBIENT,
+            libc::PR_CAP_AMBIENT_CLEAR_ALL,
+            0,
+            0,
+            0,
+        );
+        check(libc::setgroups(1, &privsep.gid))?;
+        check(libc::setgid(privsep.gid))?;
+        // changing to a non root user clears all capabilities
+        if privsep.bind_service {
+            check(libc::prctl(libc::PR_SET_KEEPCAPS, 1, 0, 0, 0))?;
+        }
+        check(libc::setuid(privsep.uid))?;
+        if privsep.bind_service {
+            // keep only the capability to listen on a port below 1024, also after exec
+            let header = CapHeader {
+                version: LINUX_CAPABILITY_VERSION_3,
+                pid: 0,
+            };
+            let bit = 1 << CAP_NET_BIND_SERVICE;
+            let data = [
+                CapData {
+                    effective: bit,
+                    permitted: bit,
+                    inheritable: bit,
+                },
+                CapData {
+                    effective: 0,
+                    permitted: 0,
+                    inheritable: 0,
+                },
+            ];
+            if libc::syscall(libc::SYS_capset, &header, data.as_ptr()) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            check(libc::prctl(
+                libc::PR_CAP_AMBIENT,
+                libc::PR_CAP_AMBIENT_RAISE,
+                CAP_NET_BIND_SERVICE,
+                0,
+                0,
+            ))?;
+        }
+    }
+    // also sets no_new_privs
+    return seccompiler::apply_filter(&privsep.seccomp).map_err(io::Error::other);
+}
+
+// Start the command with the configured credentials and confinement
+pub fn apply(command: &mut Command, privsep: &Privsep) {
+    let privsep = privsep.clone();
+    unsafe {
+        command.pre_exec(move || confine(&privsep));
+    }
+}
@@ -0,0 +1,176 @@
+use crate::commands::{Context, run};
+use protocol::{Counter, Response};
+use serde_json::Value;
+
+const FAMILIES: [&str; 6] = ["ip", "ip6", "inet", "arp", "bridge", "netdev"];
+
+fn invalid(message: String) -> Response {
+    return Response::Error { message: message };
+}
+
+fn check_family(family: &str) -> Result<(), Response> {
+    if !FAMILIES.contains(&family) {
+        return Err(invalid(format!("Invalid family {}", family)));
+    }
+    return Ok(());
+}
+
+// Table and set names are passed to nft as arguments, only identifiers are allowed
+fn check_name(name: &str) -> Result<(), Response> {
+    let valid = name.len() > 0
+        && name.len() < 256
+        && name.starts_with(|c: char| c.is_ascii_alphabetic())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.');
+    if !valid {
+        return Err(invalid(format!("Invalid name {}", name)));
+    }
+    return Ok(());
+}
+
+// Addresses, networks, ranges, ports and interface names
+fn check_element(element: &str) -> Result<(), Response> {
+    let valid = element.len() > 0
+        && element.len() < 256
+        && element
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || ".:/-_".contains(c));
+    if !valid {
+        return Err(invalid(format!("Invalid set element {}", element)));
+    }
+    return Ok(());
+}
+
+fn parse_json(output: &str) -> Result<Value, Response> {
+    return serde_json::from_str(output)
+        .map_err(|e| invalid(format!("Invalid nft json output: {}", e)));
+}
+
+// The objects of the "nftables" array of nft json output
+fn objects(json: &Value) -> Vec<&Value> {
+    match json.get("nftables").and_then(Value::as_array) {
+        Some(a) => return a.iter().collect(),
+        None => return vec![],
+    }
+}
+
+fn text(value: &Value, key: &str) -> String {
+    return String::from(value.get(key).and_then(Value::as_str).unwrap_or(""));
+}
+
+// The counters of all rules in the active ruleset that have one
+pub fn list_counters(ctx: &Context) -> Result<Vec<Counter>, Response> {
+    let output = run(ctx, &ctx.nft, &["-j", "-a", "list", "ruleset"])?;
+    let json = parse_json(&output)?;
+    let mut counters: Vec<Counter> = vec![];
+    for object in objects(&json) {
+        let Some(rule) = object.get("rule") else {
+            continue;
+        };
+        let expressions = rule.get("expr").and_then(Value::as_array);
+        let counter = expressions
+            .into_iter()
+            .flatten()
+            .find_map(|e| e.get("counter"));
+        let Some(counter) = counter else {
+            continue;
+        };
+        counters.push(Counter {
+            family: text(rule, "family"),
+            table: text(rule, "table"),
+            chain: text(rule, "chain"),
+            handle: rule.get("handle").and_then(Value::as_u64).unwrap_or(0),
+            comment: rule
+                .get("comment")
+                .and_then(Value::as_str)
+                .map(String::from),
+            packets: counter.get("packets").and_then(Value::as_u64).unwrap_or(0),
+            bytes: counter.get("bytes").and_then(Value::as_u64).unwrap_or(0),
+        });
+    }
+    return Ok(counters);
+}
+
+// Reset the counters of all rules in a table
+pub fn reset_counters(ctx: &Context, family: &str, table: &str) -> Result<(), Response> {
+    check_family(family)?;
+    check_name(table)?;
+    run(ctx, &ctx.nft, &["reset", "rules", "table", family, table])?;
+    return Ok(());
+}
+
+// Set element of nft json output as it is written in a script
+fn element_text(value: &Value) -> String {
+    if let Some(s) = value.as_str() {
+        return String::from(s);
+    }
+    if let Some(prefix) = value.get("prefix") {
+        return format!(
+            "{}/{}",
+            element_text(&prefix["addr"]),
+            element_text(&prefix["len"])
+        );
+    }
+    if let Some(range) = value.get("range").and_then(Value::as_array) {
+        let parts: Vec<String> = range.iter().map(element_text).collect();
+        return parts.join("-");
+    }
+    if let Some(concat) = value.get("concat").and_then(Value::as_array) {
+        let parts: Vec<String> = concat.iter().map(element_text).collect();
+        return parts.join(" . ");
+    }
+    // elements with options like a timeout
+    if let Some(elem) = value.get("elem") {
+        return element_text(&elem["val"]);
+    }
+    return value.to_string();
+}
+
+pub fn list_set_elements(
+    ctx: &Context,
+    family: &str,
+    table: &str,
+    set: &str,
+) -> Result<Vec<String>, Response> {
+    check_family(family)?;
+    check_name(table)?;
+    check_name(set)?;
+    let output = run(ctx, &ctx.nft, &["-j", "list", "set", family, table, set])?;
+    let json = parse_json(&output)?;
+    let elements = objects(&json)
+        .into_iter()
+        .filter_map(|o| o.get("set"))
+        .filter_map(|s| s.get("elem").and_then(Value::as_array))
+        .flatten()
+        .map(element_text)
+        .collect();
+    return Ok(elements);
+}
+
+// Add ("add") or delete ("delete") elements of a set
+pub fn modify_set_elements(
+    ctx: &Context,
+    operation: &str,
+    family: &str,
+    table: &str,
+    set: &str,
+    elements: &[String],
+) -> Result<(), Response> {
+    check_family(family)?;
+    check_name(table)?;
+    check_name(set)?;
+    if elements.len() == 0 {
+        return Err(invalid(String::from("No set elements given")));
+    }
+    for element in elements {
+        check_element(element)?;
+    }
+    let list = format!("{{ {} }}", elements.join(", "));
+    run(
+        ctx,
+        &ctx.nft,
+        &[operation, "element", family, table, set, &list],
+    )?;
+    return Ok(());
+}
@@ -0,0 +1,688 @@
+use crate::commands::{Context, run_input};
+use protocol::{Probe, ProbeResult, Response, SandboxReport};
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, UdpSocket};
+use std::os::fd::AsRawFd;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+// A script is verified in a topology of throwaway network namespaces: the router
+// namespace gets the ruleset and a veth interface for every configured interface, the
+// other end of each veth pair is the "peer" interface of a namespace of its own. The
+// addresses of the probes are added to the namespace they are sent from or to, the
+// router itself when the probe has no interface.
+//
+// Creating a user namespace requires a single threaded process, so the sandbox runs as
+// "nftablesbuilder sandbox", which reads a Spec on stdin and writes a SandboxReport on
+// stdout. Started as root it drops to nobody first and only keeps root when the
+// kernel does not allow unprivileged user namespaces.
+
+const MAX_INTERFACES: usize = 32;
+const MAX_PROBES: usize = 64;
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+// uid and gid the sandbox runs as when it is started as root
+const NOBODY: u32 = 65534;
+// exit code of a sandbox that could not create a user namespace
+const NO_USER_NAMESPACE: i32 = 3;
+const IP: &str = "ip";
+const PEER_INTERFACE: &str = "peer";
+// link addresses of both ends of every veth pair, the neighbours are static so the
+// ruleset cannot break arp or neighbour discovery
+const ROUTER_IPV4: &str = "169.254.0.1";
+const ROUTER_IPV6: &str = "fe80::1";
+const PEER_IPV6: &str = "fe80::2";
+const PROBE_DATA: &[u8] = b"nftablesbuilder probe";
+
+#[derive(Deserialize, Serialize)]
+struct Spec {
+    nft: String,
+    script: String,
+    interfaces: Vec<String>,
+    probes: Vec<Probe>,
+}
+
+fn valid_interface(name: &str) -> bool {
+    return name.len() > 0
+        && name.len() < 16
+        && name != "lo"
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '_');
+}
+
+fn check_probe(probe: &Probe, interfaces: &[String]) -> Result<(), String> {
+    for iface in [&probe.iif, &probe.oif] {
+        if iface.len() > 0 && !interfaces.contains(iface) {
+            return Err(format!("Unknown interface {}", iface));
+        }
+    }
+    if probe.iif == probe.oif {
+        return Err(String::from(
+            "A probe needs different input and output interfaces",
+        ));
+    }
+    let saddr = probe
+        .saddr
+        .parse::<IpAddr>()
+        .map_err(|_e| format!("Invalid address {}", probe.saddr))?;
+    let daddr = probe
+        .daddr
+        .parse::<IpAddr>()
+        .map_err(|_e| format!("Invalid address {}", probe.daddr))?;
+    if saddr.is_ipv6() != daddr.is_ipv6() {
+        return Err(format!(
+            "Addresses {} and {} are of different families",
+            saddr, daddr
+        ));
+    }
+    match (probe.protocol.as_str(), probe.dport) {
+        ("tcp" | "udp", Some(port)) if port > 0 => {}
+        ("tcp" | "udp", _) => return Err(String::from("Tcp and udp probes need a port")),
+        ("icmp" | "icmpv6", _) => {}
+        (p, _) => return Err(format!("Invalid protocol {}", p)),
+    }
+    return Ok(());
+}
+
+// Load the script in a sandbox and run the probes through it
+pub fn verify(ctx: &Context, script: &str, interfaces: &[String], probes: &[Probe]) -> Response {
+    let mut names: Vec<String> = vec![];
+    for iface in interfaces {
+        if !valid_interface(iface) {
+            return Response::Error {
+                message: format!("Invalid interface {}", iface),
+            };
+        }
+        if !names.contains(iface) {
+            names.push(iface.clone());
+        }
+    }
+    if names.len() > MAX_INTERFACES || probes.len() > MAX_PROBES {
+        return Response::Error {
+            message: format!(
+                "A sandbox has at most {} interfaces and {} probes",
+                MAX_INTERFACES, MAX_PROBES
+            ),
+        };
+    }
+    for probe in probes {
+        if let Err(e) = check_probe(probe, &names) {
+            return Response::Error { message: e };
+        }
+    }
+    let spec = Spec {
+        nft: ctx.nft.clone(),
+        script: String::from(script),
+        interfaces: names,
+        probes: probes.to_vec(),
+    };
+    let input = serde_json::to_string(&spec).unwrap();
+    match run_input(ctx, &ctx.program, &["sandbox"], Some(&input)) {
+        Ok((stdout, _stderr)) => match serde_json::from_str::<SandboxReport>(&stdout) {
+            Ok(report) => return Response::Sandbox { report: report },
+            Err(e) => {
+                return Response::Error {
+                    message: format!("Invalid sandbox report: {}", e),
+                };
+            }
+        },
+        Err(r) => return r,
+    }
+}
+
+fn check(rc: libc::c_int) -> io::Result<()> {
+    if rc != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    return Ok(());
+}
+
+fn drop_privileges() -> io::Result<()> {
+    unsafe {
+        check(libc::setgroups(0, std::ptr::null()))?;
+        check(libc::setgid(NOBODY))?;
+        check(libc::setuid(NOBODY))?;
+        // changing the user makes /proc/self root owned, the id maps are written there
+        check(libc::prctl(libc::PR_SET_DUMPABLE, 1, 0, 0, 0))?;
+    }
+    return Ok(());
+}
+
+// Make the current user root of a new user namespace
+fn map_root(uid: u32, gid: u32) -> Result<(), String> {
+    for (file, content) in [
+        ("/proc/self/setgroups", String::from("deny")),
+        ("/proc/self/uid_map", format!("0 {} 1", uid)),
+        ("/proc/self/gid_map", format!("0 {} 1", gid)),
+    ] {
+        fs::write(file, content).map_err(|e| format!("Could not write {}: {}", file, e))?;
+    }
+    return Ok(());
+}
+
+fn enter(namespace: &File) -> Result<(), String> {
+    return check(unsafe { libc::setns(namespace.as_raw_fd(), libc::CLONE_NEWNET) })
+        .map_err(|e| format!("Could not enter a network namespace: {}", e));
+}
+
+// Run ip with a command line, the words of the command contain no white space
+fn ip(command: &str) -> Result<(), String> {
+    let output = Command::new(IP)
+        .args(command.split_whitespace())
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|e| format!("Could not run {}: {}", IP, e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "{} {}: {}",
+            IP,
+            command,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    return Ok(());
+}
+
+fn sysctl(name: &str, value: &str) -> Result<(), String> {
+    let path = format!("/proc/sys/{}", name.replace('.', "/"));
+    return fs::write(&path, value).map_err(|e| format!("Could not set {}: {}", name, e));
+}
+
+fn mac(index: usize, end: u8) -> String {
+    return format!("02:00:00:00:{:02x}:{:02x}", index, end);
+}
+
+// A peer namespace and the router interface it is connected to
+struct Peer {
+    namespace: File,
+    interface: String,
+    router_mac: String,
+    peer_mac: String,
+}
+
+struct Topology {
+    router: File,
+    peers: HashMap<String, Peer>,
+    // where every probe address was added, to detect conflicts
+    addresses: HashMap<IpAddr, String>,
+}
+
+impl Topology {
+    // The current network namespace becomes the router
+    fn build(interfaces: &[String]) -> Result<Topology, String> {
+        let router = File::open("/proc/self/ns/net")
+            .map_err(|e| format!("Could not open the network namespace: {}", e))?;
+        let mut topology = Topology {
+            router: router,
+            peers: HashMap::new(),
+            addresses: HashMap::new(),
+        };
+        ip("link set lo up")?;
+        sysctl("net.ipv4.ip_forward", "1")?;
+        sysctl("net.ipv6.conf.all.forwarding", "1")?;
+        sysctl("net.ipv4.conf.all.rp_filter", "0")?;
+        sysctl("net.ipv4.conf.default.rp_filter", "0")?;
+        for (index, iface) in interfaces.iter().enumerate() {
+            topology.add_peer(index + 1, iface)?;
+        }
+        return Ok(topology);
+    }
+
+    fn add_peer(&mut self, index: usize, iface: &str) -> Result<(), String> {
+        check(unsafe { libc::unshare(libc::CLONE_NEWNET) })
+            .map_err(|e| format!("Could not create a network namespace: {}", e))?;
+        let namespace = File::open("/proc/self/ns/net");
+        enter(&self.router)?;
+        let namespace =
+            namespace.map_err(|e| format!("Could not open the network namespace: {}", e))?;
+        let peer = Peer {
+            interface: String::from(iface),
+            router_mac: mac(index, 1),
+            peer_mac: mac(index, 2),
+            namespace: namespace,
+        };
+        // ip opens the namespace through the file descriptor of this process
+        let path = format!(
+            "/proc/{}/fd/{}",
+            std::process::id(),
+            peer.namespace.as_raw_fd()
+        );
+        ip(&format!(
+            "link add {} address {} type veth peer name {} address {} netns {}",
+            iface, peer.router_mac, PEER_INTERFACE, peer.peer_mac, path
+        ))?;
+        ip(&format!("addr add {}/32 dev {}", ROUTER_IPV4, iface))?;
+        ip(&format!("addr add {}/64 dev {} nodad", ROUTER_IPV6, iface))?;
+        ip(&format!("link set {} up", iface))?;
+        ip(&format!(
+            "neigh add {} lladdr {} dev {} nud permanent",
+            PEER_IPV6, peer.peer_mac, iface
+        ))?;
+        self.in_namespace(&peer.namespace, || {
+            ip("link set lo up")?;
+            ip(&format!(
+                "addr add {}/64 dev {} nodad",
+                PEER_IPV6, PEER_INTERFACE
+            ))?;
+            ip(&format!("link set {} up", PEER_INTERFACE))?;
+            for gateway in [ROUTER_IPV4, ROUTER_IPV6] {
+                ip(&format!(
+                    "neigh add {} lladdr {} dev {} nud permanent",
+                    gateway, peer.router_mac, PEER_INTERFACE
+                ))?;
+            }
+            ip(&format!(
+                "route add default via {} dev {} onlink",
+                ROUTER_IPV4, PEER_INTERFACE
+            ))?;
+            ip(&format!(
+                "-6 route add default via {} dev {}",
+                ROUTER_IPV6, PEER_INTERFACE
+            ))?;
+            return Ok(());
+        })?;
+        self.peers.insert(String::from(iface), peer);
+        return Ok(());
+    }
+
+    // Run f in another network namespace, processes started by f run there as well
+    fn in_namespace<T>(
+        &self,
+        namespace: &File,
+        f: impl FnOnce() -> Result<T, String>,
+    ) -> Result<T, String> {
+        enter(namespace)?;
+        let result = f();
+        enter(&self.router)?;
+        return result;
+    }
+
+    // The namespace of an interface, the router for an empty interface
+    fn namespace(&self, iface: &str) -> &File {
+        match self.peers.get(iface) {
+            Some(peer) => return &peer.namespace,
+            None => return &self.router,
+        }
+    }
+
+    // Add an address of a probe to the peer namespace of the interface and route it
+    // there, or to the router itself when there is no interface
+    fn add_address(&mut self, iface: &str, address: IpAddr) -> Result<(), String> {
+        let location = if iface.len() > 0 { iface } else { "the router" };
+        match self.addresses.get(&address) {
+            Some(l) if l == location => return Ok(()),
+            Some(l) => {
+                return Err(format!(
+                    "Address {} is used on {} and on {}",
+                    address, l, location
+                ));
+            }
+            None => {}
+        }
+        let prefix = if address.is_ipv6() { 128 } else { 32 };
+        let cidr = format!("{}/{}", address, prefix);
+        match self.peers.get(iface) {
+            None => ip(&format!("addr add {} dev lo", cidr))?,
+            Some(peer) => {
+                let nodad = if address.is_ipv6() { "nodad" } else { "" };
+                self.in_namespace(&peer.namespace, || {
+                    return ip(&format!(
+                        "addr add {} dev {} {}",
+                        cidr, PEER_INTERFACE, nodad
+                    ));
+                })?;
+                if address.is_ipv6() {
+                    ip(&format!(
+                        "-6 route add {} via {} dev {}",
+                        cidr, PEER_IPV6, iface
+                    ))?;
+                } else {
+                    ip(&format!("route add {} dev {}", cidr, iface))?;
+                    ip(&format!(
+                        "neigh add {} lladdr {} dev {} nud permanent",
+                        address, peer.peer_mac, peer.interface
+                    ))?;
+                }
+            }
+        }
+        self.addresses.insert(address, String::from(location));
+        return Ok(());
+    }
+
+    // Create a socket in the namespace of an interface
+    fn socket(
+        &self,
+        iface: &str,
+        domain: Domain,
+        kind: Type,
+        protocol: Option<Protocol>,
+    ) -> Result<Socket, String> {
+        return self.in_namespace(self.namespace(iface), || {
+            return Socket::new(domain, kind, protocol)
+                .map_err(|e| format!("Could not create a socket: {}", e));
+        });
+    }
+}
+
+// Load the script in the router namespace
+fn load(nft: &str, script: &str) -> Result<(), String> {
+    let mut child = Command::new(nft)
+        .args(["-f", "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Could not run {}: {}", nft, e))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin
+            .write_all(script.as_bytes())
+            .map_err(|e| format!("Could not run {}: {}", nft, e))?;
+    }
+    let output = child
+        .wait_with_output()
+        .map_err(|e| format!("Could not run {}: {}", nft, e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "Could not load the ruleset: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    return Ok(());
+}
+
+fn bind(socket: &Socket, address: IpAddr, port: u16) -> Result<(), String> {
+    return socket
+        .bind(&SockAddr::from(SocketAddr::new(address, port)))
+        .map_err(|e| format!("Could not bind {}: {}", address, e));
+}
+
+fn timed_out(e: &io::Error) -> bool {
+    return e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut;
+}
+
+// Whether the probe reached its destination, with a description of what happened
+fn tcp_probe(
+    topology: &Topology,
+    probe: &Probe,
+    saddr: IpAddr,
+    daddr: IpAddr,
+) -> Result<(bool, String), String> {
+    let port = probe.dport.unwrap_or(0);
+    let domain = Domain::for_address(SocketAddr::new(daddr, port));
+    let listener = topology.socket(&probe.oif, domain, Type::STREAM, None)?;
+    let _ = listener.set_reuse_address(true);
+    bind(&listener, daddr, port)?;
+    listener
+        .listen(1)
+        .map_err(|e| format!("Could not listen on {}: {}", daddr, e))?;
+    let listener: TcpListener = listener.into();
+    let client = topology.socket(&probe.iif, domain, Type::STREAM, None)?;
+    bind(&client, saddr, 0)?;
+    let destination = SockAddr::from(SocketAddr::new(daddr, port));
+    match client.connect_timeout(&destination, PROBE_TIMEOUT) {
+        Ok(()) => {}
+        Err(e) if timed_out(&e) => return Ok((false, String::from("no answer"))),
+        Err(e) => return Ok((false, e.to_string())),
+    }
+    // the connection may have been translated to another destination
+    listener.set_nonblocking(true).map_err(|e| e.to_string())?;
+    match listener.accept() {
+        Ok(_connection) => return Ok((true, String::from("connected"))),
+        Err(_e) => return Ok((false, String::from("connected elsewhere"))),
+    }
+}
+
+fn udp_probe(
+    topology: &Topology,
+    probe: &Probe,
+    saddr: IpAddr,
+    daddr: IpAddr,
+) -> Result<(bool, String), String> {
+    let port = probe.dport.unwrap_or(0);
+    let domain = Domain::for_address(SocketAddr::new(daddr, port));
+    let receiver = topology.socket(&probe.oif, domain, Type::DGRAM, None)?;
+    bind(&receiver, daddr, port)?;
+    let receiver: UdpSocket = receiver.into();
+    receiver
+        .set_read_timeout(Some(PROBE_TIMEOUT))
+        .map_err(|e| e.to_string())?;
+    let sender = topology.socket(&probe.iif, domain, Type::DGRAM, None)?;
+    bind(&sender, saddr, 0)?;
+    let sender: UdpSocket = sender.into();
+    sender
+        .send_to(PROBE_DATA, SocketAddr::new(daddr, port))
+        .map_err(|e| format!("Could not send to {}: {}", daddr, e))?;
+    let mut buffer = [0u8; 64];
+    match receiver.recv_from(&mut buffer) {
+        Ok((size, _from)) if &buffer[..size] == PROBE_DATA => {
+            return Ok((true, String::from("datagram received")));
+        }
+        Ok(_received) => return Ok((false, String::from("unexpected datagram"))),
+        Err(e) if timed_out(&e) => return Ok((false, String::from("no datagram received"))),
+        Err(e) => return Ok((false, e.to_string())),
+    }
+}
+
+fn icmp_checksum(packet: &[u8]) -> u16 {
+    let mut sum: u32 = 0;
+    for chunk in packet.chunks(2) {
+        let word = match chunk {
+            [a, b] => u16::from_be_bytes([*a, *b]),
+            [a] => u16::from_be_bytes([*a, 0]),
+            _ => 0,
+        };
+        sum += word as u32;
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    return !(sum as u16);
+}
+
+// Send an echo request and wait for the reply
+fn icmp_probe(
+    topology: &Topology,
+    probe: &Probe,
+    saddr: IpAddr,
+    daddr: IpAddr,
+    sequence: u16,
+) -> Result<(bool, String), String> {
+    let (domain, protocol, request, reply) = if daddr.is_ipv6() {
+        (Domain::IPV6, Protocol::ICMPV6, 128, 129)
+    } else {
+        (Domain::IPV4, Protocol::ICMPV4, 8, 0)
+    };
+    let mut socket = topology.socket(
+        &probe.iif,
+        domain,
+        Type::from(libc::SOCK_RAW),
+        Some(protocol),
+    )?;
+    bind(&socket, saddr, 0)?;
+    let identifier = (std::process::id() & 0xffff) as u16;
+    let mut packet = vec![request, 0, 0, 0];
+    packet.extend(identifier.to_be_bytes());
+    packet.extend(sequence.to_be_bytes());
+    packet.extend(PROBE_DATA);
+    // the kernel computes the checksum of icmpv6
+    if !daddr.is_ipv6() {
+        let checksum = icmp_checksum(&packet);
+        packet[2..4].copy_from_slice(&checksum.to_be_bytes());
+    }
+    socket
+        .send_to(&packet, &SockAddr::from(SocketAddr::new(daddr, 0)))
+        .map_err(|e| format!("Could not send to {}: {}", daddr, e))?;
+    let deadline = Instant::now() + PROBE_TIMEOUT;
+    let mut buffer = [0u8; 1500];
+    while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+        if remaining.is_zero() {
+            break;
+        }
+        socket
+            .set_read_timeout(Some(remaining))
+            .map_err(|e| e.to_string())?;
+        let size = match socket.read(&mut buffer) {
+            Ok(s) => s,
+            Err(e) if timed_out(&e) => break,
+            Err(e) => return Ok((false, e.to_string())),
+        };
+        // raw ipv4 sockets receive the ip header as well
+        let start = if daddr.is_ipv6() {
+            0
+        } else {
+            ((buffer[0] & 0x0f) as usize) * 4
+        };
+        let message = &buffer[start.min(size)..size];
+        if message.len() >= 8
+            && message[0] == reply
+            && message[4..6] == identifier.to_be_bytes()
+            && message[6..8] == sequence.to_be_bytes()
+        {
+            return Ok((true, String::from("echo reply received")));
+        }
+    }
+    return Ok((false, String::from("no echo reply")));
+}
+
+fn run_probe(topology: &Topology, probe: &Probe, sequence: u16) -> ProbeResult {
+    // the addresses were checked by verify
+    let saddr = probe.saddr.parse::<IpAddr>().unwrap();
+    let daddr = probe.daddr.parse::<IpAddr>().unwrap();
+    let result = match probe.protocol.as_str() {
+        "tcp" => tcp_probe(topology, probe, saddr, daddr),
+        "udp" => udp_probe(topology, probe, saddr, daddr),
+        _ => icmp_probe(topology, probe, saddr, daddr, sequence),
+    };
+    match result {
+        Ok((allowed, detail)) => {
+            return ProbeResult {
+                probe: probe.clone(),
+                allowed: allowed,
+                passed: allowed == probe.allow,
+                detail: detail,
+            };
+        }
+        // a probe that could not run neither passes nor fails the flow
+        Err(e) => {
+            return ProbeResult {
+                probe: probe.clone(),
+                allowed: false,
+                passed: false,
+                detail: e,
+            };
+        }
+    }
+}
+
+// Build the topology in the current network namespace, load the script and run the probes
+fn run_sandbox(spec: &Spec, unprivileged: bool) -> Result<SandboxReport, String> {
+    let mut topology = Topology::build(&spec.interfaces)?;
+    for probe in &spec.probes {
+        for (iface, address) in [(&probe.iif, &probe.saddr), (&probe.oif, &probe.daddr)] {
+            let address = address
+                .parse::<IpAddr>()
+                .map_err(|_e| format!("Invalid address {}", address))?;
+            topology.add_address(iface, address)?;
+        }
+    }
+    load(&spec.nft, &spec.script)?;
+    let results = spec
+        .probes
+        .iter()
+        .enumerate()
+        .map(|(index, probe)| run_probe(&topology, probe, index as u16))
+        .collect();
+    return Ok(SandboxReport {
+        unprivileged: unprivileged,
+        results: results,
+    });
+}
+
+// Run the sandbox in new namespaces and print the report, returns the exit code
+fn sandbox(spec: &Spec, unprivileged: bool) -> i32 {
+    let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+    let flags = if unprivileged {
+        libc::CLONE_NEWUSER | libc::CLONE_NEWNET
+    } else {
+        libc::CLONE_NEWNET
+    };
+    if let Err(e) = check(unsafe { libc::unshare(flags) }) {
+        eprintln!("Could not create the sandbox namespaces: {}", e);
+        return if unprivileged { NO_USER_NAMESPACE } else { 1 };
+    }
+    let result = (|| {
+        if unprivileged {
+            map_root(uid, gid)?;
+        }
+        return run_sandbox(spec, unprivileged);
+    })();
+    match result {
+        Ok(report) => {
+            println!("{}", serde_json::to_string(&report).unwrap());
+            return 0;
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            return 1;
+        }
+    }
+}
+
+// Entry point of "nftablesbuilder sandbox", returns the exit code
+pub fn main() -> i32 {
+    let mut input = String::new();
+    if let Err(e) = io::stdin().read_to_string(&mut input) {
+        eprintln!("Could not read the sandbox specification: {}", e);
+        return 1;
+    }
+    let spec: Spec = match serde_json::from_str(&input) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("Invalid sandbox specification: {}", e);
+            return 1;
+        }
+    };
+    if unsafe { libc::geteuid() } != 0 {
+        return sandbox(&spec, true);
+    }
+    // try without privileges in a child first, root cannot be regained afterwards
+    let pid = unsafe { libc::fork() };
+    if pid < 0 {
+        eprintln!(
+            "Could not start the sandbox: {}",
+            io::Error::last_os_error()
+        );
+        return 1;
+    }
+    if pid == 0 {
+        let code = match drop_privileges() {
+            Ok(()) => sandbox(&spec, true),
+            Err(e) => {
+                eprintln!("Could not drop privileges: {}", e);
+                NO_USER_NAMESPACE
+            }
+        };
+        std::process::exit(code);
+    }
+    let mut status: libc::c_int = 0;
+    if unsafe { libc::waitpid(pid, &mut status, 0) } < 0 {
+        eprintln!(
+            "Could not wait for the sandbox: {}",
+            io::Error::last_os_error()
+        );
+        return 1;
+    }
+    if !libc::WIFEXITED(status) {
+        eprintln!("The sandbox was killed");
+        return 1;
+    }
+    if libc::WEXITSTATUS(status) != NO_USER_NAMESPACE {
+        return libc::WEXITSTATUS(status);
+    }
+    eprintln!("User namespaces are not available, the sandbox runs as root");
+    return sandbox(&spec, false);
+}
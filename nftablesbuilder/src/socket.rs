@@ -0,0 +1,71 @@
+use std::fs;
+use std::io;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+
+// Process credentials of the other end of a Unix socket
+pub struct PeerCredentials {
+    pub pid: u32,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+// Listen on a Unix socket that only root and the given owner can connect to. A socket
+// left behind by an earlier run is replaced.
+pub fn listen(path: &str, uid: u32, gid: u32) -> Result<UnixListener, String> {
+    let socket = Path::new(path);
+    if let Some(dir) = socket.parent() {
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o755)
+            .create(dir)
+            .map_err(|e| format!("Could not create {}: {}", dir.display(), e))?;
+    }
+    match fs::remove_file(socket) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(format!("Could not remove {}: {}", path, e)),
+    }
+    let listener =
+        UnixListener::bind(socket).map_err(|e| format!("Could not bind {}: {}", path, e))?;
+    fs::set_permissions(socket, fs::Permissions::from_mode(0o600))
+        .map_err(|e| format!("Could not set permissions of {}: {}", path, e))?;
+    std::os::unix::fs::chown(socket, Some(uid), Some(gid))
+        .map_err(|e| format!("Could not set the owner of {}: {}", path, e))?;
+    listener
+        .set_nonblocking(true)
+        .map_err(|e| format!("Could not configure {}: {}", path, e))?;
+    return Ok(listener);
+}
+
+// The credentials the kernel recorded for the connecting process (SO_PEERCRED)
+pub fn peer_credentials(stream: &UnixStream) -> Result<PeerCredentials, String> {
+    let mut cred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if result != 0 {
+        return Err(format!(
+            "Could not get peer credentials: {}",
+            io::Error::last_os_error()
+        ));
+    }
+    return Ok(PeerCredentials {
+        pid: cred.pid as u32,
+        uid: cred.uid,
+        gid: cred.gid,
+    });
+}
@@ -0,0 +1,232 @@
+use crate::commands::{Context, run_input};
+use crate::executor::{LineSink, Process, Stream};
+use protocol::TraceMatch;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+// Table holding the rules that mark packets for tracing
+const TRACE_TABLE: &str = "nftablesbuilder_trace";
+const MAX_TRACE_SECONDS: u64 = 600;
+// Lines that are kept until they are read, further lines are dropped
+const MAX_TRACE_LINES: usize = 10000;
+
+// Lines printed by nft monitor that were not read yet, its errors start with "error: "
+#[derive(Default)]
+struct Output {
+    lines: Vec<String>,
+    dropped: usize,
+}
+
+// A running trace: the nft monitor process and the lines it has printed so far
+#[derive(Default)]
+pub struct Trace {
+    monitor: Option<Box<dyn Process>>,
+    output: Arc<Mutex<Output>>,
+    // incremented on every start so an old timer does not stop a newer trace
+    generation: u64,
+}
+
+fn valid_interface(name: &str) -> bool {
+    return name.len() > 0
+        && name.len() < 16
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '_');
+}
+
+// Parse an address or network, returns the nft address family keyword and the address
+fn trace_address(value: &str) -> Result<(&'static str, String), String> {
+    let (addr, prefix) = match value.split_once('/') {
+        Some((a, p)) => (a, Some(p)),
+        None => (value, None),
+    };
+    let ip = addr
+        .parse::<IpAddr>()
+        .map_err(|_e| format!("Invalid address {}", value))?;
+    let family = if ip.is_ipv6() { "ip6" } else { "ip" };
+    match prefix {
+        Some(p) => {
+            let max = if ip.is_ipv6() { 128 } else { 32 };
+            match p.parse::<u8>() {
+                Ok(bits) if bits <= max => return Ok((family, format!("{}/{}", ip, bits))),
+                _ => return Err(format!("Invalid address {}", value)),
+            }
+        }
+        None => return Ok((family, ip.to_string())),
+    }
+}
+
+// Build the nft match of a trace.
+// Returns the match for the prerouting chain and, when no input interface is
+// given, the match for the output chain.
+fn trace_match(m: &TraceMatch) -> Result<(String, Option<String>), String> {
+    let mut statements: Vec<String> = vec![];
+    let mut iif: Option<String> = None;
+    if let Some(name) = &m.iif {
+        if !valid_interface(name) {
+            return Err(format!("Invalid interface {}", name));
+        }
+        iif = Some(format!("iifname \"{}\"", name));
+    }
+    for (key, value) in [("saddr", &m.saddr), ("daddr", &m.daddr)] {
+        if let Some(v) = value {
+            let (family, address) = trace_address(v)?;
+            statements.push(format!("{} {} {}", family, key, address));
+        }
+    }
+    let protocol = match m.protocol.as_deref() {
+        None => None,
+        Some("tcp") => Some("tcp"),
+        Some("udp") => Some("udp"),
+        Some("icmp") => Some("icmp"),
+        Some("icmpv6") => Some("ipv6-icmp"),
+        Some(p) => return Err(format!("Invalid protocol {}", p)),
+    };
+    if iif.is_none() && statements.len() == 0 && protocol.is_none() {
+        return Err(String::from("A trace needs at least one match"));
+    }
+    if let Some(p) = protocol {
+        statements.push(format!("meta l4proto {}", p));
+    }
+    for (key, port) in [("sport", m.sport), ("dport", m.dport)] {
+        let Some(port) = port else {
+            continue;
+        };
+        match protocol {
+            Some("tcp") | Some("udp") => {
+                statements.push(format!("{} {} {}", protocol.unwrap(), key, port))
+            }
+            _ => return Err(String::from("Ports can only be traced for tcp or udp")),
+        }
+    }
+    let rule = statements.join(" ");
+    match iif {
+        Some(i) => return Ok((format!("{} {}", i, rule), None)),
+        None => return Ok((rule.clone(), Some(rule))),
+    }
+}
+
+fn run_nft(ctx: &Context, script: &str) -> Result<(), String> {
+    match run_input(ctx, &ctx.nft, &["-f", "-"], Some(script)) {
+        Ok(_output) => return Ok(()),
+        Err(r) => return Err(r.to_string()),
+    }
+}
+
+// Remove the trace rules and stop the monitor process
+fn stop_trace(trace: &mut Trace, ctx: &Context) -> Result<(), String> {
+    if let Some(mut monitor) = trace.monitor.take() {
+        monitor.stop();
+    }
+    // adding the table first makes the delete succeed when it does not exist
+    return run_nft(
+        ctx,
+        &format!("add table inet {0}\ndelete table inet {0}\n", TRACE_TABLE),
+    );
+}
+
+// Mark packets matching m for tracing and collect the nft monitor trace output,
+// the trace is stopped automatically after the given seconds
+pub fn start(
+    trace: &Arc<Mutex<Trace>>,
+    ctx: &Context,
+    seconds: u64,
+    m: &TraceMatch,
+) -> Result<(), String> {
+    if seconds == 0 || seconds > MAX_TRACE_SECONDS {
+        return Err(format!(
+            "Trace timeout must be 1 to {} seconds",
+            MAX_TRACE_SECONDS
+        ));
+    }
+    let (prerouting, output) = trace_match(m)?;
+
+    let mut guard = trace.lock().unwrap();
+    stop_trace(&mut guard, ctx)?;
+    // lines of an earlier monitor cannot end up in the new trace
+    guard.output = Arc::default();
+    guard.generation += 1;
+
+    // mark matching packets before any other chain sees them
+    let mut script = format!(
+        "table inet {} {{\n  chain prerouting {{\n    type filter hook prerouting priority raw - 10;\n    {} meta nftrace set 1\n  }}\n",
+        TRACE_TABLE, prerouting
+    );
+    if let Some(o) = output {
+        script.push_str(&format!(
+            "  chain output {{\n    type filter hook output priority raw - 10;\n    {} meta nftrace set 1\n  }}\n",
+            o
+        ));
+    }
+    script.push_str("}\n");
+    run_nft(ctx, &script)?;
+
+    // collect the monitor output until the process is stopped
+    let output = Arc::clone(&guard.output);
+    let sink: LineSink = Arc::new(move |stream, line| {
+        let mut output = output.lock().unwrap();
+        if output.lines.len() >= MAX_TRACE_LINES {
+            output.dropped += 1;
+            return;
+        }
+        match stream {
+            Stream::Stdout => output.lines.push(line),
+            Stream::Stderr => output.lines.push(format!("error: {}", line)),
+        }
+    });
+    let monitor = match ctx.executor.spawn(&ctx.nft, &["monitor", "trace"], sink) {
+        Ok(m) => m,
+        Err(e) => {
+            let _ = stop_trace(&mut guard, ctx);
+            return Err(format!("Could not run {}: {}", ctx.nft, e));
+        }
+    };
+    guard.monitor = Some(monitor);
+
+    // remove the trace rules automatically after the timeout
+    let generation = guard.generation;
+    let timer_trace = Arc::clone(trace);
+    let timer_ctx = ctx.clone();
+    thread::spawn(move || {
+        thread::sleep(Duration::from_secs(seconds));
+        let mut guard = timer_trace.lock().unwrap();
+        if guard.generation == generation && guard.monitor.is_some() {
+            let _ = stop_trace(&mut guard, &timer_ctx);
+        }
+    });
+    return Ok(());
+}
+
+// The lines printed since the last read, preceded by
+// "RUNNING" or "STOPPED"
+pub fn read(trace: &Arc<Mutex<Trace>>) -> String {
+    let guard = trace.lock().unwrap();
+    let status = if guard.monitor.is_some() {
+        "RUNNING"
+    } else {
+        "STOPPED"
+    };
+    let mut response = vec![String::from(status)];
+    let mut output = guard.output.lock().unwrap();
+    response.append(&mut output.lines);
+    if output.dropped > 0 {
+        response.push(format!(
+            "error: {} lines of the trace were dropped",
+            output.dropped
+        ));
+        output.dropped = 0;
+    }
+    return response.join("\n");
+}
+
+pub fn is_running(trace: &Arc<Mutex<Trace>>) -> bool {
+    return trace.lock().unwrap().monitor.is_some();
+}
+
+// Stop a running trace
+pub fn stop(trace: &Arc<Mutex<Trace>>, ctx: &Context) -> Result<(), String> {
+    let mut guard = trace.lock().unwrap();
+    return stop_trace(&mut guard, ctx);
+}
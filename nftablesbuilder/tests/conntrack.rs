@@ -0,0 +1,91 @@
+// Deleting connection tracking entries with a recording executor
+#![allow(
+    clippy::needless_return,
+    clippy::len_zero,
+    clippy::redundant_field_names
+)]
+
+use nftablesbuilder::commands::{Context, handle};
+use nftablesbuilder::executor::{CommandOutput, RecordingExecutor};
+use nftablesbuilder::{confirm, trace};
+use protocol::{ConntrackTuple, Request, Response};
+use std::sync::{Arc, Mutex};
+
+fn delete(executor: &Arc<RecordingExecutor>, entries: Vec<ConntrackTuple>) -> Response {
+    let ctx = Context {
+        nft: String::from("nft"),
+        test: String::from("/nonexistent/test.nft"),
+        conf: String::from("/nonexistent/nftables.conf"),
+        reload: String::from("true"),
+        program: String::from("nftablesbuilder"),
+        executor: executor.clone(),
+    };
+    let pending = Arc::new(Mutex::new(confirm::Pending::default()));
+    let trace = Arc::new(Mutex::new(trace::Trace::default()));
+    return handle(Request::ConntrackDelete { entries }, &ctx, &pending, &trace);
+}
+
+fn tuple(protocol: &str, dst: &str, dport: Option<u16>) -> ConntrackTuple {
+    return ConntrackTuple {
+        protocol: String::from(protocol),
+        src: String::from("10.0.0.2"),
+        dst: String::from(dst),
+        sport: dport.map(|_p| 40000),
+        dport: dport,
+    };
+}
+
+#[test]
+fn entries_are_deleted_in_one_request() {
+    let executor = Arc::new(RecordingExecutor::new());
+    // the connection to the database ended before it was deleted
+    executor.respond(
+        &[
+            "conntrack",
+            "-D",
+            "-f",
+            "ipv4",
+            "-p",
+            "tcp",
+            "-s",
+            "10.0.0.2",
+            "-d",
+            "192.0.2.20",
+        ],
+        CommandOutput::failure(1, "0 flow entries have been deleted."),
+    );
+    let entries = vec![
+        tuple("udp", "192.0.2.20", Some(53)),
+        tuple("tcp", "192.0.2.20", Some(5432)),
+        tuple("icmp", "192.0.2.10", None),
+    ];
+    match delete(&executor, entries) {
+        Response::Deleted { count } => assert_eq!(count, 2),
+        response => panic!("unexpected response {}", response),
+    }
+    let commands: Vec<String> = executor
+        .calls()
+        .into_iter()
+        .map(|c| c.command.join(" "))
+        .collect();
+    assert_eq!(
+        commands,
+        [
+            "conntrack -D -f ipv4 -p udp -s 10.0.0.2 -d 192.0.2.20 --sport 40000 --dport 53",
+            "conntrack -D -f ipv4 -p tcp -s 10.0.0.2 -d 192.0.2.20 --sport 40000 --dport 5432",
+            "conntrack -D -f ipv4 -p icmp -s 10.0.0.2 -d 192.0.2.10",
+        ]
+    );
+}
+
+#[test]
+fn invalid_entry_deletes_nothing() {
+    let executor = Arc::new(RecordingExecutor::new());
+    let entries = vec![
+        tuple("udp", "192.0.2.20", Some(53)),
+        tuple("tcp", "192.0.2.20 -F", Some(5432)),
+    ];
+    let response = delete(&executor, entries);
+    assert!(matches!(response, Response::Error { .. }), "{}", response);
+    assert_eq!(executor.calls().len(), 0);
+}
@@ -0,0 +1,26 @@
+// The system executor with real programs
+#![allow(
+    clippy::needless_return,
+    clippy::len_zero,
+    clippy::redundant_field_names
+)]
+
+use nftablesbuilder::executor::{Executor, SystemExecutor};
+
+#[test]
+fn large_input_and_output() {
+    // more than a pipe holds in both directions, cat writes output while it reads input
+    let input = "0123456789abcdef\n".repeat(64 * 1024);
+    let output = SystemExecutor.run("cat", &[], Some(&input)).unwrap();
+    assert_eq!(output.exit_code, Some(0));
+    assert_eq!(output.stdout.len(), input.len());
+}
+
+#[test]
+fn exit_code_and_stderr() {
+    let output = SystemExecutor
+        .run("sh", &["-c", "echo failed >&2; exit 3"], None)
+        .unwrap();
+    assert_eq!(output.exit_code, Some(3));
+    assert_eq!(output.stderr, "failed\n");
+}
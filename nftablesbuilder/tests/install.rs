@@ -0,0 +1,239 @@
+// The install pipeline with a recording executor: nothing is run, the scripts are
+// written to a temporary directory
+#![allow(
+    clippy::needless_return,
+    clippy::len_zero,
+    clippy::redundant_field_names
+)]
+
+use nftablesbuilder::commands::{Context, handle};
+use nftablesbuilder::executor::{CommandOutput, RecordingExecutor};
+use nftablesbuilder::{confirm, trace};
+use protocol::{Request, Response};
+use std::fs;
+use std::sync::{Arc, Mutex};
+use tempfile::TempDir;
+
+const SCRIPT: &str = "table inet filter {\n  chain input {\n    type filter hook input priority 0; policy drop;\n  }\n}";
+const PREVIOUS: &str = "flush ruleset\n";
+
+struct Helper {
+    dir: TempDir,
+    executor: Arc<RecordingExecutor>,
+    ctx: Context,
+    pending: Arc<Mutex<confirm::Pending>>,
+    trace: Arc<Mutex<trace::Trace>>,
+}
+
+impl Helper {
+    fn new() -> Helper {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name).to_string_lossy().to_string();
+        let executor = Arc::new(RecordingExecutor::new());
+        let ctx = Context {
+            nft: String::from("nft"),
+            test: path("test.nft"),
+            conf: path("nftables.conf"),
+            reload: String::from("systemctl reload 'nftables.service'"),
+            program: String::from("nftablesbuilder"),
+            executor: executor.clone(),
+        };
+        fs::write(&ctx.conf, PREVIOUS).unwrap();
+        return Helper {
+            dir: dir,
+            executor: executor,
+            ctx: ctx,
+            pending: Arc::new(Mutex::new(confirm::Pending::default())),
+            trace: Arc::new(Mutex::new(trace::Trace::default())),
+        };
+    }
+
+    fn handle(&self, request: Request) -> Response {
+        return handle(request, &self.ctx, &self.pending, &self.trace);
+    }
+
+    fn install(&self, script: &str) -> Response {
+        return self.handle(Request::Install {
+            script: String::from(script),
+            user: String::from("admin"),
+            name: String::from("office"),
+        });
+    }
+
+    fn commands(&self) -> Vec<Vec<String>> {
+        return self
+            .executor
+            .calls()
+            .into_iter()
+            .map(|c| c.command)
+            .collect();
+    }
+
+    fn conf(&self) -> String {
+        return fs::read_to_string(&self.ctx.conf).unwrap();
+    }
+
+    fn history(&self) -> serde_json::Value {
+        let Response::Text { text } = self.handle(Request::HistoryList) else {
+            panic!("history list failed");
+        };
+        return serde_json::from_str(&text).unwrap();
+    }
+}
+
+fn command(words: &[&str]) -> Vec<String> {
+    return words.iter().map(|w| String::from(*w)).collect();
+}
+
+#[test]
+fn install_checks_copies_and_reloads() {
+    let helper = Helper::new();
+    let response = helper.install(SCRIPT);
+    assert!(response.is_ok(), "{}", response);
+    assert_eq!(
+        helper.commands(),
+        vec![
+            command(&["nft", "-c", "-f", &helper.ctx.test]),
+            command(&["systemctl", "reload", "nftables.service"]),
+        ]
+    );
+    assert_eq!(fs::read_to_string(&helper.ctx.test).unwrap(), SCRIPT);
+    assert_eq!(helper.conf(), SCRIPT);
+
+    let history = helper.history();
+    assert_eq!(history.as_array().unwrap().len(), 1);
+    assert_eq!(history[0]["user"], "admin");
+    assert_eq!(history[0]["name"], "office");
+}
+
+#[test]
+fn failing_check_leaves_the_ruleset_alone() {
+    let helper = Helper::new();
+    helper.executor.respond(
+        &["nft", "-c"],
+        CommandOutput::failure(1, "test.nft:3:5-9: Error: syntax error"),
+    );
+    let response = helper.install(SCRIPT);
+    match response {
+        Response::Failed { exit_code, stderr } => {
+            assert_eq!(exit_code, Some(1));
+            assert!(stderr.contains("syntax error"));
+        }
+        r => panic!("unexpected response {}", r),
+    }
+    // no reload and nothing archived
+    assert_eq!(helper.commands().len(), 1);
+    assert_eq!(helper.conf(), PREVIOUS);
+    assert_eq!(helper.history().as_array().unwrap().len(), 0);
+}
+
+#[test]
+fn failing_reload_is_reported() {
+    let helper = Helper::new();
+    helper.executor.respond(
+        &["systemctl"],
+        CommandOutput::failure(4, "Unit nftables.service not found."),
+    );
+    let response = helper.install(SCRIPT);
+    assert_eq!(response.to_string(), "Unit nftables.service not found.");
+    assert_eq!(helper.commands().len(), 2);
+    assert_eq!(helper.history().as_array().unwrap().len(), 0);
+}
+
+#[test]
+fn invalid_reload_command_is_an_error() {
+    let mut helper = Helper::new();
+    helper.ctx.reload = String::from("systemctl reload 'nftables");
+    let response = helper.install(SCRIPT);
+    assert_eq!(response.to_string(), "Invalid reload command");
+}
+
+#[test]
+fn check_does_not_install() {
+    let helper = Helper::new();
+    let response = helper.handle(Request::Check {
+        script: String::from(SCRIPT),
+    });
+    assert!(matches!(response, Response::Text { .. }), "{}", response);
+    let calls = helper.commands();
+    assert_eq!(calls.len(), 1);
+    assert_eq!(calls[0][..3], command(&["nft", "-c", "-f"]));
+    // the script is checked in a temporary file that is removed afterwards
+    assert!(!std::path::Path::new(&calls[0][3]).exists());
+    assert_eq!(helper.conf(), PREVIOUS);
+    assert!(!std::path::Path::new(&helper.ctx.test).exists());
+}
+
+#[test]
+fn installed_script_is_the_conf() {
+    let helper = Helper::new();
+    assert!(helper.install(SCRIPT).is_ok());
+    match helper.handle(Request::InstalledScript) {
+        Response::Text { text } => assert_eq!(text, SCRIPT),
+        response => panic!("unexpected response {}", response),
+    }
+    fs::remove_file(&helper.ctx.conf).unwrap();
+    assert!(matches!(
+        helper.handle(Request::InstalledScript),
+        Response::Error { .. }
+    ));
+}
+
+#[test]
+fn rollback_installs_the_previous_generation() {
+    let helper = Helper::new();
+    assert!(helper.install(PREVIOUS).is_ok());
+    assert!(helper.install(SCRIPT).is_ok());
+    let response = helper.handle(Request::Rollback {
+        user: String::from("admin"),
+    });
+    assert!(response.is_ok(), "{}", response);
+    assert_eq!(helper.conf(), PREVIOUS);
+    let history = helper.history();
+    assert_eq!(history.as_array().unwrap().len(), 3);
+    assert_eq!(history[2]["name"], "rollback to generation 1");
+    assert!(helper.dir.path().join("nftables.conf.history").is_dir());
+}
+
+#[test]
+fn unconfirmed_install_is_rolled_back() {
+    let helper = Helper::new();
+    let response = helper.handle(Request::InstallConfirm {
+        script: String::from(SCRIPT),
+        seconds: 60,
+        user: String::from("admin"),
+        name: String::from("office"),
+    });
+    assert!(response.is_ok(), "{}", response);
+    assert_eq!(helper.conf(), SCRIPT);
+    let response = helper.handle(Request::Rollback {
+        user: String::from("admin"),
+    });
+    assert!(response.is_ok(), "{}", response);
+    assert_eq!(helper.conf(), PREVIOUS);
+    assert!(
+        helper
+            .handle(Request::Confirm)
+            .to_string()
+            .contains("No install")
+    );
+}
+
+#[test]
+fn unconfirmed_first_install_rolls_back_to_an_empty_ruleset() {
+    let helper = Helper::new();
+    fs::remove_file(&helper.ctx.conf).unwrap();
+    let response = helper.handle(Request::InstallConfirm {
+        script: String::from(SCRIPT),
+        seconds: 60,
+        user: String::from("admin"),
+        name: String::from("office"),
+    });
+    assert!(response.is_ok(), "{}", response);
+    assert_eq!(helper.conf(), SCRIPT);
+    let response = helper.handle(Request::Rollback {
+        user: String::from("admin"),
+    });
+    assert!(response.is_ok(), "{}", response);
+    assert_eq!(helper.conf(), "flush ruleset\n");
+}
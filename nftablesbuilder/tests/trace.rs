@@ -0,0 +1,170 @@
+// Packet traces with a recording executor: nft monitor prints captured trace lines and
+// keeps running until the trace is stopped
+#![allow(
+    clippy::needless_return,
+    clippy::len_zero,
+    clippy::redundant_field_names
+)]
+
+use nftablesbuilder::commands::{Context, handle};
+use nftablesbuilder::executor::{CommandOutput, RecordingExecutor};
+use nftablesbuilder::{confirm, trace};
+use protocol::{Request, Response, TraceMatch};
+use std::sync::{Arc, Mutex};
+
+// Output of nft monitor trace for a tcp connection from the office to the web server
+const MONITOR: &str = r#"trace id 6a2c0b1f inet filter_inet all_forward packet: iif "eth0" oif "eth1" ether saddr 52:54:00:12:34:56 ether daddr 52:54:00:ab:cd:ef ip saddr 10.0.0.2 ip daddr 192.0.2.10 ip dscp cs0 ip ecn not-ect ip ttl 63 ip id 54321 ip length 60 tcp sport 40000 tcp dport 443 tcp flags == syn tcp window 64240
+trace id 6a2c0b1f inet filter_inet all_forward rule iifname "eth0" oifname "eth1" jump lan_dmz (verdict jump lan_dmz)
+trace id 6a2c0b1f inet filter_inet lan_dmz rule ip saddr 10.0.0.0/24 ip daddr 192.0.2.10 tcp dport 443 accept comment "nb:filter:lan_dmz:1" (verdict accept)"#;
+
+struct Helper {
+    executor: Arc<RecordingExecutor>,
+    ctx: Context,
+    pending: Arc<Mutex<confirm::Pending>>,
+    trace: Arc<Mutex<trace::Trace>>,
+}
+
+impl Helper {
+    fn new() -> Helper {
+        let executor = Arc::new(RecordingExecutor::new());
+        let ctx = Context {
+            nft: String::from("nft"),
+            test: String::from("/nonexistent/test.nft"),
+            conf: String::from("/nonexistent/nftables.conf"),
+            reload: String::from("true"),
+            program: String::from("nftablesbuilder"),
+            executor: executor.clone(),
+        };
+        return Helper {
+            executor: executor,
+            ctx: ctx,
+            pending: Arc::new(Mutex::new(confirm::Pending::default())),
+            trace: Arc::new(Mutex::new(trace::Trace::default())),
+        };
+    }
+
+    fn handle(&self, request: Request) -> Response {
+        return handle(request, &self.ctx, &self.pending, &self.trace);
+    }
+
+    fn start(&self, m: TraceMatch) -> Response {
+        return self.handle(Request::TraceStart {
+            seconds: 60,
+            trace: m,
+        });
+    }
+
+    // scripts that were loaded with nft -f -
+    fn scripts(&self) -> Vec<String> {
+        return self
+            .executor
+            .calls()
+            .into_iter()
+            .filter_map(|c| c.input)
+            .collect();
+    }
+}
+
+fn https_from_office() -> TraceMatch {
+    return TraceMatch {
+        saddr: Some(String::from("10.0.0.0/24")),
+        protocol: Some(String::from("tcp")),
+        dport: Some(443),
+        ..Default::default()
+    };
+}
+
+#[test]
+fn trace_marks_packets_and_collects_the_monitor_output() {
+    let helper = Helper::new();
+    helper.executor.respond(
+        &["nft", "monitor", "trace"],
+        CommandOutput::success(MONITOR),
+    );
+    let response = helper.start(https_from_office());
+    assert!(response.is_ok(), "{}", response);
+
+    // without an input interface the packets are also marked in the output chain
+    let scripts = helper.scripts();
+    let marks = scripts.last().unwrap();
+    assert!(
+        marks.contains("ip saddr 10.0.0.0/24 meta l4proto tcp tcp dport 443 meta nftrace set 1")
+    );
+    assert!(marks.contains("chain output"));
+
+    let read = helper.handle(Request::TraceRead).to_string();
+    let mut lines = read.lines();
+    assert_eq!(lines.next(), Some("RUNNING"));
+    assert_eq!(
+        lines.collect::<Vec<&str>>(),
+        MONITOR.lines().collect::<Vec<&str>>()
+    );
+    // lines are only returned once
+    assert_eq!(helper.handle(Request::TraceRead).to_string(), "RUNNING");
+
+    assert!(helper.handle(Request::TraceStop).is_ok());
+    assert_eq!(helper.executor.stopped(), 1);
+    assert_eq!(helper.handle(Request::TraceRead).to_string(), "STOPPED");
+}
+
+#[test]
+fn monitor_errors_are_returned() {
+    let helper = Helper::new();
+    helper.executor.respond(
+        &["nft", "monitor", "trace"],
+        CommandOutput::failure(1, "Error: Operation not permitted"),
+    );
+    assert!(helper.start(https_from_office()).is_ok());
+    assert_eq!(
+        helper.handle(Request::TraceRead).to_string(),
+        "RUNNING\nerror: Error: Operation not permitted"
+    );
+}
+
+#[test]
+fn input_interface_is_only_marked_in_prerouting() {
+    let helper = Helper::new();
+    let m = TraceMatch {
+        iif: Some(String::from("eth0")),
+        daddr: Some(String::from("2001:db8::10")),
+        ..Default::default()
+    };
+    assert!(helper.start(m).is_ok());
+    let scripts = helper.scripts();
+    let marks = scripts.last().unwrap();
+    assert!(marks.contains("iifname \"eth0\" ip6 daddr 2001:db8::10 meta nftrace set 1"));
+    assert!(!marks.contains("chain output"));
+}
+
+#[test]
+fn invalid_matches_are_refused() {
+    let helper = Helper::new();
+    let invalid = [
+        TraceMatch::default(),
+        TraceMatch {
+            iif: Some(String::from("eth0\" accept")),
+            ..Default::default()
+        },
+        TraceMatch {
+            saddr: Some(String::from("10.0.0.0/33")),
+            ..Default::default()
+        },
+        TraceMatch {
+            protocol: Some(String::from("icmp")),
+            dport: Some(22),
+            ..Default::default()
+        },
+    ];
+    for m in invalid {
+        let response = helper.start(m);
+        assert!(!response.is_ok(), "{}", response);
+    }
+    // nothing was spawned for a refused trace
+    assert!(
+        helper
+            .executor
+            .calls()
+            .iter()
+            .all(|c| c.command[1] != "monitor")
+    );
+}
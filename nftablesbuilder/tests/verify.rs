@@ -0,0 +1,113 @@
+// Verification requests with a recording executor, the sandbox process is not started
+#![allow(
+    clippy::needless_return,
+    clippy::len_zero,
+    clippy::redundant_field_names
+)]
+
+use nftablesbuilder::commands::{Context, handle};
+use nftablesbuilder::executor::{CommandOutput, RecordingExecutor};
+use nftablesbuilder::{confirm, trace};
+use protocol::{Probe, Request, Response};
+use std::sync::{Arc, Mutex};
+
+const REPORT: &str = r#"{"unprivileged":true,"results":[{"probe":{"name":"web","iif":"lan","oif":"wan","saddr":"10.0.0.2","daddr":"192.0.2.10","protocol":"tcp","dport":80,"allow":true},"allowed":false,"passed":false,"detail":"no answer"}]}"#;
+
+fn verify(executor: &Arc<RecordingExecutor>, probes: Vec<Probe>) -> Response {
+    let ctx = Context {
+        nft: String::from("nft"),
+        test: String::from("/nonexistent/test.nft"),
+        conf: String::from("/nonexistent/nftables.conf"),
+        reload: String::from("true"),
+        program: String::from("nftablesbuilder"),
+        executor: executor.clone(),
+    };
+    let request = Request::Verify {
+        script: String::from("flush ruleset"),
+        interfaces: vec![String::from("lan"), String::from("wan")],
+        probes: probes,
+    };
+    let pending = Arc::new(Mutex::new(confirm::Pending::default()));
+    let trace = Arc::new(Mutex::new(trace::Trace::default()));
+    return handle(request, &ctx, &pending, &trace);
+}
+
+fn web() -> Probe {
+    return Probe {
+        name: String::from("web"),
+        iif: String::from("lan"),
+        oif: String::from("wan"),
+        saddr: String::from("10.0.0.2"),
+        daddr: String::from("192.0.2.10"),
+        protocol: String::from("tcp"),
+        dport: Some(80),
+        allow: true,
+    };
+}
+
+#[test]
+fn report_of_the_sandbox_is_returned() {
+    let executor = Arc::new(RecordingExecutor::new());
+    executor.respond(
+        &["nftablesbuilder", "sandbox"],
+        CommandOutput::success(REPORT),
+    );
+    let Response::Sandbox { report } = verify(&executor, vec![web()]) else {
+        panic!("no sandbox report");
+    };
+    assert_eq!(report.results.len(), 1);
+    assert!(!report.results[0].passed);
+
+    let calls = executor.calls();
+    assert_eq!(calls.len(), 1);
+    let spec: serde_json::Value = serde_json::from_str(calls[0].input.as_ref().unwrap()).unwrap();
+    assert_eq!(spec["nft"], "nft");
+    assert_eq!(spec["script"], "flush ruleset");
+    assert_eq!(spec["probes"][0]["daddr"], "192.0.2.10");
+}
+
+#[test]
+fn failing_sandbox_is_reported() {
+    let executor = Arc::new(RecordingExecutor::new());
+    executor.respond(
+        &["nftablesbuilder", "sandbox"],
+        CommandOutput::failure(1, "Could not load the ruleset: syntax error"),
+    );
+    let response = verify(&executor, vec![web()]);
+    assert_eq!(
+        response.to_string(),
+        "Could not load the ruleset: syntax error"
+    );
+}
+
+#[test]
+fn invalid_probes_are_rejected() {
+    let cases = [
+        Probe {
+            oif: String::from("dmz"),
+            ..web()
+        },
+        Probe {
+            oif: String::from("lan"),
+            ..web()
+        },
+        Probe {
+            daddr: String::from("2001:db8::10"),
+            ..web()
+        },
+        Probe {
+            dport: None,
+            ..web()
+        },
+        Probe {
+            protocol: String::from("sctp"),
+            ..web()
+        },
+    ];
+    for probe in cases {
+        let executor = Arc::new(RecordingExecutor::new());
+        let response = verify(&executor, vec![probe]);
+        assert!(matches!(response, Response::Error { .. }), "{}", response);
+        assert_eq!(executor.calls().len(), 0);
+    }
+}
@@ -0,0 +1,466 @@
+// explicit returns and len() comparisons are the style of this repository
+#![allow(clippy::needless_return, clippy::len_zero)]
+
+use openssl::rand::rand_bytes;
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+// Messages exchanged between the webserver and the privileged nftablesbuilder process.
+//
+// nftablesbuilder listens on SOCKET_PATH and passes the key on the stdin of the
+// webserver it starts, the webserver then connects to the socket.
+//
+// Every message is one line of hex encoded bytes:
+//   version (4) | direction (1) | sequence number (8) | nonce (12) | tag (16) | ciphertext
+// The ciphertext is the json form of a Request or Response, encrypted with AES-256-GCM.
+// Version, direction and sequence number are authenticated as additional data, so a
+// message can not be replayed, reordered or reflected back to its sender.
+
+pub const VERSION: u32 = 1;
+pub const KEY_LENGTH: usize = 32;
+
+const NONCE_LENGTH: usize = 12;
+const TAG_LENGTH: usize = 16;
+const HEADER_LENGTH: usize = 4 + 1 + 8;
+
+// Unix socket of nftablesbuilder, only the webserver it started may connect
+pub const SOCKET_PATH: &str = "/run/nftablesbuilder/helper.sock";
+
+// Name of the script in the messages of a Check request
+pub const CHECK_FILE: &str = "check.nft";
+
+// Direction byte of requests (webserver to nftablesbuilder) and responses
+pub const REQUEST: u8 = b'Q';
+pub const RESPONSE: u8 = b'R';
+
+// Match of a packet trace, fields that are not set match any packet
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct TraceMatch {
+    pub iif: Option<String>,
+    pub saddr: Option<String>,
+    pub daddr: Option<String>,
+    pub protocol: Option<String>,
+    pub sport: Option<u16>,
+    pub dport: Option<u16>,
+}
+
+// A rule counter of the active ruleset
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Counter {
+    pub family: String,
+    pub table: String,
+    pub chain: String,
+    pub handle: u64,
+    // rule id of rules generated by nftablesbuilder
+    pub comment: Option<String>,
+    pub packets: u64,
+    pub bytes: u64,
+}
+
+// The original tuple of a connection tracking entry
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct ConntrackTuple {
+    pub protocol: String,
+    pub src: String,
+    pub dst: String,
+    pub sport: Option<u16>,
+    pub dport: Option<u16>,
+}
+
+// State of the nftablesbuilder process
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct HelperStatus {
+    pub version: u32,
+    // an install is waiting for confirmation
+    pub confirm_pending: bool,
+    pub trace_running: bool,
+    // last archived generation
+    pub generation: Option<u64>,
+    // sha256 of the installed conf file
+    pub conf_sha256: Option<String>,
+}
+
+// A connection attempt in the verification sandbox. Interfaces are system names, an
+// empty iif means the router itself sends the packet, an empty oif that it is the
+// destination.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Probe {
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub iif: String,
+    #[serde(default)]
+    pub oif: String,
+    pub saddr: String,
+    pub daddr: String,
+    // tcp, udp or icmp
+    pub protocol: String,
+    #[serde(default)]
+    pub dport: Option<u16>,
+    // the flow is expected to pass
+    pub allow: bool,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ProbeResult {
+    pub probe: Probe,
+    // the probe reached its destination
+    pub allowed: bool,
+    pub passed: bool,
+    pub detail: String,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct SandboxReport {
+    // the sandbox ran in a user namespace without privileges on the host
+    pub unprivileged: bool,
+    pub results: Vec<ProbeResult>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Request {
+    // check a script with nft -c without installing it, the warnings are returned
+    // as text
+    Check {
+        script: String,
+    },
+    // write the script to the test file, check it and install it, user and name are
+    // archived with it
+    Install {
+        script: String,
+        user: String,
+        name: String,
+    },
+    // install and roll back unless confirmed within the given seconds
+    InstallConfirm {
+        script: String,
+        seconds: u64,
+        user: String,
+        name: String,
+    },
+    Confirm,
+    // roll back an unconfirmed install, or else to the previous generation
+    Rollback {
+        user: String,
+    },
+    // load a script in throwaway network namespaces with an interface for every one of
+    // interfaces and run the probes through it
+    Verify {
+        script: String,
+        interfaces: Vec<String>,
+        probes: Vec<Probe>,
+    },
+    ListRuleset,
+    // the installed script, which the webserver cannot read itself
+    InstalledScript,
+    ListCounters,
+    // reset the rule counters of a table
+    ResetCounters {
+        family: String,
+        table: String,
+    },
+    ListSetElements {
+        family: String,
+        table: String,
+        set: String,
+    },
+    AddSetElements {
+        family: String,
+        table: String,
+        set: String,
+        elements: Vec<String>,
+    },
+    DeleteSetElements {
+        family: String,
+        table: String,
+        set: String,
+        elements: Vec<String>,
+    },
+    ConntrackList,
+    // delete the connection tracking entries, entries that do not exist anymore are
+    // skipped and the number of deleted entries is returned
+    ConntrackDelete {
+        entries: Vec<ConntrackTuple>,
+    },
+    TraceStart {
+        seconds: u64,
+        trace: TraceMatch,
+    },
+    TraceRead,
+    TraceStop,
+    HistoryList,
+    HistoryShow {
+        generation: u64,
+    },
+    HistoryRollback {
+        generation: u64,
+        user: String,
+    },
+    Status,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum Response {
+    Ok,
+    Error {
+        message: String,
+    },
+    // a command that ran but failed, with its exit code when it was not killed
+    Failed {
+        exit_code: Option<i32>,
+        stderr: String,
+    },
+    // command output like listings and scripts
+    Text {
+        text: String,
+    },
+    Counters {
+        counters: Vec<Counter>,
+    },
+    SetElements {
+        elements: Vec<String>,
+    },
+    Status {
+        status: HelperStatus,
+    },
+    Sandbox {
+        report: SandboxReport,
+    },
+    Deleted {
+        count: usize,
+    },
+}
+
+impl Response {
+    pub fn is_ok(&self) -> bool {
+        return matches!(self, Response::Ok);
+    }
+}
+
+// "OK", the error or the output of a response
+impl fmt::Display for Response {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Response::Ok => return write!(f, "OK"),
+            Response::Error { message } => return write!(f, "{}", message),
+            Response::Failed { exit_code, stderr } if stderr.len() == 0 => match exit_code {
+                Some(code) => return write!(f, "Failed with exit code {}", code),
+                None => return write!(f, "Failed"),
+            },
+            Response::Failed { stderr, .. } => return write!(f, "{}", stderr),
+            Response::Text { text } => return write!(f, "{}", text),
+            _ => return write!(f, "{}", serde_json::to_string(self).unwrap()),
+        }
+    }
+}
+
+// One side of the encrypted channel. Both sides count the messages they send and
+// receive, a message is only accepted with the next expected sequence number.
+pub struct Channel {
+    key: Vec<u8>,
+    sent: u64,
+    received: u64,
+}
+
+impl Channel {
+    pub fn new(key: &[u8]) -> Result<Channel, String> {
+        if key.len() != KEY_LENGTH {
+            return Err(String::from("Invalid key length"));
+        }
+        return Ok(Channel {
+            key: key.to_vec(),
+            sent: 0,
+            received: 0,
+        });
+    }
+
+    // Generate a random key for a new channel
+    pub fn generate_key() -> Result<Vec<u8>, String> {
+        let mut key = vec![0; KEY_LENGTH];
+        rand_bytes(&mut key).map_err(|e| e.to_string())?;
+        return Ok(key);
+    }
+
+    fn header(direction: u8, sequence: u64) -> Vec<u8> {
+        let mut header = Vec::with_capacity(HEADER_LENGTH);
+        header.extend_from_slice(&VERSION.to_be_bytes());
+        header.push(direction);
+        header.extend_from_slice(&sequence.to_be_bytes());
+        return header;
+    }
+
+    // Encrypt a message into a hex line without line ending. The sequence number is only
+    // used up by mark_sent, once the line has been written.
+    pub fn seal<T: Serialize>(&self, direction: u8, message: &T) -> Result<String, String> {
+        let plaintext = serde_json::to_vec(message).map_err(|e| e.to_string())?;
+        let header = Channel::header(direction, self.sent);
+        let mut nonce = [0; NONCE_LENGTH];
+        rand_bytes(&mut nonce).map_err(|e| e.to_string())?;
+        let mut tag = [0; TAG_LENGTH];
+        let ciphertext = encrypt_aead(
+            Cipher::aes_256_gcm(),
+            &self.key,
+            Some(&nonce),
+            &header,
+            &plaintext,
+            &mut tag,
+        )
+        .map_err(|e| e.to_string())?;
+
+        let mut frame = header;
+        frame.extend_from_slice(&nonce);
+        frame.extend_from_slice(&tag);
+        frame.extend_from_slice(&ciphertext);
+        return Ok(hex::encode(frame));
+    }
+
+    // Count the last sealed message as sent, the next one gets the next sequence number
+    pub fn mark_sent(&mut self) {
+        self.sent += 1;
+    }
+
+    // Decrypt and authenticate a hex line, checking version, direction and sequence number
+    pub fn open<T: DeserializeOwned>(&mut self, direction: u8, line: &str) -> Result<T, String> {
+        let frame =
+            hex::decode(line.trim()).map_err(|_e| String::from("Invalid message encoding"))?;
+        if frame.len() < HEADER_LENGTH + NONCE_LENGTH + TAG_LENGTH {
+            return Err(String::from("Message too short"));
+        }
+        let version = u32::from_be_bytes(frame[0..4].try_into().unwrap());
+        if version != VERSION {
+            return Err(format!("Unsupported protocol version {}", version));
+        }
+        if frame[4] != direction {
+            return Err(String::from("Message has the wrong direction"));
+        }
+        let sequence = u64::from_be_bytes(frame[5..HEADER_LENGTH].try_into().unwrap());
+        if sequence != self.received {
+            return Err(format!(
+                "Unexpected sequence number {}, expected {}",
+                sequence, self.received
+            ));
+        }
+        let nonce = &frame[HEADER_LENGTH..HEADER_LENGTH + NONCE_LENGTH];
+        let tag = &frame[HEADER_LENGTH + NONCE_LENGTH..HEADER_LENGTH + NONCE_LENGTH + TAG_LENGTH];
+        let ciphertext = &frame[HEADER_LENGTH + NONCE_LENGTH + TAG_LENGTH..];
+        let plaintext = decrypt_aead(
+            Cipher::aes_256_gcm(),
+            &self.key,
+            Some(nonce),
+            &frame[0..HEADER_LENGTH],
+            ciphertext,
+            tag,
+        )
+        .map_err(|_e| String::from("Message authentication failed"))?;
+        // only authenticated messages advance the sequence
+        self.received += 1;
+        return serde_json::from_slice(&plaintext).map_err(|e| format!("Invalid message: {}", e));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn channels() -> (Channel, Channel) {
+        let key = Channel::generate_key().unwrap();
+        return (Channel::new(&key).unwrap(), Channel::new(&key).unwrap());
+    }
+
+    fn send(sender: &mut Channel, message: &str) -> String {
+        let line = sender.seal(REQUEST, &message).unwrap();
+        sender.mark_sent();
+        return line;
+    }
+
+    // Change one byte of a sealed frame
+    fn modify(line: &str, index: usize, value: u8) -> String {
+        let mut frame = hex::decode(line).unwrap();
+        frame[index] = value;
+        return hex::encode(frame);
+    }
+
+    #[test]
+    fn seal_and_open() {
+        let (mut sender, mut receiver) = channels();
+        for message in ["first", "second"] {
+            let line = send(&mut sender, message);
+            let opened: String = receiver.open(REQUEST, &line).unwrap();
+            assert_eq!(opened, message);
+        }
+    }
+
+    #[test]
+    fn unsent_messages_keep_the_sequence_number() {
+        let (mut sender, mut receiver) = channels();
+        // sealed but never written, for example because the write failed
+        sender.seal(REQUEST, &"lost").unwrap();
+        let line = send(&mut sender, "message");
+        assert_eq!(receiver.open::<String>(REQUEST, &line).unwrap(), "message");
+    }
+
+    #[test]
+    fn reject_tampered_ciphertext() {
+        let (mut sender, mut receiver) = channels();
+        let line = send(&mut sender, "message");
+        let frame = hex::decode(&line).unwrap();
+        let last = frame.len() - 1;
+        let tampered = modify(&line, last, frame[last] ^ 1);
+        assert_eq!(
+            receiver.open::<String>(REQUEST, &tampered),
+            Err(String::from("Message authentication failed"))
+        );
+        // the failed message does not use up the sequence number
+        assert_eq!(receiver.open::<String>(REQUEST, &line).unwrap(), "message");
+    }
+
+    #[test]
+    fn reject_replayed_and_reordered_messages() {
+        let (mut sender, mut receiver) = channels();
+        let first = send(&mut sender, "first");
+        let second = send(&mut sender, "second");
+        assert!(receiver.open::<String>(REQUEST, &second).is_err());
+        assert_eq!(receiver.open::<String>(REQUEST, &first).unwrap(), "first");
+        assert!(receiver.open::<String>(REQUEST, &first).is_err());
+        assert_eq!(receiver.open::<String>(REQUEST, &second).unwrap(), "second");
+
+        // a sequence number changed in the header fails authentication
+        let third = send(&mut sender, "third");
+        let fourth = send(&mut sender, "fourth");
+        assert_eq!(
+            receiver.open::<String>(REQUEST, &modify(&fourth, HEADER_LENGTH - 1, 2)),
+            Err(String::from("Message authentication failed"))
+        );
+        assert_eq!(receiver.open::<String>(REQUEST, &third).unwrap(), "third");
+    }
+
+    #[test]
+    fn reject_wrong_direction() {
+        let (mut sender, mut receiver) = channels();
+        let line = send(&mut sender, "message");
+        assert_eq!(
+            receiver.open::<String>(RESPONSE, &line),
+            Err(String::from("Message has the wrong direction"))
+        );
+        // the direction byte is authenticated as well
+        assert_eq!(
+            receiver.open::<String>(RESPONSE, &modify(&line, 4, RESPONSE)),
+            Err(String::from("Message authentication failed"))
+        );
+    }
+
+    #[test]
+    fn reject_wrong_version() {
+        let (mut sender, mut receiver) = channels();
+        let line = send(&mut sender, "message");
+        let other = (VERSION + 1).to_be_bytes();
+        assert_eq!(
+            receiver.open::<String>(REQUEST, &modify(&line, 3, other[3])),
+            Err(format!("Unsupported protocol version {}", VERSION + 1))
+        );
+    }
+}
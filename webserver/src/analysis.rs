@@ -0,0 +1,368 @@
+use crate::rules::{
+    address_range, addresses_from_defs, expand_filter_rule, expand_nat_accept, family_valid,
+    RuleMatch,
+};
+use crate::{
+    check_session, get_chain, icmpv4_types_from_services, icmpv6_types_from_services,
+    tcp_ports_from_services, udp_ports_from_services, AppState, Configuration,
+    ConfigurationItems, FilterRuleData, FilterTableData,
+};
+use axum::extract::{self, State};
+use axum::http::StatusCode;
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tower_sessions::Session;
+
+// Reference to a rule in the configuration, table is "filter", "snat" or "dnat"
+#[derive(Clone, Deserialize, Serialize, PartialEq)]
+pub struct RuleRef {
+    pub table: String,
+    pub chain: String,
+    pub rule: usize,
+}
+
+// kind is "shadowed", "redundant", "conflict" or "unreachable"
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Finding {
+    pub kind: String,
+    pub rule: RuleRef,
+    pub related: Vec<RuleRef>,
+    pub message: String,
+}
+
+fn ranges_cover(outer: &str, inner: &str) -> bool {
+    match (address_range(outer), address_range(inner)) {
+        (Some((o6, ofirst, olast)), Some((i6, ifirst, ilast))) => {
+            return o6 == i6 && ofirst <= ifirst && ilast <= olast
+        }
+        _ => return false,
+    }
+}
+
+fn ranges_overlap(a: &str, b: &str) -> bool {
+    match (address_range(a), address_range(b)) {
+        (Some((a6, afirst, alast)), Some((b6, bfirst, blast))) => {
+            return a6 == b6 && afirst <= blast && bfirst <= alast
+        }
+        _ => return false,
+    }
+}
+
+// an empty list matches any address
+fn addresses_cover(outer: &Vec<String>, inner: &Vec<String>) -> bool {
+    if outer.len() == 0 {
+        return true;
+    }
+    if inner.len() == 0 {
+        return false;
+    }
+    return inner
+        .iter()
+        .all(|i| outer.iter().any(|o| ranges_cover(o, i)));
+}
+
+fn addresses_overlap(a: &Vec<String>, b: &Vec<String>) -> bool {
+    if a.len() == 0 || b.len() == 0 {
+        return true;
+    }
+    return a.iter().any(|x| b.iter().any(|y| ranges_overlap(x, y)));
+}
+
+// an empty list matches any port or type
+fn values_cover(outer: &Vec<String>, inner: &Vec<String>) -> bool {
+    if outer.len() == 0 {
+        return true;
+    }
+    return inner.len() > 0 && inner.iter().all(|i| outer.contains(i));
+}
+
+fn values_overlap(a: &Vec<String>, b: &Vec<String>) -> bool {
+    if a.len() == 0 || b.len() == 0 {
+        return true;
+    }
+    return a.iter().any(|x| b.contains(x));
+}
+
+// Check whether every packet matched by inner is also matched by outer
+pub fn match_covers(outer: &RuleMatch, inner: &RuleMatch) -> bool {
+    return outer.family == inner.family
+        && outer.protocol == inner.protocol
+        && addresses_cover(&outer.saddr, &inner.saddr)
+        && addresses_cover(&outer.daddr, &inner.daddr)
+        && values_cover(&outer.sport, &inner.sport)
+        && values_cover(&outer.dport, &inner.dport)
+        && values_cover(&outer.icmptypes, &inner.icmptypes);
+}
+
+// Check whether some packet is matched by both a and b
+pub fn matches_overlap(a: &RuleMatch, b: &RuleMatch) -> bool {
+    return a.family == b.family
+        && a.protocol == b.protocol
+        && addresses_overlap(&a.saddr, &b.saddr)
+        && addresses_overlap(&a.daddr, &b.daddr)
+        && values_overlap(&a.sport, &b.sport)
+        && values_overlap(&a.dport, &b.dport)
+        && values_overlap(&a.icmptypes, &b.icmptypes);
+}
+
+// Explain why an active rule does not generate any nft rule
+fn unreachable_reason(config_items: &ConfigurationItems, rule: &FilterRuleData) -> String {
+    let (source_ipv4s, source_ipv6s) = addresses_from_defs(config_items, &rule.source);
+    let (dest_ipv4s, dest_ipv6s) = addresses_from_defs(config_items, &rule.destination);
+    let tcp = tcp_ports_from_services(config_items, &rule.sourceservice).len()
+        + tcp_ports_from_services(config_items, &rule.destinationservice).len();
+    let udp = udp_ports_from_services(config_items, &rule.sourceservice).len()
+        + udp_ports_from_services(config_items, &rule.destinationservice).len();
+    let icmp = icmpv4_types_from_services(&rule.sourceservice).len()
+        + icmpv4_types_from_services(&rule.destinationservice).len()
+        + icmpv6_types_from_services(&rule.sourceservice).len()
+        + icmpv6_types_from_services(&rule.destinationservice).len();
+    if tcp + udp + icmp == 0 {
+        return String::from("it has no services");
+    }
+    if !family_valid(&source_ipv4s, &dest_ipv4s, &source_ipv6s, &dest_ipv6s, true)
+        && !family_valid(&source_ipv6s, &dest_ipv6s, &source_ipv4s, &dest_ipv4s, true)
+    {
+        return String::from("its source and destination addresses have no address family in common");
+    }
+    if tcp + udp == 0 {
+        return String::from("ICMP services are only used when the ICMP default is inactive");
+    }
+    return String::from("its source and destination services have no protocol in common");
+}
+
+fn rule_list(refs: &Vec<RuleRef>) -> String {
+    let names: Vec<String> = refs
+        .iter()
+        .map(|r| {
+            if r.table == "filter" {
+                format!("rule {}", r.rule + 1)
+            } else {
+                format!("{} rule {}", r.table.to_uppercase(), r.rule + 1)
+            }
+        })
+        .collect();
+    return names.join(", ");
+}
+
+fn analyze_table(
+    config_items: &ConfigurationItems,
+    filtertable: &FilterTableData,
+    findings: &mut Vec<Finding>,
+) -> Result<(), String> {
+    let chain = get_chain(config_items, &filtertable.chain)?;
+    let rule_ref = |index: usize| RuleRef {
+        table: String::from("filter"),
+        chain: filtertable.chain.clone(),
+        rule: index,
+    };
+    if !chain.filter {
+        for (index, rule) in filtertable.rules.iter().enumerate() {
+            if rule.active {
+                findings.push(Finding {
+                    kind: String::from("unreachable"),
+                    rule: rule_ref(index),
+                    related: vec![],
+                    message: format!(
+                        "Rule {} in chain {} is never used because filtering is disabled for the chain.",
+                        index + 1,
+                        filtertable.chain
+                    ),
+                });
+            }
+        }
+        return Ok(());
+    }
+
+    // rules that come earlier in the chain: nat accept rules are inserted at the top
+    let mut earlier: Vec<(RuleRef, RuleMatch)> = vec![];
+    if !config_items
+        .inactive_defaults
+        .contains(&String::from("AllowNAT"))
+    {
+        for (table, nat, enabled, dnat) in [
+            ("snat", &config_items.snat, chain.snat, false),
+            ("dnat", &config_items.dnat, chain.dnat, true),
+        ] {
+            for nattable in nat.nattables.iter() {
+                if !enabled || nattable.deleted || nattable.chain != filtertable.chain {
+                    continue;
+                }
+                for (index, rule) in nattable.rules.iter().enumerate() {
+                    for m in expand_nat_accept(config_items, rule, dnat) {
+                        let r = RuleRef {
+                            table: String::from(table),
+                            chain: nattable.chain.clone(),
+                            rule: index,
+                        };
+                        earlier.push((r, m));
+                    }
+                }
+            }
+        }
+    }
+
+    for (index, rule) in filtertable.rules.iter().enumerate() {
+        if !rule.active {
+            continue;
+        }
+        let matches = expand_filter_rule(config_items, rule);
+        if matches.len() == 0 {
+            findings.push(Finding {
+                kind: String::from("unreachable"),
+                rule: rule_ref(index),
+                related: vec![],
+                message: format!(
+                    "Rule {} in chain {} can never match because {}.",
+                    index + 1,
+                    filtertable.chain,
+                    unreachable_reason(config_items, rule)
+                ),
+            });
+            continue;
+        }
+
+        // find the earlier rules that already match all traffic of this rule
+        let mut covering: Vec<RuleRef> = vec![];
+        let mut same_action = true;
+        let mut covered = true;
+        for m in matches.iter() {
+            match earlier.iter().find(|(_r, e)| match_covers(e, m)) {
+                Some((r, e)) => {
+                    if e.action != m.action {
+                        same_action = false;
+                    }
+                    if !covering.contains(r) {
+                        covering.push(r.clone());
+                    }
+                }
+                None => covered = false,
+            }
+        }
+        if covered {
+            let (kind, message) = if same_action {
+                (
+                    "redundant",
+                    format!(
+                        "Rule {} in chain {} is redundant, {} already {} all of its traffic.",
+                        index + 1,
+                        filtertable.chain,
+                        rule_list(&covering),
+                        rule.action
+                    ),
+                )
+            } else {
+                (
+                    "shadowed",
+                    format!(
+                        "Rule {} in chain {} is shadowed by {}, its action {} is never applied.",
+                        index + 1,
+                        filtertable.chain,
+                        rule_list(&covering),
+                        rule.action
+                    ),
+                )
+            };
+            findings.push(Finding {
+                kind: String::from(kind),
+                rule: rule_ref(index),
+                related: covering,
+                message: message,
+            });
+        } else {
+            // earlier rules with the opposite action that take part of the traffic
+            let mut conflicting: Vec<RuleRef> = vec![];
+            for (r, e) in earlier.iter() {
+                if e.action != rule.action
+                    && matches.iter().any(|m| matches_overlap(e, m))
+                    && !conflicting.contains(r)
+                {
+                    conflicting.push(r.clone());
+                }
+            }
+            if conflicting.len() > 0 {
+                findings.push(Finding {
+                    kind: String::from("conflict"),
+                    rule: rule_ref(index),
+                    related: conflicting.clone(),
+                    message: format!(
+                        "Part of the traffic of rule {} in chain {} ({}) is already handled by {} with the opposite action.",
+                        index + 1,
+                        filtertable.chain,
+                        rule.action,
+                        rule_list(&conflicting)
+                    ),
+                });
+            }
+        }
+        for m in matches {
+            earlier.push((rule_ref(index), m));
+        }
+    }
+    return Ok(());
+}
+
+// Find shadowed, redundant, conflicting and unreachable filter rules
+pub fn analyze(config_items: &ConfigurationItems) -> Result<Vec<Finding>, String> {
+    let mut findings: Vec<Finding> = vec![];
+    for filtertable in config_items.filters.filtertables.iter() {
+        if filtertable.deleted {
+            continue;
+        }
+        analyze_table(config_items, filtertable, &mut findings)?;
+    }
+    return Ok(findings);
+}
+
+pub async fn analyze_configuration(
+    session: Session,
+    State(state): State<Arc<AppState>>,
+    extract::Json(payload): extract::Json<Configuration>,
+) -> Result<Json<Vec<Finding>>, (StatusCode, String)> {
+    if !check_session(session, &state).await {
+        return Err((StatusCode::UNAUTHORIZED, String::from("Not logged in")));
+    }
+    let config_items: ConfigurationItems = match serde_json::from_str(&payload.json) {
+        Ok(c) => c,
+        Err(e) => return Err((StatusCode::BAD_REQUEST, format!("Invalid configuration: {}", e))),
+    };
+    match analyze(&config_items) {
+        Ok(findings) => return Ok(Json(findings)),
+        Err(e) => return Err((StatusCode::BAD_REQUEST, e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdata::{self, rule};
+
+    #[test]
+    fn one_finding_per_kind() {
+        let mut config_items = testdata::configuration();
+        let rules = &mut config_items.filters.filtertables[0].rules;
+        // rule 0 accepts https from office to web
+        rules.push(rule(&["pc1"], &["web"], &["https"], "accept"));
+        rules.push(rule(&["pc1"], &["web"], &["https"], "drop"));
+        rules.push(rule(&["office"], &["web"], &["https", "pg"], "drop"));
+        rules.push(rule(&["office"], &["db"], &[], "accept"));
+        let findings = analyze(&config_items).unwrap();
+        let kinds: Vec<(&str, usize, Vec<usize>)> = findings
+            .iter()
+            .map(|f| {
+                let related = f.related.iter().map(|r| r.rule).collect();
+                (f.kind.as_str(), f.rule.rule, related)
+            })
+            .collect();
+        assert_eq!(
+            kinds,
+            [
+                ("redundant", 1, vec![0]),
+                ("shadowed", 2, vec![0]),
+                ("conflict", 3, vec![0, 1]),
+                ("unreachable", 4, vec![]),
+            ]
+        );
+    }
+}
@@ -0,0 +1,436 @@
+use crate::configrepo::check_name;
+use crate::history::diff_lines;
+use crate::{check_session, AppState, ConfigurationItems, FilterTableData, NatTableData};
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tower_sessions::Session;
+
+// A difference in one object of two configurations
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct Change {
+    // hosts, hostgroups, ipv4networks, ipv6networks, services, interfaces, chains, filter,
+    // snat, dnat, defaults, tests, settings, pre or post
+    pub section: String,
+    // name of the object, "<chain> rule <n>" for rules, numbered from 1
+    pub object: String,
+    // "added", "removed" or "changed"
+    pub change: String,
+    // the object in both configurations, None when it does not exist
+    pub old: Option<Value>,
+    pub new: Option<Value>,
+    // fields of a changed object that differ
+    pub fields: Vec<String>,
+    // line diff of pre and post text, every line is prefixed with " ", "-" or "+"
+    pub lines: Vec<String>,
+}
+
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct ConfigurationDiff {
+    pub old: String,
+    pub new: String,
+    pub changes: Vec<Change>,
+}
+
+// Fields of a filter or nat table that are compared, the rules are compared one by one
+const TABLE_SKIPPED: [&str; 2] = ["rules", "deleted"];
+
+fn change(section: &str, object: &str, old: Option<Value>, new: Option<Value>) -> Option<Change> {
+    if old == new {
+        return None;
+    }
+    let kind = match (&old, &new) {
+        (None, _) => "added",
+        (_, None) => "removed",
+        _ => "changed",
+    };
+    let mut fields: Vec<String> = vec![];
+    if let (Some(Value::Object(a)), Some(Value::Object(b))) = (&old, &new) {
+        for key in a.keys().chain(b.keys().filter(|k| !a.contains_key(*k))) {
+            if a.get(key) != b.get(key) {
+                fields.push(key.clone());
+            }
+        }
+    }
+    return Some(Change {
+        section: String::from(section),
+        object: String::from(object),
+        change: String::from(kind),
+        old: old,
+        new: new,
+        fields: fields,
+        lines: vec![],
+    });
+}
+
+fn to_object<T: Serialize>(value: &T) -> Map<String, Value> {
+    match serde_json::to_value(value) {
+        Ok(Value::Object(map)) => return map,
+        _ => return Map::new(),
+    }
+}
+
+// Objects that are kept in a map by name, such as hosts and services
+fn diff_named(section: &str, old: Map<String, Value>, new: Map<String, Value>) -> Vec<Change> {
+    let mut names: Vec<&String> = old.keys().chain(new.keys()).collect();
+    names.sort();
+    names.dedup();
+    return names
+        .into_iter()
+        .filter_map(|name| {
+            change(
+                section,
+                name,
+                old.get(name).cloned(),
+                new.get(name).cloned(),
+            )
+        })
+        .collect();
+}
+
+// Align the rules of a chain on the rules that did not change, so that inserting or
+// deleting a rule does not show every following rule as changed. Rules that were
+// removed and added at the same place are reported as changed.
+fn diff_rules(section: &str, chain: &str, old: &[Value], new: &[Value]) -> Vec<Change> {
+    let mut lcs = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+    let mut changes: Vec<Change> = vec![];
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            i += 1;
+            j += 1;
+            continue;
+        }
+        // the run of removed and added rules up to the next unchanged rule
+        let (start_i, start_j) = (i, j);
+        while (i < old.len() || j < new.len())
+            && !(i < old.len() && j < new.len() && old[i] == new[j])
+        {
+            if j < new.len() && (i == old.len() || lcs[i][j + 1] >= lcs[i + 1][j]) {
+                j += 1;
+            } else {
+                i += 1;
+            }
+        }
+        for k in 0..(i - start_i).max(j - start_j) {
+            let (a, b) = (start_i + k, start_j + k);
+            let number = if b < j { b + 1 } else { a + 1 };
+            let object = format!("{} rule {}", chain, number);
+            let old_rule = old[start_i..i].get(k).cloned();
+            let new_rule = new[start_j..j].get(k).cloned();
+            changes.extend(change(section, &object, old_rule, new_rule));
+        }
+    }
+    return changes;
+}
+
+fn filter_tables(tables: &[FilterTableData]) -> Vec<(String, Map<String, Value>)> {
+    return tables
+        .iter()
+        .filter(|t| !t.deleted)
+        .map(|t| (t.chain.clone(), to_object(t)))
+        .collect();
+}
+
+fn nat_tables(tables: &[NatTableData]) -> Vec<(String, Map<String, Value>)> {
+    return tables
+        .iter()
+        .filter(|t| !t.deleted)
+        .map(|t| (t.chain.clone(), to_object(t)))
+        .collect();
+}
+
+// Filter or nat tables, compared by chain and then rule by rule
+fn diff_tables(
+    section: &str,
+    old: Vec<(String, Map<String, Value>)>,
+    new: Vec<(String, Map<String, Value>)>,
+) -> Vec<Change> {
+    let mut chains: Vec<&String> = new.iter().map(|(c, _t)| c).collect();
+    chains.extend(
+        old.iter()
+            .map(|(c, _t)| c)
+            .filter(|c| !new.iter().any(|(n, _t)| n == *c)),
+    );
+    let mut changes: Vec<Change> = vec![];
+    for chain in chains {
+        let find = |tables: &Vec<(String, Map<String, Value>)>| {
+            return tables
+                .iter()
+                .find(|(c, _t)| c == chain)
+                .map(|(_c, t)| t.clone());
+        };
+        let (old_table, new_table) = (find(&old), find(&new));
+        let rules = |table: &Option<Map<String, Value>>| match table
+            .as_ref()
+            .and_then(|t| t.get("rules"))
+        {
+            Some(Value::Array(rules)) => rules.clone(),
+            _ => vec![],
+        };
+        let (old_rules, new_rules) = (rules(&old_table), rules(&new_table));
+        let without_rules = |table: Option<Map<String, Value>>| {
+            return table.map(|mut t| {
+                for key in TABLE_SKIPPED {
+                    t.remove(key);
+                }
+                Value::Object(t)
+            });
+        };
+        changes.extend(change(
+            section,
+            chain,
+            without_rules(old_table),
+            without_rules(new_table),
+        ));
+        changes.extend(diff_rules(section, chain, &old_rules, &new_rules));
+    }
+    return changes;
+}
+
+fn diff_text(section: &str, old: &String, new: &String) -> Vec<Change> {
+    let Some(mut c) = change(
+        section,
+        section,
+        Some(Value::from(old.clone())),
+        Some(Value::from(new.clone())),
+    ) else {
+        return vec![];
+    };
+    c.lines = diff_lines(old, new);
+    return vec![c];
+}
+
+// Differences between two configurations object by object. Positions of the chains and
+// checks in the editor are left out, they do not change the generated ruleset.
+pub fn diff(old: &ConfigurationItems, new: &ConfigurationItems) -> Vec<Change> {
+    let mut changes: Vec<Change> = vec![];
+    changes.extend(diff_named(
+        "interfaces",
+        to_object(&old.interfaces),
+        to_object(&new.interfaces),
+    ));
+    changes.extend(diff_named(
+        "hosts",
+        to_object(&old.hosts),
+        to_object(&new.hosts),
+    ));
+    changes.extend(diff_named(
+        "hostgroups",
+        to_object(&old.hostgroups),
+        to_object(&new.hostgroups),
+    ));
+    changes.extend(diff_named(
+        "ipv4networks",
+        to_object(&old.ipv4networks),
+        to_object(&new.ipv4networks),
+    ));
+    changes.extend(diff_named(
+        "ipv6networks",
+        to_object(&old.ipv6networks),
+        to_object(&new.ipv6networks),
+    ));
+    changes.extend(diff_named(
+        "services",
+        to_object(&old.services),
+        to_object(&new.services),
+    ));
+    changes.extend(diff_named(
+        "chains",
+        to_object(&old.chains),
+        to_object(&new.chains),
+    ));
+    changes.extend(diff_tables(
+        "filter",
+        filter_tables(&old.filters.filtertables),
+        filter_tables(&new.filters.filtertables),
+    ));
+    changes.extend(diff_tables(
+        "snat",
+        nat_tables(&old.snat.nattables),
+        nat_tables(&new.snat.nattables),
+    ));
+    changes.extend(diff_tables(
+        "dnat",
+        nat_tables(&old.dnat.nattables),
+        nat_tables(&new.dnat.nattables),
+    ));
+
+    let defaults = |config_items: &ConfigurationItems| {
+        return config_items
+            .inactive_defaults
+            .iter()
+            .map(|d| (d.clone(), Value::from("inactive")))
+            .collect::<Map<String, Value>>();
+    };
+    for mut c in diff_named("defaults", defaults(old), defaults(new)) {
+        // a default is active unless it is listed as inactive
+        c.change = String::from("changed");
+        c.old = c.old.or(Some(Value::from("active")));
+        c.new = c.new.or(Some(Value::from("active")));
+        changes.push(c);
+    }
+
+    let tests = |config_items: &ConfigurationItems| {
+        return config_items
+            .tests
+            .iter()
+            .map(|t| (t.name.clone(), serde_json::to_value(t).unwrap_or_default()))
+            .collect::<Map<String, Value>>();
+    };
+    changes.extend(diff_named("tests", tests(old), tests(new)));
+
+    let settings = |config_items: &ConfigurationItems| {
+        let mut map = Map::new();
+        map.insert(
+            String::from("logging"),
+            Value::from(config_items.logging.clone()),
+        );
+        map.insert(
+            String::from("scoped_tables"),
+            Value::from(config_items.scoped_tables),
+        );
+        map.insert(
+            String::from("management_rule"),
+            Value::from(config_items.management_rule),
+        );
+        return map;
+    };
+    changes.extend(diff_named("settings", settings(old), settings(new)));
+    changes.extend(diff_text("pre", &old.pre, &new.pre));
+    changes.extend(diff_text("post", &old.post, &new.post));
+    return changes;
+}
+
+// A saved configuration, names are single file names in the save path
+async fn load(savepath: &str, name: &str) -> Result<ConfigurationItems, (StatusCode, String)> {
+    check_name(name)?;
+    let mut path = PathBuf::from(savepath);
+    path.push(name);
+    let json = tokio::fs::read_to_string(&path).await.map_err(|_e| {
+        (
+            StatusCode::NOT_FOUND,
+            format!("Configuration {} not found", name),
+        )
+    })?;
+    return serde_json::from_str(&json).map_err(|e| {
+        (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            format!("Invalid configuration {}: {}", name, e),
+        )
+    });
+}
+
+pub async fn diff_configurations(
+    session: Session,
+    Path((old, new)): Path<(String, String)>,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<ConfigurationDiff>, (StatusCode, String)> {
+    if !check_session(session, &state).await {
+        return Err((StatusCode::UNAUTHORIZED, String::from("Not logged in")));
+    }
+    let savepath = state.settings.lock().await.paths.savepath.clone();
+    let old_config = load(&savepath, &old).await?;
+    let new_config = load(&savepath, &new).await?;
+    return Ok(Json(ConfigurationDiff {
+        changes: diff(&old_config, &new_config),
+        old: old,
+        new: new,
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdata::{self, rule};
+
+    fn summary(changes: &Vec<Change>) -> Vec<(String, String, String, Vec<String>)> {
+        return changes
+            .iter()
+            .map(|c| {
+                (
+                    c.section.clone(),
+                    c.object.clone(),
+                    c.change.clone(),
+                    c.fields.clone(),
+                )
+            })
+            .collect();
+    }
+
+    #[test]
+    fn unchanged_configuration() {
+        let old = testdata::configuration();
+        let mut new = testdata::configuration();
+        // moving the checks in the editor is not a change
+        new.checksdragpos.top = 100.0;
+        assert_eq!(diff(&old, &new).len(), 0);
+    }
+
+    #[test]
+    fn changes_by_object() {
+        let old = testdata::configuration();
+        let mut new = testdata::configuration();
+        new.hosts.get_mut("web").unwrap().ipv4 = vec![String::from("192.0.2.11")];
+        new.hosts.remove("db");
+        new.inactive_defaults.push(String::from("ICMP"));
+        new.pre = String::from("define a = 1");
+        let changes = diff(&old, &new);
+        let s = |v: &str| String::from(v);
+        assert_eq!(
+            summary(&changes),
+            [
+                (s("hosts"), s("db"), s("removed"), vec![]),
+                (s("hosts"), s("web"), s("changed"), vec![s("ipv4")]),
+                (s("defaults"), s("ICMP"), s("changed"), vec![]),
+                (s("pre"), s("pre"), s("changed"), vec![]),
+            ]
+        );
+        assert!(changes[2].old == Some(Value::from("active")));
+        assert!(changes[2].new == Some(Value::from("inactive")));
+        assert_eq!(changes[3].lines, ["+define a = 1"]);
+    }
+
+    #[test]
+    fn inserted_rule_does_not_change_the_following_rules() {
+        let old = testdata::configuration();
+        let mut new = testdata::configuration();
+        let rules = &mut new.filters.filtertables[0].rules;
+        rules.insert(0, rule(&["pc1"], &["db"], &["pg"], "accept"));
+        rules.push(rule(&["pc1"], &["web"], &["https"], "accept"));
+        let s = |v: &str| String::from(v);
+        assert_eq!(
+            summary(&diff(&old, &new)),
+            [
+                (s("filter"), s("lan_dmz rule 1"), s("added"), vec![]),
+                (s("filter"), s("lan_dmz rule 3"), s("added"), vec![]),
+            ]
+        );
+
+        // a rule that is replaced in place is changed
+        let mut new = testdata::configuration();
+        new.filters.filtertables[0].rules[0].action = String::from("drop");
+        assert_eq!(
+            summary(&diff(&old, &new)),
+            [(
+                s("filter"),
+                s("lan_dmz rule 1"),
+                s("changed"),
+                vec![s("action")]
+            )]
+        );
+    }
+}
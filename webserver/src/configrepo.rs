@@ -0,0 +1,429 @@
+use crate::{check_session, AppState, Configuration};
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tower_sessions::Session;
+
+// The save path is the work tree of a git repository in which every save, delete and
+// restore of a configuration is a commit by the logged in user. Only configuration files
+// are added, the users directory and the installed configurations stay out of it.
+// Installs of a saved version are tagged installed-<unix time>-<short commit>.
+
+// Identity of the commits and tags the webserver makes without a logged in user
+const GIT_CONFIG: [&str; 10] = [
+    "-c",
+    "user.name=nftablesbuilder",
+    "-c",
+    "user.email=nftablesbuilder@localhost",
+    "-c",
+    "commit.gpgsign=false",
+    "-c",
+    "tag.gpgsign=false",
+    "-c",
+    "safe.directory=*",
+];
+
+// A commit of a configuration
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Revision {
+    pub commit: String,
+    pub author: String,
+    // unix time of the commit
+    pub timestamp: i64,
+    pub message: String,
+    pub tags: Vec<String>,
+}
+
+// Run git in the repository of the save path, commits and tags get user as author
+async fn git(
+    savepath: &str,
+    user: Option<&str>,
+    args: &[&str],
+    input: Option<&str>,
+) -> Result<String, String> {
+    let mut command = Command::new("git");
+    command.arg("-C").arg(savepath).args(GIT_CONFIG).args(args);
+    if let Some(user) = user.filter(|u| u.len() > 0) {
+        let email = format!("{}@nftablesbuilder", user);
+        command
+            .env("GIT_AUTHOR_NAME", user)
+            .env("GIT_AUTHOR_EMAIL", &email)
+            .env("GIT_COMMITTER_NAME", user)
+            .env("GIT_COMMITTER_EMAIL", &email);
+    }
+    let mut child = command
+        .stdin(match input {
+            Some(_i) => Stdio::piped(),
+            None => Stdio::null(),
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Could not run git: {}", e))?;
+    if let (Some(mut stdin), Some(text)) = (child.stdin.take(), input) {
+        stdin
+            .write_all(text.as_bytes())
+            .await
+            .map_err(|e| format!("Could not write to git: {}", e))?;
+    }
+    let output = child
+        .wait_with_output()
+        .await
+        .map_err(|e| format!("Could not run git: {}", e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "git {} failed: {}",
+            args.first().unwrap_or(&""),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    return Ok(String::from_utf8_lossy(&output.stdout).to_string());
+}
+
+// Configuration names are single file names in the save path
+pub fn check_name(name: &str) -> Result<(), (StatusCode, String)> {
+    if name.len() == 0 || name.starts_with('.') || name.contains('/') {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("Invalid configuration name {}", name),
+        ));
+    }
+    return Ok(());
+}
+
+fn check_commit(commit: &str) -> Result<(), (StatusCode, String)> {
+    if commit.len() < 4 || commit.len() > 64 || !commit.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("Invalid commit {}", commit),
+        ));
+    }
+    return Ok(());
+}
+
+// Create the repository on first use with the configurations saved before it existed
+async fn ensure_repository(savepath: &str) -> Result<(), String> {
+    let mut git_dir = PathBuf::from(savepath);
+    git_dir.push(".git");
+    if git_dir.exists() {
+        return Ok(());
+    }
+    git(savepath, None, &["init", "-q"], None).await?;
+    let mut names: Vec<String> = vec![];
+    if let Ok(entries) = std::fs::read_dir(savepath) {
+        for entry in entries.flatten() {
+            let is_file = entry.file_type().map(|t| t.is_file()).unwrap_or(false);
+            let name = entry.file_name().to_string_lossy().to_string();
+            if is_file && !name.starts_with('.') {
+                names.push(name);
+            }
+        }
+    }
+    if names.len() == 0 {
+        return Ok(());
+    }
+    let mut args: Vec<&str> = vec!["add", "--"];
+    args.extend(names.iter().map(String::as_str));
+    git(savepath, None, &args, None).await?;
+    git(
+        savepath,
+        None,
+        &["commit", "-q", "-m", "Import saved configurations"],
+        None,
+    )
+    .await?;
+    return Ok(());
+}
+
+// Commit the current state of a configuration file, which may have been deleted.
+// Nothing is committed when the file did not change.
+pub async fn commit_file(
+    savepath: &str,
+    name: &str,
+    user: &str,
+    message: &str,
+) -> Result<(), String> {
+    ensure_repository(savepath).await?;
+    let mut path = PathBuf::from(savepath);
+    path.push(name);
+    if path.exists() {
+        git(savepath, None, &["add", "--", name], None).await?;
+    } else {
+        let args = ["rm", "-q", "--cached", "--ignore-unmatch", "--", name];
+        git(savepath, None, &args, None).await?;
+    }
+    if git(
+        savepath,
+        None,
+        &["diff", "--cached", "--quiet", "--", name],
+        None,
+    )
+    .await
+    .is_ok()
+    {
+        return Ok(());
+    }
+    git(
+        savepath,
+        Some(user),
+        &["commit", "-q", "-m", message, "--", name],
+        None,
+    )
+    .await?;
+    return Ok(());
+}
+
+// Tag a commit as installed, a counter is added when the commit was already tagged in the
+// same second
+async fn tag(savepath: &str, name: &str, commit: &str, user: &str) -> Result<String, String> {
+    let short = git(
+        savepath,
+        None,
+        &["rev-parse", "--short=12", &format!("{}^{{commit}}", commit)],
+        None,
+    )
+    .await?;
+    let base = format!(
+        "installed-{}-{}",
+        time::OffsetDateTime::now_utc().unix_timestamp(),
+        short.trim()
+    );
+    let mut tag = base.clone();
+    let mut count = 1;
+    while git(
+        savepath,
+        None,
+        &["rev-parse", "-q", "--verify", &format!("refs/tags/{}", tag)],
+        None,
+    )
+    .await
+    .is_ok()
+    {
+        count += 1;
+        tag = format!("{}-{}", base, count);
+    }
+    let message = format!("Installed {}", name);
+    git(
+        savepath,
+        Some(user),
+        &["tag", "-a", &tag, "-m", &message, commit],
+        None,
+    )
+    .await?;
+    return Ok(tag);
+}
+
+// Tag the last saved version of a configuration as installed when it is the installed
+// json, None when the configuration was changed after it was saved
+pub async fn tag_installed(
+    savepath: &str,
+    name: &str,
+    json: &str,
+    user: &str,
+) -> Result<Option<String>, String> {
+    if name.len() == 0 {
+        return Ok(None);
+    }
+    ensure_repository(savepath).await?;
+    let installed = git(savepath, None, &["hash-object", "--stdin"], Some(json)).await?;
+    let Ok(saved) = git(
+        savepath,
+        None,
+        &["rev-parse", &format!("HEAD:{}", name)],
+        None,
+    )
+    .await
+    else {
+        return Ok(None);
+    };
+    if installed.trim() != saved.trim() {
+        return Ok(None);
+    }
+    let commit = git(
+        savepath,
+        None,
+        &["rev-list", "-1", "HEAD", "--", name],
+        None,
+    )
+    .await?;
+    return Ok(Some(tag(savepath, name, commit.trim(), user).await?));
+}
+
+async fn revisions(savepath: &str, name: &str) -> Result<Vec<Revision>, String> {
+    ensure_repository(savepath).await?;
+    // an empty repository has no HEAD to log
+    if git(
+        savepath,
+        None,
+        &["rev-parse", "--verify", "-q", "HEAD"],
+        None,
+    )
+    .await
+    .is_err()
+    {
+        return Ok(vec![]);
+    }
+    let log = git(
+        savepath,
+        None,
+        &[
+            "log",
+            "--format=%H%x1f%an%x1f%at%x1f%s%x1f%D%x1e",
+            "--",
+            name,
+        ],
+        None,
+    )
+    .await?;
+    let mut result: Vec<Revision> = vec![];
+    for record in log.split('\x1e') {
+        let fields: Vec<&str> = record.trim().split('\x1f').collect();
+        if fields.len() < 5 {
+            continue;
+        }
+        result.push(Revision {
+            commit: String::from(fields[0]),
+            author: String::from(fields[1]),
+            timestamp: fields[2].parse().unwrap_or(0),
+            message: String::from(fields[3]),
+            tags: fields[4]
+                .split(", ")
+                .filter_map(|r| r.strip_prefix("tag: "))
+                .map(String::from)
+                .collect(),
+        });
+    }
+    return Ok(result);
+}
+
+async fn show(savepath: &str, name: &str, commit: &str) -> Result<String, (StatusCode, String)> {
+    check_name(name)?;
+    check_commit(commit)?;
+    ensure_repository(savepath)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
+    return git(
+        savepath,
+        None,
+        &["show", &format!("{}:{}", commit, name)],
+        None,
+    )
+    .await
+    .map_err(|_e| {
+        (
+            StatusCode::NOT_FOUND,
+            format!("Configuration {} has no version {}", name, commit),
+        )
+    });
+}
+
+pub async fn list_revisions(
+    session: Session,
+    Path(name): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<Vec<Revision>>, (StatusCode, String)> {
+    if !check_session(session, &state).await {
+        return Err((StatusCode::UNAUTHORIZED, String::from("Not logged in")));
+    }
+    check_name(&name)?;
+    let savepath = state.settings.lock().await.paths.savepath.clone();
+    match revisions(&savepath, &name).await {
+        Ok(r) => return Ok(Json(r)),
+        Err(e) => return Err((StatusCode::INTERNAL_SERVER_ERROR, e)),
+    }
+}
+
+// An old version of a configuration, in the form /load returns
+pub async fn show_revision(
+    session: Session,
+    Path((name, commit)): Path<(String, String)>,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<Configuration>, (StatusCode, String)> {
+    if !check_session(session, &state).await {
+        return Err((StatusCode::UNAUTHORIZED, String::from("Not logged in")));
+    }
+    let savepath = state.settings.lock().await.paths.savepath.clone();
+    let json = show(&savepath, &name, &commit).await?;
+    return Ok(Json(Configuration {
+        name: name,
+        json: json,
+        flush_conntrack: false,
+        confirm_seconds: 0,
+        allow_lockout: false,
+        message: String::from(""),
+    }));
+}
+
+// Make an old version the current one, as a new commit
+pub async fn restore_revision(
+    session: Session,
+    Path((name, commit)): Path<(String, String)>,
+    State(state): State<Arc<AppState>>,
+) -> Result<String, (StatusCode, String)> {
+    if !check_session(session, &state).await {
+        return Err((StatusCode::UNAUTHORIZED, String::from("Not logged in")));
+    }
+    let savepath = state.settings.lock().await.paths.savepath.clone();
+    let json = show(&savepath, &name, &commit).await?;
+    let mut path = PathBuf::from(&savepath);
+    path.push(&name);
+    tokio::fs::write(&path, json).await.map_err(|_e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Could not write configuration file {}", path.display()),
+        )
+    })?;
+    let user = state.current_user.lock().await.clone();
+    let message = format!("Restore {} from {}", name, &commit[..commit.len().min(12)]);
+    commit_file(&savepath, &name, &user, &message)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
+    return Ok(String::from("OK"));
+}
+
+// Tag a version as installed, for installs of configurations that were saved afterwards
+pub async fn tag_revision(
+    session: Session,
+    Path((name, commit)): Path<(String, String)>,
+    State(state): State<Arc<AppState>>,
+) -> Result<String, (StatusCode, String)> {
+    if !check_session(session, &state).await {
+        return Err((StatusCode::UNAUTHORIZED, String::from("Not logged in")));
+    }
+    let savepath = state.settings.lock().await.paths.savepath.clone();
+    show(&savepath, &name, &commit).await?;
+    let user = state.current_user.lock().await.clone();
+    return tag(&savepath, &name, &commit, &user)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn tags_of_the_same_second_differ() {
+        let mut dir = std::env::temp_dir();
+        dir.push(format!("configrepo-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let savepath = dir.to_string_lossy().to_string();
+        let json = "{}";
+        std::fs::write(dir.join("fw"), json).unwrap();
+        commit_file(&savepath, "fw", "admin", "Save fw")
+            .await
+            .unwrap();
+        let first = tag_installed(&savepath, "fw", json, "admin").await.unwrap();
+        let second = tag_installed(&savepath, "fw", json, "admin").await.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        let (first, second) = (first.unwrap(), second.unwrap());
+        assert!(first.starts_with("installed-"));
+        assert!(first != second);
+    }
+}
@@ -0,0 +1,302 @@
+use crate::rules::{address_matches, filter_verdict, Flow};
+use crate::{helper_command, helper_request, AppState, ConfigurationItems};
+use network_interface::{NetworkInterface, NetworkInterfaceConfig};
+use protocol::{ConntrackTuple, Request, Response};
+use std::net::IpAddr;
+use std::sync::Arc;
+
+// A connection tracking entry as listed by conntrack -L -o extended
+struct CtEntry {
+    protocol: String,
+    src: String,
+    dst: String,
+    sport: Option<u16>,
+    dport: Option<u16>,
+    icmptype: Option<u8>,
+    reply_src: String,
+    reply_sport: Option<u16>,
+}
+
+fn parse_entry(line: &str) -> Option<CtEntry> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    if fields.len() < 4 || line.contains("[UNREPLIED]") {
+        return None;
+    }
+    let protocol = String::from(fields[2]);
+    if protocol == "tcp" && !fields.contains(&"ESTABLISHED") {
+        return None;
+    }
+    if protocol != "tcp" && protocol != "udp" && protocol != "icmp" && protocol != "icmpv6" {
+        return None;
+    }
+    // the first occurrence of a key belongs to the original tuple, the second to the reply tuple
+    let mut original: Vec<(&str, &str)> = vec![];
+    let mut reply: Vec<(&str, &str)> = vec![];
+    for field in fields.iter() {
+        if let Some((key, value)) = field.split_once('=') {
+            if original.iter().any(|(k, _v)| *k == key) {
+                reply.push((key, value));
+            } else {
+                original.push((key, value));
+            }
+        }
+    }
+    let get = |tuple: &Vec<(&str, &str)>, key: &str| -> Option<String> {
+        tuple
+            .iter()
+            .find(|(k, _v)| *k == key)
+            .map(|(_k, v)| String::from(*v))
+    };
+    return Some(CtEntry {
+        protocol: protocol,
+        src: get(&original, "src")?,
+        dst: get(&original, "dst")?,
+        sport: get(&original, "sport").and_then(|p| p.parse().ok()),
+        dport: get(&original, "dport").and_then(|p| p.parse().ok()),
+        icmptype: get(&original, "type").and_then(|t| t.parse().ok()),
+        reply_src: get(&reply, "src")?,
+        reply_sport: get(&reply, "sport").and_then(|p| p.parse().ok()),
+    });
+}
+
+// Local addresses and the directly connected networks of the system interfaces
+pub struct LocalNetworks {
+    pub addresses: Vec<IpAddr>,
+    pub networks: Vec<(String, String)>,
+}
+
+pub fn local_networks() -> LocalNetworks {
+    let mut local = LocalNetworks {
+        addresses: vec![],
+        networks: vec![],
+    };
+    if let Ok(interfaces) = NetworkInterface::show() {
+        for itf in interfaces.iter() {
+            for a in itf.addr.iter() {
+                local.addresses.push(a.ip());
+                if let Some(mask) = a.netmask() {
+                    let prefix = match mask {
+                        IpAddr::V4(m) => u32::from(m).count_ones(),
+                        IpAddr::V6(m) => u128::from(m).count_ones(),
+                    };
+                    local
+                        .networks
+                        .push((itf.name.clone(), format!("{}/{}", a.ip(), prefix)));
+                }
+            }
+        }
+    }
+    return local;
+}
+
+// Find the interface a remote address is reached through, preferring the most specific network
+pub fn interface_for(local: &LocalNetworks, addr: &str) -> Option<String> {
+    let ip = addr.parse::<IpAddr>().ok()?;
+    let mut best: Option<(u32, String)> = None;
+    for (name, network) in local.networks.iter() {
+        if address_matches(network, &ip) {
+            let prefix: u32 = network
+                .split_once('/')
+                .and_then(|(_a, p)| p.parse().ok())
+                .unwrap_or(0);
+            if best.as_ref().map(|(p, _n)| prefix > *p).unwrap_or(true) {
+                best = Some((prefix, name.clone()));
+            }
+        }
+    }
+    return best.map(|(_p, n)| n);
+}
+
+pub fn is_local(local: &LocalNetworks, addr: &str) -> bool {
+    match addr.parse::<IpAddr>() {
+        Ok(ip) => return local.addresses.contains(&ip),
+        Err(_e) => return false,
+    }
+}
+
+// Work out which filter hook and interfaces the entry passes through.
+// Destination nat happens before filtering, so the reply source is the filtered destination.
+fn entry_flow(local: &LocalNetworks, entry: &CtEntry) -> Option<(Flow, &'static str)> {
+    let mut flow = Flow {
+        iif: String::from(""),
+        oif: String::from(""),
+        saddr: entry.src.clone(),
+        daddr: entry.reply_src.clone(),
+        protocol: entry.protocol.clone(),
+        sport: entry.sport,
+        dport: entry.reply_sport.or(entry.dport),
+        icmptype: entry.icmptype,
+    };
+    if is_local(local, &flow.saddr) {
+        flow.oif = interface_for(local, &flow.daddr)?;
+        return Some((flow, "output"));
+    }
+    flow.iif = interface_for(local, &flow.saddr)?;
+    if is_local(local, &flow.daddr) {
+        return Some((flow, "input"));
+    }
+    flow.oif = interface_for(local, &flow.daddr)?;
+    return Some((flow, "forward"));
+}
+
+// The entries of a conntrack listing for established connections that the ruleset
+// would no longer accept as new connections
+fn disallowed(
+    config_items: &ConfigurationItems,
+    local: &LocalNetworks,
+    listing: &str,
+    management_port: u16,
+) -> Result<Vec<ConntrackTuple>, String> {
+    let mut entries: Vec<ConntrackTuple> = vec![];
+    for line in listing.lines() {
+        let Some(entry) = parse_entry(line) else {
+            continue;
+        };
+        // entries whose path cannot be determined are left alone
+        let Some((flow, direction)) = entry_flow(local, &entry) else {
+            continue;
+        };
+        let verdict = filter_verdict(config_items, &flow, "new", direction, Some(management_port))?;
+        if verdict.verdict != "drop" {
+            continue;
+        }
+        entries.push(ConntrackTuple {
+            protocol: entry.protocol,
+            src: entry.src,
+            dst: entry.dst,
+            sport: entry.sport,
+            dport: entry.dport,
+        });
+    }
+    return Ok(entries);
+}
+
+// Delete the conntrack entries of established connections that the new ruleset
+// would no longer accept as new connections. Returns the number of deleted entries.
+pub async fn flush_disallowed(
+    state: &Arc<AppState>,
+    config_items: &ConfigurationItems,
+) -> Result<usize, String> {
+    let listing = helper_command(state, Request::ConntrackList).await;
+    let port = state.settings.lock().await.connection.port;
+    let entries = disallowed(config_items, &local_networks(), &listing, port)?;
+    if entries.len() == 0 {
+        return Ok(0);
+    }
+    match helper_request(state, Request::ConntrackDelete { entries }).await {
+        Ok(Response::Deleted { count }) => return Ok(count),
+        Ok(response) => return Err(response.to_string()),
+        Err(e) => return Err(format!("Communication with main process failed: {}", e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdata;
+
+    // captured with conntrack -L -o extended
+    const HTTPS: &str = "ipv4     2 tcp      6 431999 ESTABLISHED src=10.0.0.2 dst=192.0.2.10 sport=40000 dport=443 src=192.0.2.10 dst=10.0.0.2 sport=443 dport=40000 [ASSURED] mark=0 zone=0 use=1";
+    const UNREPLIED: &str = "ipv4     2 tcp      6 119 SYN_SENT src=10.0.0.2 dst=192.0.2.20 sport=40001 dport=5432 [UNREPLIED] src=192.0.2.20 dst=10.0.0.2 sport=5432 dport=40001 mark=0 use=1";
+    const DNS: &str = "ipv4     2 udp      17 29 src=10.0.0.2 dst=192.0.2.20 sport=53000 dport=53 src=192.0.2.20 dst=10.0.0.2 sport=53 dport=53000 mark=0 use=1";
+    const PING: &str = "ipv4     2 icmp     1 29 src=10.0.0.2 dst=192.0.2.10 type=8 code=0 id=1234 src=192.0.2.10 dst=10.0.0.2 type=0 code=0 id=1234 mark=0 use=1";
+    // https to a public address that is forwarded to the database
+    const DNAT: &str = "ipv4     2 tcp      6 431999 ESTABLISHED src=10.0.0.2 dst=203.0.113.1 sport=40002 dport=443 src=192.0.2.20 dst=10.0.0.2 sport=5432 dport=40002 [ASSURED] mark=1 zone=2 use=1";
+    // an administrator connected to the firewall itself
+    const SSH: &str = "ipv4     2 tcp      6 431999 ESTABLISHED src=10.0.0.2 dst=10.0.0.1 sport=40003 dport=22 src=10.0.0.1 dst=10.0.0.2 sport=22 dport=40003 [ASSURED] mark=0 use=1";
+
+    // the firewall with lan on eth0 and the dmz on eth1
+    fn local() -> LocalNetworks {
+        return LocalNetworks {
+            addresses: vec!["10.0.0.1".parse().unwrap(), "192.0.2.1".parse().unwrap()],
+            networks: vec![
+                (String::from("eth0"), String::from("10.0.0.1/24")),
+                (String::from("eth1"), String::from("192.0.2.1/24")),
+            ],
+        };
+    }
+
+    #[test]
+    fn parse_entries() {
+        let entry = parse_entry(HTTPS).unwrap();
+        assert_eq!(entry.protocol, "tcp");
+        assert_eq!(
+            (entry.src.as_str(), entry.dst.as_str()),
+            ("10.0.0.2", "192.0.2.10")
+        );
+        assert_eq!((entry.sport, entry.dport), (Some(40000), Some(443)));
+        assert_eq!(
+            (entry.reply_src.as_str(), entry.reply_sport),
+            ("192.0.2.10", Some(443))
+        );
+
+        // connections that were never answered are not established
+        assert!(parse_entry(UNREPLIED).is_none());
+
+        let entry = parse_entry(DNS).unwrap();
+        assert_eq!(entry.protocol, "udp");
+        assert_eq!(entry.dport, Some(53));
+
+        let entry = parse_entry(PING).unwrap();
+        assert_eq!(entry.protocol, "icmp");
+        assert_eq!(entry.icmptype, Some(8));
+        assert_eq!(entry.dport, None);
+
+        // mark and zone only occur once and do not shift the reply tuple
+        let entry = parse_entry(DNAT).unwrap();
+        assert_eq!(entry.dst, "203.0.113.1");
+        assert_eq!(
+            (entry.reply_src.as_str(), entry.reply_sport),
+            ("192.0.2.20", Some(5432))
+        );
+
+        assert!(
+            parse_entry("conntrack v1.4.7 (conntrack-tools): 6 flow entries have been shown.")
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn flows_of_entries() {
+        let (flow, direction) = entry_flow(&local(), &parse_entry(HTTPS).unwrap()).unwrap();
+        assert_eq!(direction, "forward");
+        assert_eq!((flow.iif.as_str(), flow.oif.as_str()), ("eth0", "eth1"));
+
+        // the filter sees the destination after the translation
+        let (flow, direction) = entry_flow(&local(), &parse_entry(DNAT).unwrap()).unwrap();
+        assert_eq!(direction, "forward");
+        assert_eq!(
+            (flow.daddr.as_str(), flow.dport),
+            ("192.0.2.20", Some(5432))
+        );
+
+        let (flow, direction) = entry_flow(&local(), &parse_entry(SSH).unwrap()).unwrap();
+        assert_eq!(direction, "input");
+        assert_eq!((flow.iif.as_str(), flow.oif.as_str()), ("eth0", ""));
+
+        // no interface reaches the source
+        let mut entry = parse_entry(HTTPS).unwrap();
+        entry.src = String::from("198.51.100.7");
+        assert!(entry_flow(&local(), &entry).is_none());
+    }
+
+    #[test]
+    fn disallowed_entries() {
+        let config_items = testdata::configuration();
+        let listing = [HTTPS, UNREPLIED, DNS, PING, DNAT, SSH].join("\n");
+        let entries = disallowed(&config_items, &local(), &listing, 443).unwrap();
+        // only https from the office to the web server is allowed from lan to the dmz,
+        // the firewall itself has no input chain
+        let tuple = |line: &str| {
+            let entry = parse_entry(line).unwrap();
+            return ConntrackTuple {
+                protocol: entry.protocol,
+                src: entry.src,
+                dst: entry.dst,
+                sport: entry.sport,
+                dport: entry.dport,
+            };
+        };
+        assert_eq!(entries, vec![tuple(DNS), tuple(PING), tuple(DNAT)]);
+    }
+}
@@ -0,0 +1,509 @@
+use crate::{check_session, helper_command, helper_request, AppState};
+use axum::extract::{self, State};
+use axum::http::StatusCode;
+use axum::Json;
+use protocol::{Request, Response};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tower_sessions::Session;
+
+// Tables that are created temporarily by nftablesbuilder itself (packet tracing)
+const IGNORED_TABLES: [&str; 1] = ["nftablesbuilder_trace"];
+
+// A rule in normalized form. The body is the rule text for the generated script
+// and the json expression for the running ruleset, so bodies are only compared
+// between two listings of the running ruleset.
+#[derive(Clone, Default)]
+struct RuleModel {
+    id: Option<String>,
+    body: String,
+    handle: Option<u64>,
+}
+
+#[derive(Clone, Default)]
+struct ChainModel {
+    family: String,
+    table: String,
+    name: String,
+    // type, hook, priority and policy of base chains
+    declaration: String,
+    rules: Vec<RuleModel>,
+}
+
+// Tables, chains and sets of a ruleset. flush is true when the ruleset replaces
+// all tables, so tables that are not part of it count as drift.
+#[derive(Clone, Default)]
+pub struct Model {
+    flush: bool,
+    tables: Vec<(String, String)>,
+    sets: Vec<(String, String, String)>,
+    chains: Vec<ChainModel>,
+}
+
+impl Model {
+    fn chain_mut(&mut self, family: &str, table: &str, name: &str) -> &mut ChainModel {
+        let pos = self
+            .chains
+            .iter()
+            .position(|c| c.family == family && c.table == table && c.name == name);
+        match pos {
+            Some(p) => return &mut self.chains[p],
+            None => {
+                self.chains.push(ChainModel {
+                    family: String::from(family),
+                    table: String::from(table),
+                    name: String::from(name),
+                    ..Default::default()
+                });
+                return self.chains.last_mut().unwrap();
+            }
+        }
+    }
+
+    fn chain(&self, family: &str, table: &str, name: &str) -> Option<&ChainModel> {
+        return self
+            .chains
+            .iter()
+            .find(|c| c.family == family && c.table == table && c.name == name);
+    }
+
+    fn has_table(&self, family: &str, table: &str) -> bool {
+        return self.tables.iter().any(|(f, t)| f == family && t == table);
+    }
+}
+
+// change is "added", "removed" or "changed", kind is "table", "chain", "set" or "rule"
+#[derive(Clone, Deserialize, Serialize)]
+pub struct DriftItem {
+    pub change: String,
+    pub kind: String,
+    pub family: String,
+    pub table: String,
+    pub name: String,
+    pub rule_id: Option<String>,
+    pub handle: Option<u64>,
+    pub detail: String,
+}
+
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct DriftReport {
+    // unix time of the check
+    pub checked: i64,
+    pub drifted: bool,
+    pub items: Vec<DriftItem>,
+    pub error: Option<String>,
+}
+
+// Drift detection state kept in AppState
+#[derive(Default)]
+pub struct DriftState {
+    // the running ruleset as listed right after the last install
+    pub baseline: Option<Model>,
+    // minutes between scheduled checks, 0 disables scheduling
+    pub interval: u64,
+    pub report: Option<DriftReport>,
+}
+
+fn split_comment(line: &str) -> (String, Option<String>) {
+    // nft comments start with # and never occur inside the generated statements
+    let statement = match line.find('#') {
+        Some(pos) => &line[..pos],
+        None => line,
+    };
+    let mut id = None;
+    let mut body = String::from(statement);
+    if let Some(pos) = statement.find("comment \"") {
+        let rest = &statement[pos + 9..];
+        if let Some(end) = rest.find('"') {
+            id = Some(String::from(&rest[..end]));
+            body = format!("{}{}", &statement[..pos], &rest[end + 1..]);
+        }
+    }
+    let normalized: Vec<&str> = body.split_whitespace().collect();
+    return (normalized.join(" "), id);
+}
+
+// Normalize a generated nft script
+pub fn script_model(script: &str) -> Model {
+    let mut model = Model::default();
+    let mut table: Option<(String, String)> = None;
+    let mut chain: Option<String> = None;
+    // depth of the set, counter or other named object of a table that is being skipped
+    let mut block_depth: usize = 0;
+    for raw in script.lines() {
+        let (line, id) = split_comment(raw);
+        if line.len() == 0 {
+            continue;
+        }
+        let words: Vec<&str> = line.split(' ').collect();
+        let opens = line.matches('{').count();
+        let closes = line.matches('}').count();
+        if block_depth > 0 {
+            block_depth = (block_depth + opens).saturating_sub(closes);
+            continue;
+        }
+        match (&table, &chain) {
+            (None, _) => {
+                if line == "flush ruleset" {
+                    model.flush = true;
+                } else if words[0] == "table" && words.len() == 4 && words[3] == "{" {
+                    let t = (String::from(words[1]), String::from(words[2]));
+                    if !model.has_table(&t.0, &t.1) {
+                        model.tables.push(t.clone());
+                    }
+                    table = Some(t);
+                }
+            }
+            (Some((family, name)), None) => {
+                if line == "}" {
+                    table = None;
+                } else if words[0] == "chain" && words.len() == 3 {
+                    model.chain_mut(family, name, words[1]);
+                    chain = Some(String::from(words[1]));
+                } else {
+                    if words[0] == "set" && words.len() > 2 {
+                        let set = (family.clone(), name.clone(), String::from(words[1]));
+                        if !model.sets.contains(&set) {
+                            model.sets.push(set);
+                        }
+                    }
+                    block_depth = opens.saturating_sub(closes);
+                }
+            }
+            (Some((family, name)), Some(c)) => {
+                if line == "}" {
+                    chain = None;
+                } else if words[0] == "type" {
+                    let c = c.clone();
+                    model.chain_mut(family, name, &c).declaration = line.clone();
+                } else {
+                    let c = c.clone();
+                    model.chain_mut(family, name, &c).rules.push(RuleModel {
+                        id: id,
+                        body: line.clone(),
+                        handle: None,
+                    });
+                }
+            }
+        }
+    }
+    return model;
+}
+
+// Normalize the output of nft -j -a list ruleset
+pub fn ruleset_model(json: &str) -> Result<Model, String> {
+    let ruleset: Value = match serde_json::from_str(json) {
+        Ok(v) => v,
+        Err(_e) => return Err(String::from(json.trim())),
+    };
+    let Some(objects) = ruleset["nftables"].as_array() else {
+        return Err(String::from("Invalid ruleset listing"));
+    };
+    let text = |v: &Value, key: &str| String::from(v[key].as_str().unwrap_or(""));
+    let mut model = Model::default();
+    for object in objects.iter() {
+        if let Some(t) = object.get("table") {
+            model.tables.push((text(t, "family"), text(t, "name")));
+        } else if let Some(s) = object.get("set") {
+            model
+                .sets
+                .push((text(s, "family"), text(s, "table"), text(s, "name")));
+        } else if let Some(c) = object.get("chain") {
+            let chain = model.chain_mut(&text(c, "family"), &text(c, "table"), &text(c, "name"));
+            if c.get("hook").is_some() {
+                chain.declaration = format!(
+                    "type {} hook {} priority {} policy {}",
+                    text(c, "type"),
+                    text(c, "hook"),
+                    c["prio"],
+                    text(c, "policy")
+                );
+            }
+        } else if let Some(r) = object.get("rule") {
+            let chain = model.chain_mut(&text(r, "family"), &text(r, "table"), &text(r, "chain"));
+            chain.rules.push(RuleModel {
+                id: r["comment"].as_str().map(String::from),
+                body: r["expr"].to_string(),
+                handle: r["handle"].as_u64(),
+            });
+        }
+    }
+    return Ok(model);
+}
+
+fn item(change: &str, kind: &str, family: &str, table: &str, name: &str) -> DriftItem {
+    return DriftItem {
+        change: String::from(change),
+        kind: String::from(kind),
+        family: String::from(family),
+        table: String::from(table),
+        name: String::from(name),
+        rule_id: None,
+        handle: None,
+        detail: String::from(""),
+    };
+}
+
+// Group the rules of a chain by rule id, rules without id are grouped together
+fn rules_by_id(chain: &ChainModel) -> HashMap<Option<String>, Vec<&RuleModel>> {
+    let mut groups: HashMap<Option<String>, Vec<&RuleModel>> = HashMap::new();
+    for rule in chain.rules.iter() {
+        groups.entry(rule.id.clone()).or_default().push(rule);
+    }
+    return groups;
+}
+
+fn compare_rules(
+    expected: &ChainModel,
+    running: &ChainModel,
+    baseline: Option<&ChainModel>,
+    items: &mut Vec<DriftItem>,
+) {
+    let expected_rules = rules_by_id(expected);
+    let running_rules = rules_by_id(running);
+    let baseline_rules = baseline.map(rules_by_id).unwrap_or_default();
+    let rule_item =
+        |change: &str, id: &Option<String>, rule: Option<&RuleModel>, detail: String| {
+            let mut i = item(
+                change,
+                "rule",
+                &running.family,
+                &running.table,
+                &running.name,
+            );
+            i.rule_id = id.clone();
+            i.handle = rule.and_then(|r| r.handle);
+            i.detail = detail;
+            return i;
+        };
+
+    let mut ids: Vec<&Option<String>> = expected_rules.keys().chain(running_rules.keys()).collect();
+    ids.sort();
+    ids.dedup();
+    for id in ids {
+        let want = expected_rules.get(id).map(|r| r.len()).unwrap_or(0);
+        let have = running_rules.get(id).cloned().unwrap_or_default();
+        if have.len() > want {
+            for rule in have[want..].iter() {
+                items.push(rule_item("added", id, Some(rule), rule.body.clone()));
+            }
+        } else if have.len() < want {
+            let missing = &expected_rules[id][have.len()..];
+            for rule in missing.iter() {
+                items.push(rule_item("removed", id, None, rule.body.clone()));
+            }
+        }
+        // rules present in both are compared with the listing taken after the install
+        if let Some(before) = baseline_rules.get(id) {
+            for (now, then) in have.iter().zip(before.iter()) {
+                if now.body != then.body {
+                    items.push(rule_item("changed", id, Some(now), now.body.clone()));
+                }
+            }
+        }
+    }
+}
+
+// Compare the running ruleset with the generated script and, when available,
+// with the running ruleset as it was right after the install
+pub fn compare(expected: &Model, running: &Model, baseline: Option<&Model>) -> Vec<DriftItem> {
+    let mut items: Vec<DriftItem> = vec![];
+    let ignored = |table: &String| IGNORED_TABLES.contains(&table.as_str());
+    // without flush ruleset only the tables of the script are managed
+    let managed = |family: &String, table: &String| {
+        !ignored(table) && (expected.flush || expected.has_table(family, table))
+    };
+
+    for (family, table) in expected.tables.iter() {
+        if !running.has_table(family, table) {
+            items.push(item("removed", "table", family, table, table));
+        }
+    }
+    for (family, table) in running.tables.iter() {
+        if managed(family, table) && !expected.has_table(family, table) {
+            items.push(item("added", "table", family, table, table));
+        }
+    }
+
+    let in_both = |family: &String, table: &String| {
+        expected.has_table(family, table) && running.has_table(family, table)
+    };
+    for (family, table, name) in expected.sets.iter() {
+        if in_both(family, table)
+            && !running
+                .sets
+                .contains(&(family.clone(), table.clone(), name.clone()))
+        {
+            items.push(item("removed", "set", family, table, name));
+        }
+    }
+    for (family, table, name) in running.sets.iter() {
+        if in_both(family, table)
+            && !expected
+                .sets
+                .contains(&(family.clone(), table.clone(), name.clone()))
+        {
+            items.push(item("added", "set", family, table, name));
+        }
+    }
+
+    for chain in expected.chains.iter() {
+        if in_both(&chain.family, &chain.table)
+            && running
+                .chain(&chain.family, &chain.table, &chain.name)
+                .is_none()
+        {
+            items.push(item(
+                "removed",
+                "chain",
+                &chain.family,
+                &chain.table,
+                &chain.name,
+            ));
+        }
+    }
+    for chain in running.chains.iter() {
+        if !in_both(&chain.family, &chain.table) {
+            continue;
+        }
+        let Some(want) = expected.chain(&chain.family, &chain.table, &chain.name) else {
+            items.push(item(
+                "added",
+                "chain",
+                &chain.family,
+                &chain.table,
+                &chain.name,
+            ));
+            continue;
+        };
+        let before = baseline.and_then(|b| b.chain(&chain.family, &chain.table, &chain.name));
+        if let Some(b) = before {
+            if b.declaration != chain.declaration {
+                let mut i = item("changed", "chain", &chain.family, &chain.table, &chain.name);
+                i.detail = chain.declaration.clone();
+                items.push(i);
+            }
+        }
+        compare_rules(want, chain, before, &mut items);
+    }
+    return items;
+}
+
+// List the running ruleset through the main process
+async fn running_model(state: &Arc<AppState>) -> Result<Model, String> {
+    let listing = helper_command(state, Request::ListRuleset).await;
+    return ruleset_model(&listing);
+}
+
+// The installed script, read by the main process
+async fn installed_script(state: &Arc<AppState>) -> Result<String, String> {
+    match helper_request(state, Request::InstalledScript).await {
+        Ok(Response::Text { text }) => return Ok(text),
+        Ok(response) => return Err(response.to_string()),
+        Err(e) => return Err(format!("Communication with main process failed: {}", e)),
+    }
+}
+
+// Remember the running ruleset right after an install to detect changed rules later
+pub async fn record_baseline(state: &Arc<AppState>) {
+    let baseline = running_model(state).await.ok();
+    let mut drift = state.drift.lock().await;
+    drift.baseline = baseline;
+    drift.report = None;
+}
+
+// Compare the running ruleset with the installed script and store the report
+pub async fn check(state: &Arc<AppState>) -> DriftReport {
+    let mut report = DriftReport {
+        checked: time::OffsetDateTime::now_utc().unix_timestamp(),
+        ..Default::default()
+    };
+    let result = match installed_script(state).await {
+        Ok(script) => match running_model(state).await {
+            Ok(running) => {
+                let baseline = state.drift.lock().await.baseline.clone();
+                Ok(compare(&script_model(&script), &running, baseline.as_ref()))
+            }
+            Err(e) => Err(e),
+        },
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(items) => {
+            report.drifted = items.len() > 0;
+            report.items = items;
+        }
+        Err(e) => report.error = Some(e),
+    }
+    state.drift.lock().await.report = Some(report.clone());
+    return report;
+}
+
+// Background task running the scheduled drift checks
+pub async fn schedule(state: Arc<AppState>) {
+    let mut elapsed: u64 = 0;
+    loop {
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        elapsed += 1;
+        let interval = state.drift.lock().await.interval;
+        if interval > 0 && elapsed >= interval {
+            elapsed = 0;
+            check(&state).await;
+        }
+    }
+}
+
+pub async fn check_drift(
+    session: Session,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<DriftReport>, StatusCode> {
+    if !check_session(session, &state).await {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+    return Ok(Json(check(&state).await));
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct DriftSchedule {
+    // minutes between checks, 0 disables scheduled checks
+    pub interval: u64,
+}
+
+pub async fn schedule_drift(
+    session: Session,
+    State(state): State<Arc<AppState>>,
+    extract::Json(payload): extract::Json<DriftSchedule>,
+) -> Result<Json<DriftSchedule>, StatusCode> {
+    if !check_session(session, &state).await {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+    state.drift.lock().await.interval = payload.interval;
+    return Ok(Json(payload));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generate_script;
+    use crate::testdata;
+
+    #[test]
+    fn script_model_with_counters() {
+        let mut config_items = testdata::configuration();
+        config_items.logging = String::from("counter");
+        let script = generate_script(
+            testdata::json(&config_items),
+            String::from("/usr/sbin/nft"),
+            443,
+        )
+        .unwrap();
+        let model = script_model(&script.lines.join("\n"));
+        assert!(model.has_table("inet", "filter_inet"));
+        let chain = model.chain("inet", "filter_inet", "lan_dmz").unwrap();
+        assert!(chain.rules.len() > 0);
+        assert!(chain.rules.iter().all(|r| r.id.is_some()));
+    }
+}
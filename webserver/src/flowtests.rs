@@ -0,0 +1,309 @@
+use crate::rules::{address_range, addresses_from_defs};
+use crate::simulator::{simulate, Packet, SimulationResult};
+use crate::{
+    check_session, icmpv4_types_from_services, icmpv6_types_from_services, AppState, Configuration,
+    ConfigurationItems,
+};
+use axum::extract::{self, State};
+use axum::http::StatusCode;
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::sync::Arc;
+use tower_sessions::Session;
+
+// A flow the configuration must allow or block, stored with the configuration. For
+// example "host pc1 on lan may reach the dmz web server on port 443".
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct FlowTest {
+    pub name: String,
+    // configured interfaces, an empty iif means the firewall itself sends the packet,
+    // an empty oif that it receives it
+    #[serde(default)]
+    pub iif: String,
+    #[serde(default)]
+    pub oif: String,
+    // host, host group or network name, or an address
+    pub source: String,
+    pub destination: String,
+    // a configured service, or else a protocol (tcp, udp, icmp or icmpv6) and port
+    #[serde(default)]
+    pub service: String,
+    #[serde(default)]
+    pub protocol: String,
+    #[serde(default)]
+    pub dport: Option<u16>,
+    // the flow must be accepted, otherwise it must not be
+    pub allow: bool,
+}
+
+// One packet of a test, a test has a packet for every combination of its addresses and
+// protocols
+#[derive(Clone, Deserialize, Serialize)]
+pub struct FlowOutcome {
+    pub packet: Packet,
+    pub result: SimulationResult,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct FlowTestResult {
+    pub name: String,
+    pub allow: bool,
+    pub passed: bool,
+    pub flows: Vec<FlowOutcome>,
+    pub error: Option<String>,
+}
+
+// An address that lies in an address definition: the address itself or the first
+// address of a network or range
+fn representative(def: &str) -> Option<IpAddr> {
+    let first = def.split(|c| c == '/' || c == '-').next()?;
+    return first.trim().parse::<IpAddr>().ok();
+}
+
+// The addresses a source or destination of a test stands for
+fn addresses(config_items: &ConfigurationItems, def: &String) -> Vec<IpAddr> {
+    if address_range(def).is_some() {
+        return representative(def).into_iter().collect();
+    }
+    let (ipv4s, ipv6s) = addresses_from_defs(config_items, &vec![def.clone()]);
+    return ipv4s
+        .iter()
+        .chain(ipv6s.iter())
+        .filter_map(|a| representative(a))
+        .collect();
+}
+
+// Protocol, destination port and icmp type of the packets of a test
+fn protocols(
+    config_items: &ConfigurationItems,
+    test: &FlowTest,
+) -> Vec<(String, Option<u16>, Option<u8>)> {
+    let mut result = vec![];
+    if test.service.len() == 0 {
+        result.push((test.protocol.to_lowercase(), test.dport, None));
+        return result;
+    }
+    if let Some(service) = config_items.services.get(&test.service) {
+        for protocol in ["TCP", "UDP"] {
+            if service.protocol.contains(protocol) {
+                result.push((protocol.to_lowercase(), Some(service.port), None));
+            }
+        }
+    }
+    let services = vec![test.service.clone()];
+    for (protocol, types) in [
+        ("icmp", icmpv4_types_from_services(&services)),
+        ("icmpv6", icmpv6_types_from_services(&services)),
+    ] {
+        for icmptype in types {
+            result.push((String::from(protocol), None, icmptype.parse().ok()));
+        }
+    }
+    return result;
+}
+
+fn run_test(
+    config_items: &ConfigurationItems,
+    test: &FlowTest,
+    management_port: Option<u16>,
+) -> Result<Vec<FlowOutcome>, String> {
+    let sources = addresses(config_items, &test.source);
+    let destinations = addresses(config_items, &test.destination);
+    let mut flows: Vec<FlowOutcome> = vec![];
+    for (protocol, dport, icmptype) in protocols(config_items, test) {
+        for saddr in sources.iter() {
+            for daddr in destinations.iter() {
+                // icmp only exists for ipv4 and icmpv6 only for ipv6
+                let ipv6 = saddr.is_ipv6();
+                if daddr.is_ipv6() != ipv6
+                    || (protocol == "icmp" && ipv6)
+                    || (protocol == "icmpv6" && !ipv6)
+                {
+                    continue;
+                }
+                let packet = Packet {
+                    iif: test.iif.clone(),
+                    oif: test.oif.clone(),
+                    saddr: saddr.to_string(),
+                    daddr: daddr.to_string(),
+                    protocol: protocol.clone(),
+                    dport: dport,
+                    icmptype: icmptype,
+                    ct_state: String::from("new"),
+                    ..Default::default()
+                };
+                let result = simulate(config_items, &packet, management_port)?;
+                flows.push(FlowOutcome {
+                    packet: packet,
+                    result: result,
+                });
+            }
+        }
+    }
+    if flows.len() == 0 {
+        return Err(format!(
+            "No flow from {} to {} with a matching address family and protocol",
+            test.source, test.destination
+        ));
+    }
+    return Ok(flows);
+}
+
+// Evaluate the flow tests of a configuration against its rules
+pub fn run(config_items: &ConfigurationItems, management_port: Option<u16>) -> Vec<FlowTestResult> {
+    let mut results: Vec<FlowTestResult> = vec![];
+    for test in config_items.tests.iter() {
+        match run_test(config_items, test, management_port) {
+            Ok(flows) => results.push(FlowTestResult {
+                name: test.name.clone(),
+                allow: test.allow,
+                passed: flows
+                    .iter()
+                    .all(|f| (f.result.verdict == "accept") == test.allow),
+                flows: flows,
+                error: None,
+            }),
+            Err(e) => results.push(FlowTestResult {
+                name: test.name.clone(),
+                allow: test.allow,
+                passed: false,
+                flows: vec![],
+                error: Some(e),
+            }),
+        }
+    }
+    return results;
+}
+
+// A line for every failed test, naming the first flow that violates it
+pub fn failures(results: &[FlowTestResult]) -> Vec<String> {
+    let mut messages: Vec<String> = vec![];
+    for result in results.iter().filter(|r| !r.passed) {
+        if let Some(e) = &result.error {
+            messages.push(format!("{}: {}", result.name, e));
+            continue;
+        }
+        let Some(flow) = result
+            .flows
+            .iter()
+            .find(|f| (f.result.verdict == "accept") != result.allow)
+        else {
+            continue;
+        };
+        let rule = match flow.result.rule {
+            Some(r) => format!(" rule {}", r + 1),
+            None => String::from(""),
+        };
+        messages.push(format!(
+            "{}: {} {} -> {}{} is {} by {}{} ({})",
+            result.name,
+            flow.packet.protocol,
+            flow.packet.saddr,
+            flow.packet.daddr,
+            flow.packet
+                .dport
+                .map(|p| format!(":{}", p))
+                .unwrap_or_default(),
+            flow.result.verdict,
+            flow.result.chain,
+            rule,
+            flow.result.reason
+        ));
+    }
+    return messages;
+}
+
+// Run the flow tests of a configuration without installing it
+pub async fn run_flow_tests(
+    session: Session,
+    State(state): State<Arc<AppState>>,
+    extract::Json(payload): extract::Json<Configuration>,
+) -> Result<Json<Vec<FlowTestResult>>, (StatusCode, String)> {
+    if !check_session(session, &state).await {
+        return Err((StatusCode::UNAUTHORIZED, String::from("Not logged in")));
+    }
+    let config_items: ConfigurationItems = serde_json::from_str(&payload.json).map_err(|e| {
+        (
+            StatusCode::BAD_REQUEST,
+            format!("Invalid configuration: {}", e),
+        )
+    })?;
+    let port = state.settings.lock().await.connection.port;
+    return Ok(Json(run(&config_items, Some(port))));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdata;
+
+    fn test(name: &str, source: &str, destination: &str, service: &str, allow: bool) -> FlowTest {
+        return FlowTest {
+            name: String::from(name),
+            iif: String::from("lan"),
+            oif: String::from("dmz"),
+            source: String::from(source),
+            destination: String::from(destination),
+            service: String::from(service),
+            allow: allow,
+            ..Default::default()
+        };
+    }
+
+    #[test]
+    fn passing_and_failing_tests() {
+        let mut config_items = testdata::configuration();
+        config_items.tests = vec![
+            test("office to web", "office", "web", "https", true),
+            test("pc1 to db", "pc1", "db", "pg", true),
+        ];
+        let results = run(&config_items, None);
+        assert_eq!(results.len(), 2);
+
+        // web only has an ipv4 address the office can reach
+        assert!(results[0].passed);
+        assert_eq!(results[0].flows.len(), 1);
+        assert_eq!(results[0].flows[0].packet.daddr, "192.0.2.10");
+        assert_eq!(results[0].flows[0].result.rule, Some(0));
+
+        assert!(!results[1].passed);
+        assert_eq!(
+            failures(&results),
+            [format!(
+                "pc1 to db: tcp 10.0.0.2 -> 192.0.2.20:5432 is drop by lan_dmz ({})",
+                results[1].flows[0].result.reason
+            )]
+        );
+    }
+
+    #[test]
+    fn tests_without_flows_fail() {
+        let mut config_items = testdata::configuration();
+        // pc1 has no ipv6 address to reach the database with
+        let mut ping = test("ping db", "pc1", "db", "", true);
+        ping.protocol = String::from("icmpv6");
+        config_items.tests = vec![ping];
+        let results = run(&config_items, None);
+        assert!(!results[0].passed);
+        assert!(failures(&results)[0].starts_with("ping db: No flow from pc1 to db"));
+    }
+
+    #[test]
+    fn management_rule() {
+        let mut config_items = testdata::configuration();
+        testdata::drop_input(&mut config_items, "lan_in", "lan");
+        let mut webserver = test("office to webserver", "pc1", "10.0.0.1", "https", true);
+        webserver.oif = String::from("");
+        config_items.tests = vec![webserver];
+        let results = run(&config_items, Some(443));
+        assert!(!results[0].passed);
+        assert_eq!(results[0].flows[0].result.chain, "lan_in");
+
+        config_items.management_rule = true;
+        let results = run(&config_items, Some(443));
+        assert!(results[0].passed);
+        assert_eq!(results[0].flows[0].result.reason, "management rule");
+        assert!(failures(&results).is_empty());
+    }
+}
@@ -0,0 +1,245 @@
+use crate::{check_session, drift, helper_command, helper_request, impact, lockout, AppState};
+use axum::extract::{ConnectInfo, Path, State};
+use axum::http::StatusCode;
+use axum::{Extension, Json};
+use protocol::{Request, Response};
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tower_sessions::Session;
+
+// An installed ruleset as archived by nftablesbuilder
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Generation {
+    pub generation: u64,
+    // unix time of the install
+    pub timestamp: u64,
+    pub user: String,
+    pub name: String,
+    pub sha256: String,
+}
+
+// Largest table of the longest common subsequence, larger differences are shown as all
+// old lines removed and all new lines added
+const MAX_DIFF_CELLS: usize = 4_000_000;
+
+// Line based diff of two scripts, every line is prefixed with " ", "-" or "+"
+pub fn diff_lines(old: &str, new: &str) -> Vec<String> {
+    let a: Vec<&str> = old.lines().collect();
+    let b: Vec<&str> = new.lines().collect();
+    // lines before the first and after the last change are the same in both scripts
+    let prefix = a.iter().zip(b.iter()).take_while(|(x, y)| x == y).count();
+    let suffix = a[prefix..]
+        .iter()
+        .rev()
+        .zip(b[prefix..].iter().rev())
+        .take_while(|(x, y)| x == y)
+        .count();
+    let mut result: Vec<String> = a[..prefix].iter().map(|l| format!(" {}", l)).collect();
+    let unchanged: Vec<String> = a[a.len() - suffix..]
+        .iter()
+        .map(|l| format!(" {}", l))
+        .collect();
+    let a = &a[prefix..a.len() - suffix];
+    let b = &b[prefix..b.len() - suffix];
+    if (a.len() + 1) * (b.len() + 1) > MAX_DIFF_CELLS {
+        result.extend(a.iter().map(|l| format!("-{}", l)));
+        result.extend(b.iter().map(|l| format!("+{}", l)));
+    } else {
+        result.extend(changed_lines(a, b));
+    }
+    result.extend(unchanged);
+    return result;
+}
+
+fn changed_lines(a: &[&str], b: &[&str]) -> Vec<String> {
+    // longest common subsequence table, computed from the end
+    let mut lcs = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lcs[i][j] = if a[i] == b[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+    let mut result: Vec<String> = vec![];
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        if i < a.len() && j < b.len() && a[i] == b[j] {
+            result.push(format!(" {}", a[i]));
+            i += 1;
+            j += 1;
+        } else if j < b.len() && (i == a.len() || lcs[i][j + 1] >= lcs[i + 1][j]) {
+            result.push(format!("+{}", b[j]));
+            j += 1;
+        } else {
+            result.push(format!("-{}", a[i]));
+            i += 1;
+        }
+    }
+    return result;
+}
+
+async fn show(state: &Arc<AppState>, generation: u64) -> Result<String, (StatusCode, String)> {
+    match helper_request(state, Request::HistoryShow { generation }).await {
+        Ok(Response::Text { text }) => return Ok(text),
+        Ok(response) => return Err((StatusCode::NOT_FOUND, response.to_string())),
+        Err(e) => {
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Communication with main process failed: {}", e),
+            ))
+        }
+    }
+}
+
+async fn generations(state: &Arc<AppState>) -> Result<Vec<Generation>, (StatusCode, String)> {
+    let response = helper_command(state, Request::HistoryList).await;
+    return serde_json::from_str(&response)
+        .map_err(|_e| (StatusCode::INTERNAL_SERVER_ERROR, response));
+}
+
+// Refuse a rollback that keeps the client from reaching the webserver. The target is the
+// given generation, or the one before the last for a plain rollback, which is also the
+// ruleset before an unconfirmed install. Rulesets that were not installed from a
+// configuration of this webserver cannot be checked.
+pub async fn check_rollback(
+    state: &Arc<AppState>,
+    generation: Option<u64>,
+    client: SocketAddr,
+    local: SocketAddr,
+) -> Result<(), (StatusCode, String)> {
+    let generations = generations(state).await?;
+    let target = match generation {
+        Some(g) => generations.iter().find(|x| x.generation == g),
+        None if generations.len() >= 2 => generations.get(generations.len() - 2),
+        None => None,
+    };
+    // the main process reports a missing generation
+    let Some(target) = target else {
+        return Ok(());
+    };
+    let (savepath, port) = {
+        let settings = state.settings.lock().await;
+        (settings.paths.savepath.clone(), settings.connection.port)
+    };
+    let config_items = match impact::recorded_configuration(&savepath, &target.sha256).await {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!(
+                "Access after the rollback to generation {} is not checked: {}",
+                target.generation, e
+            );
+            return Ok(());
+        }
+    };
+    match lockout::check(&config_items, client.ip(), local.ip(), port) {
+        Ok(Some(warning)) => eprintln!("{}", warning),
+        Ok(None) => {}
+        Err(e) => return Err((StatusCode::BAD_REQUEST, format!("Rollback refused: {}", e))),
+    }
+    return Ok(());
+}
+
+pub async fn list_history(
+    session: Session,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<Vec<Generation>>, (StatusCode, String)> {
+    if !check_session(session, &state).await {
+        return Err((StatusCode::UNAUTHORIZED, String::from("Not logged in")));
+    }
+    return Ok(Json(generations(&state).await?));
+}
+
+pub async fn show_generation(
+    session: Session,
+    Path(generation): Path<u64>,
+    State(state): State<Arc<AppState>>,
+) -> Result<String, (StatusCode, String)> {
+    if !check_session(session, &state).await {
+        return Err((StatusCode::UNAUTHORIZED, String::from("Not logged in")));
+    }
+    return show(&state, generation).await;
+}
+
+pub async fn diff_generations(
+    session: Session,
+    Path((old, new)): Path<(u64, u64)>,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<Vec<String>>, (StatusCode, String)> {
+    if !check_session(session, &state).await {
+        return Err((StatusCode::UNAUTHORIZED, String::from("Not logged in")));
+    }
+    let old_script = show(&state, old).await?;
+    let new_script = show(&state, new).await?;
+    return Ok(Json(diff_lines(&old_script, &new_script)));
+}
+
+pub async fn rollback_generation(
+    session: Session,
+    Path(generation): Path<u64>,
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    Extension(local): Extension<lockout::LocalAddr>,
+) -> Result<String, (StatusCode, String)> {
+    if !check_session(session, &state).await {
+        return Err((StatusCode::UNAUTHORIZED, String::from("Not logged in")));
+    }
+    check_rollback(&state, Some(generation), client, local.0).await?;
+    let user = state.current_user.lock().await.clone();
+    let response = helper_command(&state, Request::HistoryRollback { generation, user }).await;
+    if response != "OK" {
+        return Err((StatusCode::BAD_REQUEST, response));
+    }
+    drift::record_baseline(&state).await;
+    return Ok(response);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diff_of_changed_lines() {
+        let old = "table inet filter {\n  tcp dport 22 accept\n  tcp dport 80 accept\n}\n";
+        let new = "table inet filter {\n  tcp dport 443 accept\n  tcp dport 80 accept\n  udp dport 53 accept\n}\n";
+        assert_eq!(
+            diff_lines(old, new),
+            [
+                " table inet filter {",
+                "+  tcp dport 443 accept",
+                "-  tcp dport 22 accept",
+                "   tcp dport 80 accept",
+                "+  udp dport 53 accept",
+                " }",
+            ]
+        );
+        assert_eq!(
+            diff_lines(old, old)
+                .iter()
+                .filter(|l| !l.starts_with(' '))
+                .count(),
+            0
+        );
+        assert_eq!(diff_lines("", "a\nb"), ["+a", "+b"]);
+        assert_eq!(diff_lines("a\nb", ""), ["-a", "-b"]);
+        // a line repeated at the end is not counted twice by the prefix and the suffix
+        assert_eq!(diff_lines("a\na", "a\na\na"), [" a", " a", "+a"]);
+    }
+
+    #[test]
+    fn large_diffs_are_not_aligned() {
+        let old: Vec<String> = (0..3000).map(|i| format!("old {}", i)).collect();
+        let new: Vec<String> = (0..3000).map(|i| format!("new {}", i)).collect();
+        let old = format!("first\n{}\nlast", old.join("\n"));
+        let new = format!("first\n{}\nlast", new.join("\n"));
+        let diff = diff_lines(&old, &new);
+        assert_eq!(diff.len(), 6002);
+        assert_eq!(diff[0], " first");
+        assert_eq!(diff[1], "-old 0");
+        assert_eq!(diff[3001], "+new 0");
+        assert_eq!(diff[6001], " last");
+    }
+}
@@ -0,0 +1,834 @@
+use crate::rules::{address_range, expand_filter_rule, expand_nat_rule, NatMatch, RuleMatch};
+use crate::simulator::{simulate, Packet, SimulationResult};
+use crate::{
+    chain_on_loopback, check_session, get_interface, helper_request, AppState, Configuration,
+    ConfigurationItems,
+};
+use axum::extract::{self, State};
+use axum::http::StatusCode;
+use axum::Json;
+use protocol::{Request, Response};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tower_sessions::Session;
+
+// Installed configurations are kept in this directory of the save path, named after
+// the sha256 of the script they generated, so the one matching the conf file is found
+// again after a rollback
+const INSTALLED_DIR: &str = "installed";
+const INSTALLED_RETENTION: usize = 20;
+
+// Defaults generate_script applies to the whole ruleset and to every filter chain
+const GLOBAL_DEFAULTS: [&str; 3] = ["SYNFlood", "InvalidTCPFlags", "TCPMSS"];
+const CHAIN_DEFAULTS: [&str; 6] = [
+    "AllowNAT",
+    "SRCEQDST",
+    "CT-Established",
+    "CT-Related",
+    "CT-Invalid",
+    "ICMP",
+];
+
+// Addresses that stand for any source or destination address of a family in probes,
+// from the documentation ranges. They differ so that they never trigger SRCEQDST.
+const ANY_SOURCE_IPV4: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+const ANY_DESTINATION_IPV4: Ipv4Addr = Ipv4Addr::new(198, 51, 100, 1);
+const ANY_SOURCE_IPV6: Ipv6Addr = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1);
+const ANY_DESTINATION_IPV6: Ipv6Addr = Ipv6Addr::new(0x2001, 0xdb8, 1, 0, 0, 0, 0, 1);
+
+// A flow that gets a different verdict from the proposed configuration
+#[derive(Clone, Deserialize, Serialize)]
+pub struct FlowChange {
+    pub packet: Packet,
+    // host or network of the addresses and service of the destination port, empty when
+    // the configurations do not name them
+    pub source: String,
+    pub destination: String,
+    pub service: String,
+    pub installed: SimulationResult,
+    pub proposed: SimulationResult,
+}
+
+// A nat match that translates differently, a translation is None when the
+// configuration does not translate the match
+#[derive(Clone, Deserialize, Serialize)]
+pub struct NatChange {
+    pub chain: String,
+    // "dnat" or "snat"
+    pub kind: String,
+    pub rule: RuleMatch,
+    pub installed: Option<String>,
+    pub proposed: Option<String>,
+}
+
+// A changed default or chain policy, chain is empty for defaults of the whole ruleset.
+// States are "active" or "inactive" for defaults and the policy for "policy", where
+// "none" means the chain does not exist.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct DefaultChange {
+    pub chain: String,
+    pub name: String,
+    pub installed: String,
+    pub proposed: String,
+}
+
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct Impact {
+    pub newly_allowed: Vec<FlowChange>,
+    pub newly_blocked: Vec<FlowChange>,
+    pub nat: Vec<NatChange>,
+    pub defaults: Vec<DefaultChange>,
+    // probes the simulator could not evaluate
+    pub errors: Vec<String>,
+    // the changes as plain text for a change request
+    pub ticket: String,
+}
+
+fn any_address(family: &str, source: bool) -> IpAddr {
+    match (family == "ipv6", source) {
+        (false, true) => return IpAddr::V4(ANY_SOURCE_IPV4),
+        (false, false) => return IpAddr::V4(ANY_DESTINATION_IPV4),
+        (true, true) => return IpAddr::V6(ANY_SOURCE_IPV6),
+        (true, false) => return IpAddr::V6(ANY_DESTINATION_IPV6),
+    }
+}
+
+fn ip_from_value(v6: bool, value: u128) -> IpAddr {
+    if v6 {
+        return IpAddr::V6(Ipv6Addr::from(value));
+    }
+    return IpAddr::V4(Ipv4Addr::from(value as u32));
+}
+
+// First and last address of the address specs of a rule, any address when it has none.
+// Probing both ends of every range of both configurations finds ranges that grew or shrank.
+fn address_points(specs: &Vec<String>, family: &str, source: bool) -> Vec<IpAddr> {
+    if specs.len() == 0 {
+        return vec![any_address(family, source)];
+    }
+    let mut points: Vec<IpAddr> = vec![];
+    for (v6, first, last) in specs.iter().filter_map(|s| address_range(s)) {
+        for point in [ip_from_value(v6, first), ip_from_value(v6, last)] {
+            if !points.contains(&point) {
+                points.push(point);
+            }
+        }
+    }
+    return points;
+}
+
+// The values of a port or icmp type list, None stands for any other value
+fn value_points<T: std::str::FromStr>(values: &Vec<String>) -> Vec<Option<T>> {
+    if values.len() == 0 {
+        return vec![None];
+    }
+    return values.iter().map(|v| v.parse::<T>().ok()).collect();
+}
+
+// Interfaces of the packets that pass a filter chain, as system names
+fn chain_interfaces(config_items: &ConfigurationItems, chain: &String) -> Option<(String, String)> {
+    let chain = config_items.chains.get(chain)?;
+    let iif = if chain.direction != "output" {
+        get_interface(config_items, &chain.iface_in).ok()?
+    } else {
+        String::from("")
+    };
+    let oif = if chain.direction != "input" {
+        get_interface(config_items, &chain.iface_out).ok()?
+    } else {
+        String::from("")
+    };
+    return Some((iif, oif));
+}
+
+// Names of the filter chains of a configuration in the order of the filter tables
+fn filter_chains(config_items: &ConfigurationItems) -> Vec<String> {
+    return config_items
+        .filters
+        .filtertables
+        .iter()
+        .filter(|f| !f.deleted)
+        .map(|f| f.chain.clone())
+        .collect();
+}
+
+// Every rule line of a configuration that can decide about packets in a chain: its
+// own filter rules and the nat rules, which also insert accept rules
+fn chain_matches(config_items: &ConfigurationItems, chain: &String) -> Vec<RuleMatch> {
+    let mut matches: Vec<RuleMatch> = vec![];
+    for filtertable in config_items.filters.filtertables.iter() {
+        if filtertable.deleted || filtertable.chain != *chain {
+            continue;
+        }
+        for rule in filtertable.rules.iter() {
+            matches.extend(expand_filter_rule(config_items, rule));
+        }
+    }
+    for nat in [&config_items.dnat, &config_items.snat] {
+        for nattable in nat.nattables.iter().filter(|n| !n.deleted) {
+            for rule in nattable.rules.iter() {
+                matches.extend(
+                    expand_nat_rule(config_items, rule)
+                        .into_iter()
+                        .map(|n| n.rule),
+                );
+            }
+        }
+    }
+    return matches;
+}
+
+fn probes_for_match(iif: &String, oif: &String, m: &RuleMatch) -> Vec<Packet> {
+    let mut packets: Vec<Packet> = vec![];
+    let icmp = m.protocol == "icmp" || m.protocol == "icmpv6";
+    let icmptypes = if icmp {
+        value_points::<u8>(&m.icmptypes)
+    } else {
+        vec![None]
+    };
+    for saddr in address_points(&m.saddr, &m.family, true) {
+        for daddr in address_points(&m.daddr, &m.family, false) {
+            for sport in value_points::<u16>(&m.sport) {
+                for dport in value_points::<u16>(&m.dport) {
+                    for icmptype in icmptypes.iter() {
+                        packets.push(Packet {
+                            iif: iif.clone(),
+                            oif: oif.clone(),
+                            saddr: saddr.to_string(),
+                            daddr: daddr.to_string(),
+                            protocol: m.protocol.clone(),
+                            sport: sport,
+                            dport: dport,
+                            icmptype: *icmptype,
+                            ct_state: String::from("new"),
+                        });
+                    }
+                }
+            }
+        }
+    }
+    return packets;
+}
+
+// Packets that together exercise every rule line of both configurations in every chain,
+// plus packets that match no rule and get the chain policy
+fn probes(installed: &ConfigurationItems, proposed: &ConfigurationItems) -> Vec<Packet> {
+    let mut chains = filter_chains(proposed);
+    for chain in filter_chains(installed) {
+        if !chains.contains(&chain) {
+            chains.push(chain);
+        }
+    }
+    let mut seen: HashSet<String> = HashSet::new();
+    let mut packets: Vec<Packet> = vec![];
+    for chain in chains.iter() {
+        let Some((iif, oif)) =
+            chain_interfaces(proposed, chain).or_else(|| chain_interfaces(installed, chain))
+        else {
+            continue;
+        };
+        let mut matches = chain_matches(installed, chain);
+        matches.extend(chain_matches(proposed, chain));
+        for (family, icmp) in [("ipv4", "icmp"), ("ipv6", "icmpv6")] {
+            for protocol in ["tcp", "udp", icmp] {
+                matches.push(RuleMatch {
+                    family: String::from(family),
+                    protocol: String::from(protocol),
+                    ..Default::default()
+                });
+            }
+        }
+        for m in matches.iter() {
+            for packet in probes_for_match(&iif, &oif, m) {
+                let key = format!(
+                    "{}|{}|{}|{}|{}|{:?}|{:?}|{:?}",
+                    packet.iif,
+                    packet.oif,
+                    packet.protocol,
+                    packet.saddr,
+                    packet.daddr,
+                    packet.sport,
+                    packet.dport,
+                    packet.icmptype
+                );
+                if seen.insert(key) {
+                    packets.push(packet);
+                }
+            }
+        }
+    }
+    return packets;
+}
+
+// Host, else the smallest network, an address belongs to
+fn object_name(config_items: &ConfigurationItems, addr: &str) -> String {
+    let Ok(ip) = addr.parse::<IpAddr>() else {
+        return String::from("");
+    };
+    let mut hosts: Vec<&String> = config_items
+        .hosts
+        .iter()
+        .filter(|(_name, host)| {
+            host.ipv4
+                .iter()
+                .chain(host.ipv6.iter())
+                .any(|a| a.parse::<IpAddr>() == Ok(ip))
+        })
+        .map(|(name, _host)| name)
+        .collect();
+    hosts.sort();
+    if let Some(host) = hosts.first() {
+        return (*host).clone();
+    }
+    let mut best: Option<(u128, &String)> = None;
+    for (name, network) in config_items
+        .ipv4networks
+        .iter()
+        .chain(config_items.ipv6networks.iter())
+    {
+        let Some((v6, first, last)) = address_range(network) else {
+            continue;
+        };
+        if ip_from_value(v6, first).is_ipv6() != ip.is_ipv6() {
+            continue;
+        }
+        let value = match ip {
+            IpAddr::V4(a) => u32::from(a) as u128,
+            IpAddr::V6(a) => u128::from(a),
+        };
+        if value < first || value > last {
+            continue;
+        }
+        let size = last - first;
+        if best
+            .map(|(s, n)| size < s || (size == s && name < n))
+            .unwrap_or(true)
+        {
+            best = Some((size, name));
+        }
+    }
+    return best.map(|(_s, n)| n.clone()).unwrap_or_default();
+}
+
+fn service_name(config_items: &ConfigurationItems, protocol: &str, port: Option<u16>) -> String {
+    let Some(port) = port else {
+        return String::from("");
+    };
+    let mut names: Vec<&String> = config_items
+        .services
+        .iter()
+        .filter(|(_name, s)| s.port == port && s.protocol.contains(&protocol.to_uppercase()))
+        .map(|(name, _s)| name)
+        .collect();
+    names.sort();
+    return names.first().map(|n| (*n).clone()).unwrap_or_default();
+}
+
+// First name found in the proposed and then the installed configuration
+fn name_in(
+    installed: &ConfigurationItems,
+    proposed: &ConfigurationItems,
+    find: impl Fn(&ConfigurationItems) -> String,
+) -> String {
+    let name = find(proposed);
+    if name.len() > 0 {
+        return name;
+    }
+    return find(installed);
+}
+
+// Translation of a nat match as address:port, where either can be missing
+fn translation(nat: &NatMatch) -> String {
+    return format!(
+        "{}{}",
+        nat.address.clone().unwrap_or_default(),
+        nat.port
+            .as_ref()
+            .map(|p| format!(":{}", p))
+            .unwrap_or_default()
+    );
+}
+
+// The first translation of every nat match of a configuration
+fn nat_translations(config_items: &ConfigurationItems) -> Vec<(String, String, RuleMatch, String)> {
+    let mut result: Vec<(String, String, RuleMatch, String)> = vec![];
+    for (nat, kind) in [(&config_items.dnat, "dnat"), (&config_items.snat, "snat")] {
+        for nattable in nat.nattables.iter().filter(|n| !n.deleted) {
+            for rule in nattable.rules.iter() {
+                for m in expand_nat_rule(config_items, rule) {
+                    let exists = result
+                        .iter()
+                        .any(|(c, k, r, _t)| *c == nattable.chain && k == kind && *r == m.rule);
+                    if !exists {
+                        result.push((
+                            nattable.chain.clone(),
+                            String::from(kind),
+                            m.rule.clone(),
+                            translation(&m),
+                        ));
+                    }
+                }
+            }
+        }
+    }
+    return result;
+}
+
+fn nat_changes(installed: &ConfigurationItems, proposed: &ConfigurationItems) -> Vec<NatChange> {
+    let old = nat_translations(installed);
+    let new = nat_translations(proposed);
+    let find = |list: &Vec<(String, String, RuleMatch, String)>,
+                chain: &String,
+                kind: &String,
+                rule: &RuleMatch| {
+        return list
+            .iter()
+            .find(|(c, k, r, _t)| c == chain && k == kind && r == rule)
+            .map(|(_c, _k, _r, t)| t.clone());
+    };
+    let mut changes: Vec<NatChange> = vec![];
+    for (chain, kind, rule, proposed_translation) in new.iter() {
+        let installed_translation = find(&old, chain, kind, rule);
+        if installed_translation.as_ref() != Some(proposed_translation) {
+            changes.push(NatChange {
+                chain: chain.clone(),
+                kind: kind.clone(),
+                rule: rule.clone(),
+                installed: installed_translation,
+                proposed: Some(proposed_translation.clone()),
+            });
+        }
+    }
+    for (chain, kind, rule, installed_translation) in old.iter() {
+        if find(&new, chain, kind, rule).is_none() {
+            changes.push(NatChange {
+                chain: chain.clone(),
+                kind: kind.clone(),
+                rule: rule.clone(),
+                installed: Some(installed_translation.clone()),
+                proposed: None,
+            });
+        }
+    }
+    return changes;
+}
+
+fn default_state(config_items: &ConfigurationItems, name: &str) -> String {
+    if config_items.inactive_defaults.contains(&String::from(name)) {
+        return String::from("inactive");
+    }
+    return String::from("active");
+}
+
+// State of a default in a chain, SRCEQDST is never applied on the loopback interface
+fn chain_default_state(config_items: &ConfigurationItems, chain: &String, name: &str) -> String {
+    let loopback = config_items
+        .chains
+        .get(chain)
+        .map(|c| chain_on_loopback(config_items, c))
+        .unwrap_or(false);
+    if name == "SRCEQDST" && loopback {
+        return String::from("inactive");
+    }
+    return default_state(config_items, name);
+}
+
+fn chain_policy(config_items: &ConfigurationItems, chain: &String) -> String {
+    return config_items
+        .filters
+        .filtertables
+        .iter()
+        .find(|f| !f.deleted && f.chain == *chain)
+        .map(|f| f.policy.clone())
+        .unwrap_or(String::from("none"));
+}
+
+fn default_changes(
+    installed: &ConfigurationItems,
+    proposed: &ConfigurationItems,
+) -> Vec<DefaultChange> {
+    let mut changes: Vec<DefaultChange> = vec![];
+    let mut push = |chain: &String, name: &str, old: String, new: String| {
+        if old != new {
+            changes.push(DefaultChange {
+                chain: chain.clone(),
+                name: String::from(name),
+                installed: old,
+                proposed: new,
+            });
+        }
+    };
+    for name in GLOBAL_DEFAULTS {
+        push(
+            &String::from(""),
+            name,
+            default_state(installed, name),
+            default_state(proposed, name),
+        );
+    }
+    let old_chains = filter_chains(installed);
+    let new_chains = filter_chains(proposed);
+    let mut chains = new_chains.clone();
+    chains.extend(
+        old_chains
+            .iter()
+            .filter(|c| !new_chains.contains(c))
+            .cloned(),
+    );
+    for chain in chains.iter() {
+        push(
+            chain,
+            "policy",
+            chain_policy(installed, chain),
+            chain_policy(proposed, chain),
+        );
+        // defaults of new and removed chains come and go with the chain
+        if !old_chains.contains(chain) || !new_chains.contains(chain) {
+            continue;
+        }
+        for name in CHAIN_DEFAULTS {
+            push(
+                chain,
+                name,
+                chain_default_state(installed, chain, name),
+                chain_default_state(proposed, chain, name),
+            );
+        }
+    }
+    return changes;
+}
+
+fn decision(result: &SimulationResult) -> String {
+    match result.rule {
+        Some(r) => return format!("{} by {} rule {}", result.verdict, result.chain, r + 1),
+        None => return format!("{} by {} ({})", result.verdict, result.chain, result.reason),
+    }
+}
+
+fn named(value: &str, name: &String) -> String {
+    if name.len() == 0 {
+        return String::from(value);
+    }
+    return format!("{} ({})", value, name);
+}
+
+fn flow_line(change: &FlowChange) -> String {
+    let packet = &change.packet;
+    let port = match (packet.dport, packet.icmptype) {
+        (Some(p), _) => named(&format!(":{}", p), &change.service),
+        (None, Some(t)) => format!(" type {}", t),
+        (None, None) => String::from(""),
+    };
+    return format!(
+        "{} {} -> {}{}: {}, was {}",
+        packet.protocol,
+        named(&packet.saddr, &change.source),
+        named(&packet.daddr, &change.destination),
+        port,
+        decision(&change.proposed),
+        decision(&change.installed)
+    );
+}
+
+fn addresses_text(addresses: &Vec<String>) -> String {
+    if addresses.len() == 0 {
+        return String::from("any");
+    }
+    return addresses.join(",");
+}
+
+fn nat_line(change: &NatChange) -> String {
+    let ports = |ports: &Vec<String>| {
+        if ports.len() == 0 {
+            return String::from("");
+        }
+        return format!(":{}", ports.join(","));
+    };
+    let translated = |t: &Option<String>| t.clone().unwrap_or(String::from("none"));
+    return format!(
+        "{} {} {} {}{} -> {}{}: {}, was {}",
+        change.kind,
+        change.chain,
+        change.rule.protocol,
+        addresses_text(&change.rule.saddr),
+        ports(&change.rule.sport),
+        addresses_text(&change.rule.daddr),
+        ports(&change.rule.dport),
+        translated(&change.proposed),
+        translated(&change.installed)
+    );
+}
+
+fn default_line(change: &DefaultChange) -> String {
+    if change.chain.len() == 0 {
+        return format!(
+            "{}: {}, was {}",
+            change.name, change.proposed, change.installed
+        );
+    }
+    return format!(
+        "chain {} {}: {}, was {}",
+        change.chain, change.name, change.proposed, change.installed
+    );
+}
+
+// Plain text of an impact analysis, one section per kind of change
+pub fn ticket(impact: &Impact) -> String {
+    let mut lines: Vec<String> = vec![];
+    let sections = [
+        (
+            "Newly allowed flows",
+            impact
+                .newly_allowed
+                .iter()
+                .map(flow_line)
+                .collect::<Vec<String>>(),
+        ),
+        (
+            "Newly blocked flows",
+            impact.newly_blocked.iter().map(flow_line).collect(),
+        ),
+        (
+            "Changed NAT translations",
+            impact.nat.iter().map(nat_line).collect(),
+        ),
+        (
+            "Changed defaults",
+            impact.defaults.iter().map(default_line).collect(),
+        ),
+        ("Not evaluated", impact.errors.clone()),
+    ];
+    for (title, entries) in sections {
+        if entries.len() == 0 {
+            continue;
+        }
+        if lines.len() > 0 {
+            lines.push(String::from(""));
+        }
+        lines.push(format!("{} ({}):", title, entries.len()));
+        lines.extend(entries.iter().map(|e| format!("  {}", e)));
+    }
+    if lines.len() == 0 {
+        lines.push(String::from(
+            "No changes in allowed flows, NAT translations or defaults",
+        ));
+    }
+    return lines.join("\n");
+}
+
+// Compare what the installed and the proposed configuration do with the same packets,
+// the management rule is only compared with the port of the webserver
+pub fn compare(
+    installed: &ConfigurationItems,
+    proposed: &ConfigurationItems,
+    management_port: Option<u16>,
+) -> Impact {
+    let mut impact = Impact::default();
+    for packet in probes(installed, proposed) {
+        let (old, new) = match (
+            simulate(installed, &packet, management_port),
+            simulate(proposed, &packet, management_port),
+        ) {
+            (Ok(old), Ok(new)) => (old, new),
+            (Err(e), _) | (_, Err(e)) => {
+                if !impact.errors.contains(&e) {
+                    impact.errors.push(e);
+                }
+                continue;
+            }
+        };
+        let allowed = new.verdict == "accept";
+        if (old.verdict == "accept") == allowed {
+            continue;
+        }
+        let change = FlowChange {
+            source: name_in(installed, proposed, |c| object_name(c, &packet.saddr)),
+            destination: name_in(installed, proposed, |c| object_name(c, &packet.daddr)),
+            service: name_in(installed, proposed, |c| {
+                service_name(c, &packet.protocol, packet.dport)
+            }),
+            packet: packet,
+            installed: old,
+            proposed: new,
+        };
+        if allowed {
+            impact.newly_allowed.push(change);
+        } else {
+            impact.newly_blocked.push(change);
+        }
+    }
+    impact.nat = nat_changes(installed, proposed);
+    impact.defaults = default_changes(installed, proposed);
+    impact.ticket = ticket(&impact);
+    return impact;
+}
+
+fn installed_dir(savepath: &str) -> PathBuf {
+    let mut path = PathBuf::from(savepath);
+    path.push(INSTALLED_DIR);
+    return path;
+}
+
+// Keep the configuration of an installed script, older ones beyond the retention are removed
+pub async fn record_installed(savepath: &str, script: &str, json: &str) -> Result<(), String> {
+    let dir = installed_dir(savepath);
+    tokio::fs::create_dir_all(&dir)
+        .await
+        .map_err(|e| format!("Could not create {}: {}", dir.display(), e))?;
+    let path = dir.join(format!(
+        "{}.json",
+        hex::encode(openssl::sha::sha256(script.as_bytes()))
+    ));
+    tokio::fs::write(&path, json)
+        .await
+        .map_err(|e| format!("Could not write {}: {}", path.display(), e))?;
+
+    let mut files: Vec<(std::time::SystemTime, PathBuf)> = vec![];
+    if let Ok(entries) = std::fs::read_dir(&dir) {
+        for entry in entries.flatten() {
+            if let Ok(modified) = entry.metadata().and_then(|m| m.modified()) {
+                files.push((modified, entry.path()));
+            }
+        }
+    }
+    files.sort();
+    while files.len() > INSTALLED_RETENTION {
+        let (_modified, old) = files.remove(0);
+        let _ = tokio::fs::remove_file(old).await;
+    }
+    return Ok(());
+}
+
+// The configuration the installed conf file was generated from
+pub async fn installed_configuration(
+    state: &Arc<AppState>,
+    savepath: &str,
+) -> Result<ConfigurationItems, String> {
+    let sha256 = match helper_request(state, Request::Status).await {
+        Ok(Response::Status { status }) => status.conf_sha256,
+        Ok(r) => return Err(r.to_string()),
+        Err(e) => return Err(format!("Communication with main process failed: {}", e)),
+    };
+    let Some(sha256) = sha256 else {
+        return Err(String::from("No ruleset is installed"));
+    };
+    return recorded_configuration(savepath, &sha256).await;
+}
+
+// The configuration of an installed script, by the sha256 of the script
+pub async fn recorded_configuration(
+    savepath: &str,
+    sha256: &str,
+) -> Result<ConfigurationItems, String> {
+    let path = installed_dir(savepath).join(format!("{}.json", sha256));
+    let json = tokio::fs::read_to_string(&path).await.map_err(|_e| {
+        String::from(
+            "The installed ruleset was not installed from a configuration of this webserver",
+        )
+    })?;
+    return serde_json::from_str(&json)
+        .map_err(|e| format!("Invalid installed configuration: {}", e));
+}
+
+// Changes the configuration makes compared to the installed one, without installing it
+pub async fn impact_configuration(
+    session: Session,
+    State(state): State<Arc<AppState>>,
+    extract::Json(payload): extract::Json<Configuration>,
+) -> Result<Json<Impact>, (StatusCode, String)> {
+    if !check_session(session, &state).await {
+        return Err((StatusCode::UNAUTHORIZED, String::from("Not logged in")));
+    }
+    let proposed: ConfigurationItems = serde_json::from_str(&payload.json).map_err(|e| {
+        (
+            StatusCode::BAD_REQUEST,
+            format!("Invalid configuration: {}", e),
+        )
+    })?;
+    let savepath = state.settings.lock().await.paths.savepath.clone();
+    let installed = installed_configuration(&state, &savepath)
+        .await
+        .map_err(|e| (StatusCode::NOT_FOUND, e))?;
+    let port = state.settings.lock().await.connection.port;
+    return Ok(Json(compare(&installed, &proposed, Some(port))));
+}
+
+// Command line interface:
+// webserver impact <installed configuration file> <proposed configuration file> [management port]
+pub fn cli(args: &[String]) -> i32 {
+    if args.len() != 2 && args.len() != 3 {
+        eprintln!(
+            "Usage: webserver impact <installed configuration file> <proposed configuration file> [management port]"
+        );
+        return 2;
+    }
+    let management_port = match args.get(2).map(|p| p.parse::<u16>()) {
+        Some(Ok(port)) => Some(port),
+        Some(Err(_e)) => {
+            eprintln!("Invalid management port {}", args[2]);
+            return 2;
+        }
+        None => None,
+    };
+    let mut configs: Vec<ConfigurationItems> = vec![];
+    for file in args[..2].iter() {
+        let json = match std::fs::read_to_string(file) {
+            Ok(j) => j,
+            Err(e) => {
+                eprintln!("Could not read configuration file {}: {}", file, e);
+                return 2;
+            }
+        };
+        match serde_json::from_str(&json) {
+            Ok(c) => configs.push(c),
+            Err(e) => {
+                eprintln!("Invalid configuration {}: {}", file, e);
+                return 2;
+            }
+        }
+    }
+    let impact = compare(&configs[0], &configs[1], management_port);
+    println!("{}", impact.ticket);
+    return 0;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdata;
+
+    #[test]
+    fn ticket_for_growing_range() {
+        let installed = testdata::configuration();
+        let mut proposed = testdata::configuration();
+        proposed
+            .ipv4networks
+            .insert(String::from("office"), String::from("10.0.0.0/23"));
+        let impact = compare(&installed, &proposed, None);
+        assert_eq!(impact.newly_allowed.len(), 1);
+        assert_eq!(impact.newly_blocked.len(), 0);
+        assert_eq!(
+            impact.ticket,
+            "Newly allowed flows (1):\n  tcp 10.0.1.255 (office) -> 192.0.2.10 (web):443 (https): accept by lan_dmz rule 1, was drop by lan_dmz (policy)"
+        );
+
+        // the other way round the same flow is blocked
+        let impact = compare(&proposed, &installed, None);
+        assert_eq!(impact.newly_allowed.len(), 0);
+        assert_eq!(impact.newly_blocked.len(), 1);
+        assert!(impact.ticket.starts_with("Newly blocked flows (1):"));
+    }
+
+    #[test]
+    fn ticket_without_changes() {
+        let config_items = testdata::configuration();
+        let impact = compare(&config_items, &config_items, None);
+        assert_eq!(
+            impact.ticket,
+            "No changes in allowed flows, NAT translations or defaults"
+        );
+    }
+}
@@ -0,0 +1,156 @@
+use crate::conntrack::{interface_for, local_networks};
+use crate::simulator::{simulate, Packet};
+use crate::ConfigurationItems;
+use axum_server::accept::Accept;
+use std::future::{ready, Ready};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use tokio::net::TcpStream;
+use tower_http::add_extension::AddExtension;
+
+// Local address of the connection a request came in on, added to the request extensions
+#[derive(Clone, Copy)]
+pub struct LocalAddr(pub SocketAddr);
+
+// Acceptor that records the local address of every accepted connection
+#[derive(Clone, Copy)]
+pub struct LocalAddrAcceptor;
+
+impl<S> Accept<TcpStream, S> for LocalAddrAcceptor {
+    type Stream = TcpStream;
+    type Service = AddExtension<S, LocalAddr>;
+    type Future = Ready<io::Result<(Self::Stream, Self::Service)>>;
+
+    fn accept(&self, stream: TcpStream, service: S) -> Self::Future {
+        let result = stream
+            .local_addr()
+            .map(|addr| AddExtension::new(service, LocalAddr(addr)));
+        return ready(result.map(|service| (stream, service)));
+    }
+}
+
+// Interface of the default route, for clients that are not on a directly connected network
+fn default_interface(ipv6: bool) -> Option<String> {
+    if ipv6 {
+        // destination, prefix length, ..., interface name as last field
+        let routes = std::fs::read_to_string("/proc/net/ipv6_route").ok()?;
+        return routes.lines().find_map(|l| {
+            let fields: Vec<&str> = l.split_whitespace().collect();
+            match fields.len() == 10 && fields[0].chars().all(|c| c == '0') && fields[1] == "00" {
+                true => Some(String::from(fields[9])),
+                false => None,
+            }
+        });
+    }
+    // interface, destination, gateway, ... with a header line
+    let routes = std::fs::read_to_string("/proc/net/route").ok()?;
+    return routes.lines().skip(1).find_map(|l| {
+        let fields: Vec<&str> = l.split_whitespace().collect();
+        match fields.len() > 1 && fields[1] == "00000000" {
+            true => Some(String::from(fields[0])),
+            false => None,
+        }
+    });
+}
+
+// Check that the admin's client can still open a connection to the webserver
+// after the configuration is installed. server is the local address of the client's
+// connection. Returns an error when the client would be locked out and a warning when
+// this cannot be determined.
+pub fn check(
+    config_items: &ConfigurationItems,
+    client: IpAddr,
+    server: IpAddr,
+    port: u16,
+) -> Result<Option<String>, String> {
+    if config_items.management_rule {
+        return Ok(None);
+    }
+    let client = client.to_canonical();
+    if client.is_loopback() {
+        return Ok(None);
+    }
+    let local = local_networks();
+    let iif = interface_for(&local, &client.to_string()).or(default_interface(client.is_ipv6()));
+    let Some(iif) = iif else {
+        return Ok(Some(format!(
+            "Could not determine the interface that connects client {} to the webserver, access after the install is not checked",
+            client
+        )));
+    };
+    return check_on(config_items, client, server, port, &iif);
+}
+
+// The lockout check for a client that reaches the webserver through interface iif
+fn check_on(
+    config_items: &ConfigurationItems,
+    client: IpAddr,
+    server: IpAddr,
+    port: u16,
+    iif: &str,
+) -> Result<Option<String>, String> {
+    let server = server.to_canonical();
+    let packet = Packet {
+        iif: String::from(iif),
+        saddr: client.to_string(),
+        daddr: server.to_string(),
+        protocol: String::from("tcp"),
+        dport: Some(port),
+        ct_state: String::from("new"),
+        ..Default::default()
+    };
+    match simulate(config_items, &packet, Some(port)) {
+        Ok(result) if result.verdict == "accept" => return Ok(None),
+        Ok(result) => {
+            return Err(format!(
+                "The new configuration blocks the connection from {} to port {} of the webserver on {}: {}",
+                client, port, iif, result.reason
+            ))
+        }
+        Err(e) => return Ok(Some(format!("Could not check access to the webserver: {}", e))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdata;
+
+    fn ip(s: &str) -> IpAddr {
+        return s.parse().unwrap();
+    }
+
+    #[test]
+    fn dropped_client_is_refused() {
+        let mut config_items = testdata::configuration();
+        assert_eq!(
+            check_on(&config_items, ip("10.0.0.2"), ip("10.0.0.1"), 443, "eth0"),
+            Ok(None)
+        );
+        testdata::drop_input(&mut config_items, "lan_in", "lan");
+        let result = check_on(&config_items, ip("10.0.0.2"), ip("10.0.0.1"), 443, "eth0");
+        assert!(result
+            .unwrap_err()
+            .contains("blocks the connection from 10.0.0.2"));
+        // the client on loopback is never locked out
+        assert_eq!(
+            check(&config_items, ip("127.0.0.1"), ip("127.0.0.1"), 443),
+            Ok(None)
+        );
+    }
+
+    #[test]
+    fn management_rule_is_accepted() {
+        let mut config_items = testdata::configuration();
+        testdata::drop_input(&mut config_items, "lan_in", "lan");
+        config_items.management_rule = true;
+        assert_eq!(
+            check_on(&config_items, ip("10.0.0.2"), ip("10.0.0.1"), 443, "eth0"),
+            Ok(None)
+        );
+        assert_eq!(
+            check(&config_items, ip("10.0.0.2"), ip("10.0.0.1"), 443),
+            Ok(None)
+        );
+    }
+}
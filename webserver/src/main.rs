@@ -2,17 +2,19 @@ use argon2::{
     password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
     Argon2,
 };
-use axum::extract::{self, Path};
-use axum::http::StatusCode;
-use axum::Json;
+use axum::extract::{self, ConnectInfo, Path};
+use axum::http::{header, StatusCode};
+use axum::response::IntoResponse;
+use axum::{Extension, Json};
 use axum::{extract::State, routing::post};
 use axum::{routing::get, Router};
 use axum_server::tls_rustls::RustlsConfig;
 use network_interface::NetworkInterface;
 use network_interface::NetworkInterfaceConfig;
-use openssl::symm::{encrypt, Cipher};
+use protocol::{Channel, Request, Response, CHECK_FILE, REQUEST, RESPONSE, SOCKET_PATH};
 use serde::{Deserialize, Serialize};
 use settings::{get_settings, Settings};
+use sourcemap::{Origin, Script};
 use std::cmp;
 use std::collections::HashMap;
 use std::path::PathBuf;
@@ -24,13 +26,35 @@ use thotp::verify_totp;
 use thotp::{encoding::encode, generate_secret, qr::generate_code_svg, qr::EcLevel};
 use time::Duration;
 use tokio::fs;
-use tokio::io::{self, AsyncBufReadExt, AsyncWriteExt, BufReader};
-use tokio::net::lookup_host;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{lookup_host, UnixStream};
+use tokio::signal::unix::{signal, SignalKind};
 use tokio::sync::Mutex;
 use tower_http::services::{ServeDir, ServeFile};
 use tower_sessions::{session::Id, Expiry, MemoryStore, Session, SessionManagerLayer};
 use walkdir::WalkDir;
 
+mod analysis;
+mod configdiff;
+mod configrepo;
+mod conntrack;
+mod drift;
+mod flowtests;
+mod history;
+mod impact;
+mod lockout;
+mod preview;
+mod rules;
+mod ruleset;
+mod sandbox;
+mod simulator;
+mod sourcemap;
+mod status;
+#[cfg(test)]
+mod testdata;
+mod trace;
+mod validate;
+
 #[derive(Deserialize, Serialize)]
 struct TotpSecret {
     secret: String,
@@ -53,11 +77,18 @@ struct LoginData {
 struct AppState {
     current_session: Mutex<Option<Id>>,
     no_session: Mutex<Option<Id>>,
-    reader: Mutex<BufReader<io::Stdin>>,
-    cipher: Mutex<Cipher>,
-    key: Mutex<Vec<u8>>,
-    iv: Mutex<Vec<u8>>,
+    // connection to the main process
+    helper: Mutex<BufReader<UnixStream>>,
+    // encrypted channel to the main process
+    channel: Mutex<Channel>,
     settings: Mutex<Settings>,
+    drift: Mutex<drift::DriftState>,
+    // unix time at which an unconfirmed install is rolled back
+    confirm_deadline: Mutex<Option<i64>>,
+    // install that waits for its confirmation before it is recorded
+    pending_install: Mutex<Option<PendingInstall>>,
+    // name of the logged in user
+    current_user: Mutex<String>,
 }
 
 #[derive(Default, Deserialize, Serialize)]
@@ -77,6 +108,26 @@ struct Addrs {
 struct Configuration {
     name: String,
     json: String,
+    #[serde(default)]
+    flush_conntrack: bool,
+    // roll the install back unless it is confirmed within this many seconds, 0 installs directly
+    #[serde(default)]
+    confirm_seconds: u64,
+    // install even when the current client would lose access to the webserver
+    #[serde(default)]
+    allow_lockout: bool,
+    // commit message of a save
+    #[serde(default)]
+    message: String,
+}
+
+// An installed configuration, kept until the install is confirmed
+struct PendingInstall {
+    name: String,
+    json: String,
+    script: String,
+    flush_conntrack: bool,
+    user: String,
 }
 
 #[derive(Default, Deserialize, Serialize)]
@@ -119,6 +170,9 @@ struct FilterRuleData {
     action: String,
     comment: String,
     active: bool,
+    // stable id of the rule within its chain, assigned when it is missing
+    #[serde(default)]
+    id: String,
 }
 
 #[derive(Default, Deserialize, Serialize, Clone)]
@@ -151,6 +205,9 @@ struct NatRuleData {
     translatedservice: String,
     comment: String,
     active: bool,
+    // stable id of the rule within its chain, assigned when it is missing
+    #[serde(default)]
+    id: String,
 }
 
 #[derive(Default, Deserialize, Serialize, Clone)]
@@ -184,6 +241,15 @@ struct ConfigurationItems {
     dnat: NatData,
     logging: String,
     checksdragpos: PosData,
+    // only replace the builder's own tables and leave other tables intact
+    #[serde(default)]
+    scoped_tables: bool,
+    // always accept connections to the webserver port, ahead of the user rules
+    #[serde(default)]
+    management_rule: bool,
+    // flows the rules must allow or block, an install is refused when one fails
+    #[serde(default)]
+    tests: Vec<flowtests::FlowTest>,
 }
 
 fn tcp_ports_from_services(
@@ -339,33 +405,46 @@ fn chain_on_loopback(config_items: &ConfigurationItems, chain: &ChainData) -> bo
 }
 
 fn add_natrule_to_filter(
-    script: Vec<String>,
+    mut script: Script,
     chain: &String,
     line: &String,
     limit: &String,
-) -> Vec<String> {
-    let mut newscript = Vec::new();
-    for l in script {
-        newscript.push(l.clone());
-        if l.trim() == format!("chain {} {{", chain) {
-            newscript.push(format!("{}{} accept # allow nat rule", line, limit));
+) -> Script {
+    // the inserted line keeps the origin of the nat rule being generated
+    let origin = script.origin.clone();
+    let mut index = 0;
+    while index < script.lines.len() {
+        if script.lines[index].trim() == format!("chain {} {{", chain) {
+            index += 1;
+            script.insert(
+                index,
+                format!("{}{} accept # allow nat rule", line, limit),
+                origin.clone(),
+            );
         }
+        index += 1;
     }
-    newscript
+    script
 }
 
-fn addcounters(script: Vec<String>, counters: Vec<String>) -> Vec<String> {
-    let mut newscript = Vec::new();
-    for l in script {
-        newscript.push(l.clone());
-        if l.trim() == "# Counters" {
+fn addcounters(mut script: Script, counters: Vec<String>) -> Script {
+    let mut index = 0;
+    while index < script.lines.len() {
+        if script.lines[index].trim() == "# Counters" {
             for counter in counters.iter() {
-                newscript.push(format!("  counter {} {{", counter));
-                newscript.push(format!("  }}"));
+                index += 1;
+                script.insert(
+                    index,
+                    format!("  counter {} {{", counter),
+                    Origin::Generated,
+                );
+                index += 1;
+                script.insert(index, format!("  }}"), Origin::Generated);
             }
         }
+        index += 1;
     }
-    newscript
+    script
 }
 
 fn logcommand(counters: &mut Vec<String>, logging: &String, chain: &String, name: &str) -> String {
@@ -392,9 +471,38 @@ fn netdev_logcommand(logging: &String) -> String {
     return String::from("");
 }
 
-fn generate_script(json: String, nft: String) -> Result<Vec<String>, String> {
-    let config_items: ConfigurationItems = serde_json::from_str(&json).unwrap();
-    let mut script = Vec::new();
+// Tables created by the generated script
+pub const OWN_TABLES: [(&str, &str); 4] = [
+    ("inet", "filter_inet"),
+    ("inet", "snat_inet"),
+    ("inet", "dnat_inet"),
+    ("netdev", "filter_netdev"),
+];
+
+fn default_origin(name: &str, chain: Option<&String>) -> Origin {
+    return Origin::Default {
+        name: String::from(name),
+        chain: chain.cloned(),
+    };
+}
+
+fn generate_script(json: String, nft: String, management_port: u16) -> Result<Script, String> {
+    let mut config_items: ConfigurationItems = match serde_json::from_str(&json) {
+        Ok(c) => c,
+        Err(e) => return Err(format!("Invalid configuration: {}", e)),
+    };
+    for filtertable in config_items.filters.filtertables.iter_mut() {
+        sourcemap::assign_rule_ids(filtertable.rules.iter_mut().map(|r| &mut r.id).collect());
+    }
+    for nattable in config_items
+        .snat
+        .nattables
+        .iter_mut()
+        .chain(config_items.dnat.nattables.iter_mut())
+    {
+        sourcemap::assign_rule_ids(nattable.rules.iter_mut().map(|r| &mut r.id).collect());
+    }
+    let mut script = Script::new();
     let mut counters = Vec::new();
     script.push(format!("#!{} -f", nft));
     script.push(String::from(""));
@@ -402,13 +510,25 @@ fn generate_script(json: String, nft: String) -> Result<Vec<String>, String> {
     script.push(String::from("# Script generated by Nftables Builder"));
     script.push(String::from("#"));
     script.push(String::from(""));
-    script.push(String::from("# clear existing ruleset"));
-    script.push(String::from("flush ruleset"));
+    if config_items.scoped_tables {
+        // adding a table before deleting it makes the delete succeed when it does not exist
+        script.push(String::from(
+            "# replace the tables of Nftables Builder only",
+        ));
+        for (family, table) in OWN_TABLES {
+            script.push(format!("add table {} {}", family, table));
+            script.push(format!("delete table {} {}", family, table));
+        }
+    } else {
+        script.push(String::from("# clear existing ruleset"));
+        script.push(String::from("flush ruleset"));
+    }
     script.push(String::from(""));
-    config_items
-        .pre
-        .lines()
-        .for_each(|l| script.push(String::from(l)));
+    for (index, l) in config_items.pre.lines().enumerate() {
+        script.origin = Origin::Pre { line: index + 1 };
+        script.push(String::from(l));
+    }
+    script.origin = Origin::Generated;
     script.push(String::from("# Filtering rules"));
     script.push(String::from("table inet filter_inet {"));
     if config_items.logging == "counter" {
@@ -420,6 +540,7 @@ fn generate_script(json: String, nft: String) -> Result<Vec<String>, String> {
         .inactive_defaults
         .contains(&String::from("SYNFlood"))
     {
+        script.origin = default_origin("SYNFlood", None);
         script.push(format!("  set syn_rate_limit_ipv4 {{ type ipv4_addr"));
         script.push(format!("    timeout 1s"));
         script.push(format!("    flags dynamic"));
@@ -437,6 +558,7 @@ fn generate_script(json: String, nft: String) -> Result<Vec<String>, String> {
         .inactive_defaults
         .contains(&String::from("ICMP"))
     {
+        script.origin = default_origin("ICMP", None);
         script.push(format!("  set icmp_rate_limit_ipv4 {{ type ipv4_addr"));
         script.push(format!("    timeout 1s"));
         script.push(format!("    flags dynamic"));
@@ -455,6 +577,7 @@ fn generate_script(json: String, nft: String) -> Result<Vec<String>, String> {
             if filtertable.deleted {
                 continue;
             }
+            script.origin = default_origin("SRCEQDST", Some(&filtertable.chain));
             script.push(format!(
                 "  set {}_dest_ipv4 {{ type ipv4_addr; }}",
                 filtertable.chain
@@ -471,6 +594,9 @@ fn generate_script(json: String, nft: String) -> Result<Vec<String>, String> {
             continue;
         }
 
+        script.origin = Origin::Chain {
+            chain: filtertable.chain.clone(),
+        };
         script.push(format!("  chain {} {{", filtertable.chain));
         let chain = get_chain(&config_items, &filtertable.chain)?;
         if !config_items
@@ -478,6 +604,7 @@ fn generate_script(json: String, nft: String) -> Result<Vec<String>, String> {
             .contains(&String::from("SRCEQDST"))
             && !chain_on_loopback(&config_items, &chain)
         {
+            script.origin = default_origin("SRCEQDST", Some(&filtertable.chain));
             script.push(format!(
                 "    meta nfproto ipv4 update @{}_dest_ipv4 {{ ip daddr }}",
                 filtertable.chain
@@ -519,18 +646,21 @@ fn generate_script(json: String, nft: String) -> Result<Vec<String>, String> {
             .inactive_defaults
             .contains(&String::from("CT-Established"))
         {
+            script.origin = default_origin("CT-Established", Some(&filtertable.chain));
             script.push(format!("    ct state established accept"));
         }
         if !config_items
             .inactive_defaults
             .contains(&String::from("CT-Related"))
         {
+            script.origin = default_origin("CT-Related", Some(&filtertable.chain));
             script.push(format!("    ct state related accept"));
         }
         if !config_items
             .inactive_defaults
             .contains(&String::from("CT-Invalid"))
         {
+            script.origin = default_origin("CT-Invalid", Some(&filtertable.chain));
             script.push(format!(
                 "    ct state invalid {}drop",
                 logcommand(
@@ -545,6 +675,7 @@ fn generate_script(json: String, nft: String) -> Result<Vec<String>, String> {
             .inactive_defaults
             .contains(&String::from("ICMP"))
         {
+            script.origin = default_origin("ICMP", Some(&filtertable.chain));
             script.push(format!(
                 "    meta l4proto icmp update @icmp_rate_limit_ipv4 {{ ip saddr limit rate 10/second }}"
             ));
@@ -553,10 +684,14 @@ fn generate_script(json: String, nft: String) -> Result<Vec<String>, String> {
             ));
         }
         if chain.filter {
-            for rule in filtertable.rules {
+            for rule in filtertable.rules.into_iter() {
                 if !rule.active {
                     continue;
                 }
+                script.origin = Origin::FilterRule {
+                    chain: filtertable.chain.clone(),
+                    id: rule.id.clone(),
+                };
                 let mut source_ipv4s: Vec<String> = vec![];
                 let mut source_ipv6s: Vec<String> = vec![];
                 for src in rule.source.iter() {
@@ -921,6 +1056,9 @@ fn generate_script(json: String, nft: String) -> Result<Vec<String>, String> {
                 }
             }
         }
+        script.origin = Origin::Policy {
+            chain: filtertable.chain.clone(),
+        };
         if filtertable.policy == "drop" {
             script.push(format!(
                 "    {}drop ",
@@ -934,8 +1072,12 @@ fn generate_script(json: String, nft: String) -> Result<Vec<String>, String> {
         } else {
             script.push(format!("    {}", filtertable.policy));
         }
+        script.origin = Origin::Chain {
+            chain: filtertable.chain.clone(),
+        };
         script.push(String::from("  }"));
     }
+    script.origin = Origin::Generated;
     if config_items.logging == "counter" {
         script = addcounters(script, counters.clone());
     }
@@ -943,12 +1085,22 @@ fn generate_script(json: String, nft: String) -> Result<Vec<String>, String> {
     for dir in ["input", "forward", "output"] {
         script.push(format!("  chain all_{} {{", dir));
         script.push(format!("    type filter hook {} priority filter;", dir));
+        if dir == "input" && config_items.management_rule {
+            script.origin = default_origin("Management", None);
+            script.push(format!(
+                "    tcp dport {} accept # management",
+                management_port
+            ));
+        }
         let filter_slice = &config_items.filters.filtertables[..];
         for filtertable in filter_slice.iter().cloned() {
             if filtertable.deleted {
                 continue;
             }
             let chain = get_chain(&config_items, &filtertable.chain)?;
+            script.origin = Origin::Jump {
+                chain: filtertable.chain.clone(),
+            };
             if chain.direction == dir {
                 match dir {
                     "input" => {
@@ -977,6 +1129,7 @@ fn generate_script(json: String, nft: String) -> Result<Vec<String>, String> {
                 }
             }
         }
+        script.origin = Origin::Generated;
         script.push(String::from("  }"));
     }
 
@@ -1002,14 +1155,21 @@ fn generate_script(json: String, nft: String) -> Result<Vec<String>, String> {
         if chain.iface_out != "-" {
             ifspec = format!("{} oifname {} ", ifspec, chain.iface_out);
         }
+        script.origin = Origin::Chain {
+            chain: nattable.chain.clone(),
+        };
         script.push(format!("  chain {}_snat {{", nattable.chain));
         script.push(String::from(
             "    type nat hook postrouting priority srcnat",
         ));
-        for rule in nattable.rules {
+        for rule in nattable.rules.into_iter() {
             if !rule.active {
                 continue;
             }
+            script.origin = Origin::Snat {
+                chain: nattable.chain.clone(),
+                id: rule.id.clone(),
+            };
             let mut source_ipv4s: Vec<String> = vec![];
             let mut source_ipv6s: Vec<String> = vec![];
             for src in rule.source.iter() {
@@ -1290,6 +1450,7 @@ fn generate_script(json: String, nft: String) -> Result<Vec<String>, String> {
                 }
             }
         }
+        script.origin = Origin::Generated;
         script.push(String::from("  }"));
     }
 
@@ -1315,12 +1476,19 @@ fn generate_script(json: String, nft: String) -> Result<Vec<String>, String> {
         if chain.iface_out != "-" {
             ifspec = format!("{} oifname {} ", ifspec, chain.iface_out);
         }
+        script.origin = Origin::Chain {
+            chain: nattable.chain.clone(),
+        };
         script.push(format!("  chain {}_dnat {{", nattable.chain));
         script.push(String::from("    type nat hook prerouting priority dstnat"));
-        for rule in nattable.rules {
+        for rule in nattable.rules.into_iter() {
             if !rule.active {
                 continue;
             }
+            script.origin = Origin::Dnat {
+                chain: nattable.chain.clone(),
+                id: rule.id.clone(),
+            };
             let mut source_ipv4s: Vec<String> = vec![];
             let mut source_ipv6s: Vec<String> = vec![];
             for src in rule.source.iter() {
@@ -1646,6 +1814,7 @@ fn generate_script(json: String, nft: String) -> Result<Vec<String>, String> {
                 }
             }
         }
+        script.origin = Origin::Generated;
         script.push(String::from("  }"));
     }
 
@@ -1659,6 +1828,7 @@ fn generate_script(json: String, nft: String) -> Result<Vec<String>, String> {
         script.push(format!("  }}"));
     }
     config_items.interfaces.iter().for_each(|(_name, data)| {
+        script.origin = Origin::Generated;
         script.push(String::from("  chain ingress {"));
         script.push(format!(
             "    type filter hook ingress device {} priority -500;",
@@ -1668,6 +1838,7 @@ fn generate_script(json: String, nft: String) -> Result<Vec<String>, String> {
             .inactive_defaults
             .contains(&String::from("InvalidTCPFlags"))
         {
+            script.origin = default_origin("InvalidTCPFlags", None);
             script.push(String::from("    # Invalid TCP flags"));
             let invalid: [String; 10] = [
                 format!(
@@ -1719,19 +1890,22 @@ fn generate_script(json: String, nft: String) -> Result<Vec<String>, String> {
             .inactive_defaults
             .contains(&String::from("TCPMSS"))
         {
+            script.origin = default_origin("TCPMSS", None);
             script.push(String::from("    # Invalid mss"));
             script.push(String::from(format!(
                 "    tcp flags syn tcp option maxseg size 1-525 {}drop",
                 netdev_logcommand(&config_items.logging).as_str()
             )));
         }
+        script.origin = Origin::Generated;
         script.push(String::from("  }"));
     });
     script.push(String::from("}"));
-    config_items
-        .post
-        .lines()
-        .for_each(|l| script.push(String::from(l)));
+    for (index, l) in config_items.post.lines().enumerate() {
+        script.origin = Origin::Post { line: index + 1 };
+        script.push(String::from(l));
+    }
+    script.add_rule_ids();
     return Ok(script);
 }
 
@@ -1916,13 +2090,23 @@ async fn get_network_interfaces() -> Result<Json<Vec<NwInterface>>, StatusCode>
 }
 
 async fn delete_config(Path(config): Path<String>, State(state): State<Arc<AppState>>) -> String {
-    let settings = state.settings.lock().await;
-    let mut path = PathBuf::from(settings.paths.savepath.clone());
-    path.push(config);
+    // the settings are not locked while git runs
+    let savepath = state.settings.lock().await.paths.savepath.clone();
+    if let Err((_status, e)) = configrepo::check_name(&config) {
+        return e;
+    }
+    let mut path = PathBuf::from(savepath.clone());
+    path.push(config.clone());
     let result = fs::remove_file(path).await;
-    match result {
+    if let Err(_e) = result {
+        return String::from("Could not delete configurations file");
+    }
+    // the history of a deleted configuration stays in the repository
+    let user = state.current_user.lock().await.clone();
+    let message = format!("Delete {}", config);
+    match configrepo::commit_file(&savepath, &config, &user, &message).await {
         Ok(()) => return String::from("OK"),
-        Err(_e) => return String::from("Could not delete configurations file"),
+        Err(e) => return format!("Configuration deleted but not committed: {}", e),
     }
 }
 
@@ -1970,6 +2154,10 @@ async fn load_config(
     let mut conf = Configuration {
         name: String::from(""),
         json: "".to_string(),
+        flush_conntrack: false,
+        confirm_seconds: 0,
+        allow_lockout: false,
+        message: String::from(""),
     };
     let settings = state.settings.lock().await;
     let mut path = PathBuf::from(settings.paths.savepath.clone());
@@ -1991,13 +2179,27 @@ async fn save_configuration(
     State(state): State<Arc<AppState>>,
     extract::Json(payload): extract::Json<Configuration>,
 ) -> String {
-    let settings = state.settings.lock().await;
-    let mut path = PathBuf::from(settings.paths.savepath.clone());
-    path.push(payload.name);
+    // the settings are not locked while git runs
+    let savepath = state.settings.lock().await.paths.savepath.clone();
+    if let Err((_status, e)) = configrepo::check_name(&payload.name) {
+        return e;
+    }
+    let mut path = PathBuf::from(savepath.clone());
+    path.push(payload.name.clone());
     let result = fs::write(path.clone(), payload.json).await;
-    match result {
+    if let Err(_e) = result {
+        return format!("Could not write configuration file {}", path.display());
+    }
+    // every save is a commit of the logged in user
+    let user = state.current_user.lock().await.clone();
+    let message = if payload.message.len() > 0 {
+        payload.message.clone()
+    } else {
+        format!("Save {}", payload.name)
+    };
+    match configrepo::commit_file(&savepath, &payload.name, &user, &message).await {
         Ok(()) => return String::from("OK"),
-        Err(_e) => return format!("Could not write configuration file {}", path.display()),
+        Err(e) => return format!("Configuration saved but not committed: {}", e),
     }
 }
 
@@ -2007,6 +2209,7 @@ async fn logout(State(state): State<Arc<AppState>>) -> String {
         let no_id = state.no_session.lock().await;
         if let Some(_nid) = *no_id {
             *current_id = *no_id;
+            state.current_user.lock().await.clear();
             return "OK".to_string();
         }
     }
@@ -2104,6 +2307,7 @@ async fn login(
                                     session.insert("dummy", 1).await.unwrap();
                                     session.save().await.unwrap();
                                     *current_id = session.id();
+                                    *state.current_user.lock().await = payload.username.clone();
 
                                     return String::from("OK");
                                 }
@@ -2164,57 +2368,393 @@ async fn add_user(
     }
 }
 
+// Send a request to the main process and return its authenticated response. The
+// exchange runs in a task of its own that completes even when the handler waiting for it
+// is dropped, a response that is only partly read would break the channel for good.
+async fn helper_request(state: &Arc<AppState>, request: Request) -> Result<Response, String> {
+    let state = state.clone();
+    let exchange = tokio::spawn(async move {
+        return helper_exchange(&state, request).await;
+    });
+    match exchange.await {
+        Ok(result) => return result,
+        Err(e) => return Err(e.to_string()),
+    }
+}
+
+async fn helper_exchange(state: &Arc<AppState>, request: Request) -> Result<Response, String> {
+    let mut channel = state.channel.lock().await;
+    let mut helper = state.helper.lock().await;
+
+    let frame = format!("{}\n", channel.seal(REQUEST, &request)?);
+    helper
+        .get_mut()
+        .write_all(frame.as_bytes())
+        .await
+        .map_err(|e| e.to_string())?;
+    channel.mark_sent();
+
+    // read response from main process
+    let mut response = String::new();
+    match helper.read_line(&mut response).await {
+        Ok(0) => return Err(String::from("Main process closed the connection")),
+        Ok(_) => {}
+        Err(e) => return Err(e.to_string()),
+    }
+    return channel.open(RESPONSE, &response);
+}
+
+// Send a request to the main process, the response as text: "OK", an error message
+// or the output of the command
+async fn helper_command(state: &Arc<AppState>, request: Request) -> String {
+    match helper_request(state, request).await {
+        Ok(response) => return response.to_string(),
+        Err(e) => return format!("Communication with main process failed: {}", e),
+    }
+}
+
+// Send a request to the main process for a handler, unsuccessful responses become
+// an error status with the error text
+async fn helper_call(
+    state: &Arc<AppState>,
+    request: Request,
+) -> Result<Response, (StatusCode, String)> {
+    match helper_request(state, request).await {
+        Ok(response @ (Response::Error { .. } | Response::Failed { .. })) => {
+            return Err((StatusCode::BAD_REQUEST, response.to_string()))
+        }
+        Ok(response) => return Ok(response),
+        Err(e) => {
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Communication with main process failed: {}", e),
+            ))
+        }
+    }
+}
+
+// The connection is closed after the install, so that the confirmation of an install
+// with confirm_seconds has to open a new connection through the new ruleset
 async fn install_configuration(
     State(state): State<Arc<AppState>>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    Extension(local): Extension<lockout::LocalAddr>,
     extract::Json(payload): extract::Json<Configuration>,
+) -> impl IntoResponse {
+    let output = install(state, client, local.0, payload).await;
+    return ([(header::CONNECTION, "close")], output);
+}
+
+async fn install(
+    state: Arc<AppState>,
+    client: SocketAddr,
+    local: SocketAddr,
+    payload: Configuration,
 ) -> String {
     #[derive(Serialize, Deserialize)]
     struct Output {
         result: Vec<String>,
         script: Vec<String>,
+        terminated: usize,
+        validation: validate::ValidationReport,
+        diagnostics: Vec<sourcemap::Diagnostic>,
+        confirm_deadline: Option<i64>,
+        lockout: Option<String>,
+        flow_tests: Vec<flowtests::FlowTestResult>,
+        impact: Option<impact::Impact>,
+        // tag of the saved version that was installed
+        tag: Option<String>,
     }
+    let mut output: Output = Output {
+        result: vec![],
+        script: vec![],
+        terminated: 0,
+        validation: validate::ValidationReport::default(),
+        diagnostics: vec![],
+        confirm_deadline: None,
+        lockout: None,
+        flow_tests: vec![],
+        impact: None,
+        tag: None,
+    };
+
+    // refuse to install configurations that do not pass validation
+    let system = validate::system_interfaces();
+    let (config_items, report) = validate::validate_json(&payload.json, Some(&system));
+    output.validation = report;
+    let Some(config_items) = config_items.filter(|_c| output.validation.valid) else {
+        output.result = vec![String::from("Validation failed")];
+        output.result.extend(output.validation.messages());
+        return serde_json::to_string(&output).unwrap();
+    };
+
     let settings = state.settings.lock().await;
-    let script = generate_script(payload.json, settings.files.nft.clone());
 
-    let testpath = PathBuf::from(settings.files.test.clone());
-    fs::write(testpath, script.clone().unwrap().join("\n"))
-        .await
-        .unwrap();
+    // make sure the admin can still reach the webserver after the install
+    match lockout::check(
+        &config_items,
+        client.ip(),
+        local.ip(),
+        settings.connection.port,
+    ) {
+        Ok(warning) => output.lockout = warning,
+        Err(e) if payload.allow_lockout => output.lockout = Some(e),
+        Err(e) => {
+            output.result = vec![String::from("Install refused"), e.clone()];
+            output.lockout = Some(e);
+            return serde_json::to_string(&output).unwrap();
+        }
+    }
 
-    // Tell main process to test and install script
-    let cipher = state.cipher.lock().await;
-    let key = state.key.lock().await;
-    let iv = state.iv.lock().await;
-    let mut reader = state.reader.lock().await;
-    let text = String::from("install");
-    let data = text.as_bytes();
+    // the rules have to pass the flow tests stored with the configuration
+    output.flow_tests = flowtests::run(&config_items, Some(settings.connection.port));
+    let failures = flowtests::failures(&output.flow_tests);
+    if failures.len() > 0 {
+        output.result = vec![String::from("Flow tests failed")];
+        output.result.extend(failures);
+        return serde_json::to_string(&output).unwrap();
+    }
 
-    let encrypted = encrypt(*cipher, &(*key), Some(&(*iv)), data).unwrap();
+    let script = match generate_script(
+        payload.json.clone(),
+        settings.files.nft.clone(),
+        settings.connection.port,
+    ) {
+        Ok(script) => script,
+        Err(e) => {
+            output.result = vec![e];
+            return serde_json::to_string(&output).unwrap();
+        }
+    };
+    output.script = script.lines.clone();
 
-    let hex = hex::encode(encrypted);
-    let mut stdout = tokio::io::stdout();
-    let textn = format!("{}\n", hex);
-    stdout.write_all(textn.as_bytes()).await.unwrap();
-    stdout.flush().await.unwrap();
+    // what changes compared to the installed configuration, unknown when the installed
+    // ruleset did not come from this webserver
+    if let Ok(installed) =
+        impact::installed_configuration(&state, &settings.paths.savepath).await
+    {
+        output.impact = Some(impact::compare(
+            &installed,
+            &config_items,
+            Some(settings.connection.port),
+        ));
+    }
 
-    // read response from main process
-    let mut response = String::new();
-    let _fut = (*reader).read_line(&mut response).await;
-    let decoded = String::from_utf8(hex::decode(response.trim()).unwrap()).unwrap();
-    let mut resvec: Vec<String> = vec![];
+    // Tell main process to write the script to the test file, test and install it, user
+    // and configuration name are archived with the installed script
+    let user = state.current_user.lock().await.clone();
+    let name = payload.name.clone();
+    let request = if payload.confirm_seconds > 0 {
+        Request::InstallConfirm {
+            script: output.script.join("\n"),
+            seconds: payload.confirm_seconds,
+            user: user,
+            name: name,
+        }
+    } else {
+        Request::Install {
+            script: output.script.join("\n"),
+            user: user,
+            name: name,
+        }
+    };
+    let decoded = helper_command(&state, request).await;
     for line in decoded.split("\n") {
-        resvec.push(String::from(line));
+        output.result.push(String::from(line));
+    }
+    if decoded == "OK" && payload.confirm_seconds > 0 {
+        let now = time::OffsetDateTime::now_utc().unix_timestamp();
+        let deadline = now + payload.confirm_seconds as i64;
+        *state.confirm_deadline.lock().await = Some(deadline);
+        output.confirm_deadline = Some(deadline);
+    }
+    // point nft errors to the configuration items they were generated from
+    if decoded != "OK" {
+        output.diagnostics = sourcemap::diagnostics(&script, &settings.files.test, &decoded);
+    }
+
+    // an install with confirm_seconds is recorded when it is confirmed
+    if decoded == "OK" {
+        let pending = PendingInstall {
+            name: payload.name.clone(),
+            json: payload.json.clone(),
+            script: output.script.join("\n"),
+            flush_conntrack: payload.flush_conntrack,
+            user: state.current_user.lock().await.clone(),
+        };
+        if payload.confirm_seconds > 0 {
+            *state.pending_install.lock().await = Some(pending);
+        } else {
+            *state.pending_install.lock().await = None;
+            // the settings are not locked while git runs
+            let savepath = settings.paths.savepath.clone();
+            drop(settings);
+            let (tag, terminated, errors) = finish_install(&state, &savepath, pending).await;
+            output.tag = tag;
+            output.terminated = terminated;
+            output.result.extend(errors);
+        }
     }
 
-    let output: Output = Output {
-        result: resvec,
-        script: script.clone().unwrap(),
-    };
     // Serialize it to a JSON string.
     let outstr = serde_json::to_string(&output).unwrap();
     return outstr;
 }
 
+// Record an install that is kept: the running ruleset becomes the reference for drift
+// detection, the configuration is kept for the impact analysis of the next install and
+// tagged in the configuration repository, and established connections the new ruleset no
+// longer allows are terminated. Returns the tag, the number of terminated connections and
+// errors of the conntrack flush.
+async fn finish_install(
+    state: &Arc<AppState>,
+    savepath: &str,
+    pending: PendingInstall,
+) -> (Option<String>, usize, Vec<String>) {
+    let mut tag = None;
+    let mut terminated = 0;
+    let mut errors: Vec<String> = vec![];
+    drift::record_baseline(state).await;
+    if let Err(e) = impact::record_installed(savepath, &pending.script, &pending.json).await {
+        eprintln!("{}", e);
+    }
+    match configrepo::tag_installed(savepath, &pending.name, &pending.json, &pending.user).await {
+        Ok(t) => tag = t,
+        Err(e) => eprintln!("{}", e),
+    }
+    if pending.flush_conntrack {
+        match serde_json::from_str::<ConfigurationItems>(&pending.json) {
+            Ok(config_items) => match conntrack::flush_disallowed(state, &config_items).await {
+                Ok(count) => terminated = count,
+                Err(e) => errors.push(format!("Conntrack flush failed: {}", e)),
+            },
+            Err(e) => errors.push(format!("Conntrack flush failed: {}", e)),
+        }
+    }
+    return (tag, terminated, errors);
+}
+
+// Check a configuration with the nft parser of the main process without installing it,
+// neither the test file nor the conf file are written
+async fn check_configuration(
+    session: Session,
+    State(state): State<Arc<AppState>>,
+    extract::Json(payload): extract::Json<Configuration>,
+) -> Result<String, (StatusCode, String)> {
+    if !check_session(session, &state).await {
+        return Err((StatusCode::UNAUTHORIZED, String::from("Not logged in")));
+    }
+    #[derive(Serialize, Deserialize)]
+    struct Output {
+        result: Vec<String>,
+        script: Vec<String>,
+        validation: validate::ValidationReport,
+        diagnostics: Vec<sourcemap::Diagnostic>,
+    }
+    let mut output: Output = Output {
+        result: vec![],
+        script: vec![],
+        validation: validate::ValidationReport::default(),
+        diagnostics: vec![],
+    };
+
+    let system = validate::system_interfaces();
+    let (config_items, report) = validate::validate_json(&payload.json, Some(&system));
+    output.validation = report;
+    if config_items.is_none() || !output.validation.valid {
+        output.result = vec![String::from("Validation failed")];
+        output.result.extend(output.validation.messages());
+        return Ok(serde_json::to_string(&output).unwrap());
+    }
+
+    let settings = state.settings.lock().await.clone();
+    let script = match generate_script(
+        payload.json.clone(),
+        settings.files.nft.clone(),
+        settings.connection.port,
+    ) {
+        Ok(script) => script,
+        Err(e) => {
+            output.result = vec![e];
+            return Ok(serde_json::to_string(&output).unwrap());
+        }
+    };
+    output.script = script.lines.clone();
+
+    let request = Request::Check {
+        script: output.script.join("\n"),
+    };
+    let response = match helper_request(&state, request).await {
+        Ok(r) => r,
+        Err(e) => {
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Communication with main process failed: {}", e),
+            ))
+        }
+    };
+    // warnings are reported next to OK
+    let messages = match &response {
+        Response::Text { text } => {
+            output.result.push(String::from("OK"));
+            text.clone()
+        }
+        Response::Failed { stderr, .. } => stderr.clone(),
+        r => r.to_string(),
+    };
+    output
+        .result
+        .extend(messages.lines().filter(|l| l.len() > 0).map(String::from));
+    output.diagnostics = sourcemap::diagnostics(&script, CHECK_FILE, &messages);
+    return Ok(serde_json::to_string(&output).unwrap());
+}
+
+// Keep an install that was done with confirm_seconds. The request has to reach the
+// server on a new connection through the new ruleset, otherwise the install is rolled
+// back.
+async fn confirm_install(
+    session: Session,
+    State(state): State<Arc<AppState>>,
+) -> Result<String, (StatusCode, String)> {
+    if !check_session(session, &state).await {
+        return Err((StatusCode::UNAUTHORIZED, String::from("Not logged in")));
+    }
+    let decoded = helper_command(&state, Request::Confirm).await;
+    *state.confirm_deadline.lock().await = None;
+    if decoded != "OK" {
+        // nothing is waiting anymore, after a rollback when the time ran out
+        *state.pending_install.lock().await = None;
+        return Err((StatusCode::CONFLICT, decoded));
+    }
+    let Some(pending) = state.pending_install.lock().await.take() else {
+        return Ok(decoded);
+    };
+    let savepath = state.settings.lock().await.paths.savepath.clone();
+    let (_tag, _terminated, errors) = finish_install(&state, &savepath, pending).await;
+    let mut result = vec![decoded];
+    result.extend(errors);
+    return Ok(result.join("\n"));
+}
+
+// Roll back an unconfirmed install right away, or else install the previous generation
+async fn rollback_install(
+    session: Session,
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    Extension(local): Extension<lockout::LocalAddr>,
+) -> Result<String, (StatusCode, String)> {
+    if !check_session(session, &state).await {
+        return Err((StatusCode::UNAUTHORIZED, String::from("Not logged in")));
+    }
+    history::check_rollback(&state, None, client, local.0).await?;
+    let user = state.current_user.lock().await.clone();
+    helper_call(&state, Request::Rollback { user }).await?;
+    *state.confirm_deadline.lock().await = None;
+    *state.pending_install.lock().await = None;
+    drift::record_baseline(&state).await;
+    return Ok(String::from("OK"));
+}
+
 async fn check_session(session: Session, state: &Arc<AppState>) -> bool {
     let current_id = state.current_session.lock().await;
     match *current_id {
@@ -2226,50 +2766,79 @@ async fn check_session(session: Session, state: &Arc<AppState>) -> bool {
 
 #[tokio::main]
 async fn main() {
-    // read key and iv from stdin
+    // offline packet simulation from the command line
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() > 1 && args[1] == "simulate" {
+        std::process::exit(simulator::cli(&args[2..]));
+    }
+    if args.len() > 1 && args[1] == "impact" {
+        std::process::exit(impact::cli(&args[2..]));
+    }
+
+    // read the key of the channel to the main process from stdin
     let mut reader = tokio::io::BufReader::new(tokio::io::stdin());
     let mut keyline = String::new();
-    let mut ivline = String::new();
     let read_key_result = reader.read_line(&mut keyline).await;
     match read_key_result {
         Err(err) => {
-            println!("Could not read key: {}", err.to_string());
-            return;
+            eprintln!("Could not read key: {}", err.to_string());
+            std::process::exit(1);
         }
         _ => {}
     }
 
-    let read_iv_result = reader.read_line(&mut ivline).await;
-    match read_iv_result {
-        Err(err) => {
-            println!("Could not read iv: {}", err.to_string());
-            return;
+    // the certificate and the private key of https follow, the webserver cannot read
+    // the key file itself
+    let mut tlslines = vec![];
+    for name in ["certificate", "private key"] {
+        let mut line = String::new();
+        let tls = match reader.read_line(&mut line).await {
+            Ok(_) => hex::decode(line.trim()).map_err(|e| e.to_string()),
+            Err(e) => Err(e.to_string()),
+        };
+        match tls {
+            Ok(t) => tlslines.push(t),
+            Err(e) => {
+                eprintln!("Could not read the {}: {}", name, e);
+                std::process::exit(1);
+            }
         }
-        _ => {}
     }
+    let tlskey = tlslines.pop().unwrap_or_default();
+    let certificate = tlslines.pop().unwrap_or_default();
 
     let key_bytes = hex::decode(keyline.trim()).expect("Hex decoding failed");
-    let iv_bytes = hex::decode(ivline.trim()).expect("Hex decoding failed");
-    if key_bytes.len() != 32 || iv_bytes.len() != 32 {
-        println!(
-            "Invalid key (len {}) or iv (len {})",
-            keyline.len(),
-            ivline.len()
-        );
-        return;
-    }
+    let channel = match Channel::new(&key_bytes) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("Invalid key (len {}): {}", keyline.len(), e);
+            std::process::exit(1);
+        }
+    };
+
+    // requests to the main process go over its unix socket
+    let helper = match UnixStream::connect(SOCKET_PATH).await {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("Could not connect to {}: {}", SOCKET_PATH, e);
+            std::process::exit(1);
+        }
+    };
     let settings = get_settings();
     let no_session = Id::default();
     let shared_state = Arc::new(AppState {
         current_session: Mutex::new(Some(no_session)),
         no_session: Mutex::new(Some(no_session)),
-        reader: Mutex::new(reader),
-        cipher: Mutex::new(Cipher::aes_256_cbc()),
-        key: Mutex::new(key_bytes),
-        iv: Mutex::new(iv_bytes),
+        helper: Mutex::new(BufReader::new(helper)),
+        channel: Mutex::new(channel),
         settings: Mutex::new(settings.clone()),
+        drift: Mutex::new(drift::DriftState::default()),
+        confirm_deadline: Mutex::new(None),
+        pending_install: Mutex::new(None),
+        current_user: Mutex::new(String::from("")),
     });
-    println!("OK");
+    // scheduled drift checks
+    tokio::spawn(drift::schedule(shared_state.clone()));
 
     let session_store = MemoryStore::default();
     let session_layer = SessionManagerLayer::new(session_store)
@@ -2297,6 +2866,20 @@ async fn main() {
         .route("/ids", get(ids))
         .route("/interfaces", get(get_network_interfaces))
         .route("/configs", get(get_configuration_names))
+        .route("/diff/{old}/{new}", get(configdiff::diff_configurations))
+        .route("/configs/{name}/history", get(configrepo::list_revisions))
+        .route(
+            "/configs/{name}/history/{commit}",
+            get(configrepo::show_revision),
+        )
+        .route(
+            "/configs/{name}/history/{commit}/restore",
+            post(configrepo::restore_revision),
+        )
+        .route(
+            "/configs/{name}/history/{commit}/tag",
+            post(configrepo::tag_revision),
+        )
         .route("/newtotpsecret", get(new_totp_secret))
         .route("/save", post(save_configuration))
         .route("/adduser", post(add_user))
@@ -2305,6 +2888,45 @@ async fn main() {
         .route("/userexists", get(userexists))
         .route("/login", post(login))
         .route("/install", post(install_configuration))
+        .route("/check", post(check_configuration))
+        .route("/preview", post(preview::preview_configuration))
+        .route("/verify", post(sandbox::verify_configuration))
+        .route("/flowtests", post(flowtests::run_flow_tests))
+        .route("/impact", post(impact::impact_configuration))
+        .route("/confirm", post(confirm_install))
+        .route("/rollback", post(rollback_install))
+        .route("/history", get(history::list_history))
+        .route("/history/{generation}", get(history::show_generation))
+        .route("/history/{old}/diff/{new}", get(history::diff_generations))
+        .route(
+            "/history/{generation}/rollback",
+            post(history::rollback_generation),
+        )
+        .route("/simulate", post(simulator::simulate_packet))
+        .route("/analyze", post(analysis::analyze_configuration))
+        .route("/validate", post(validate::validate_configuration))
+        .route("/handles", get(ruleset::rule_handles))
+        .route("/tables", get(ruleset::table_report))
+        .route("/counters", get(ruleset::list_counters))
+        .route("/counters/reset", post(ruleset::reset_counters))
+        .route(
+            "/sets/{family}/{table}/{set}",
+            get(ruleset::list_set_elements),
+        )
+        .route(
+            "/sets/{family}/{table}/{set}/add",
+            post(ruleset::add_set_elements),
+        )
+        .route(
+            "/sets/{family}/{table}/{set}/delete",
+            post(ruleset::delete_set_elements),
+        )
+        .route("/drift", get(drift::check_drift))
+        .route("/drift/schedule", post(drift::schedule_drift))
+        .route("/status", get(status::status))
+        .route("/trace/start", post(trace::start_trace))
+        .route("/trace/stop", get(trace::stop_trace))
+        .route("/trace/events", get(trace::trace_events))
         .merge(static_router)
         .layer(session_layer)
         .with_state(shared_state);
@@ -2312,16 +2934,71 @@ async fn main() {
     let addr = SocketAddr::from(([0, 0, 0, 0], settings.connection.port));
 
     // configure certificate and private key used by https
-    let config = RustlsConfig::from_pem_file(
-        PathBuf::from(settings.files.tlscert.clone()),
-        PathBuf::from(settings.files.tlskey.clone()),
-    )
-    .await
-    .unwrap();
-
-    // run https server
+    let config = match RustlsConfig::from_pem(certificate, tlskey).await {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("Invalid certificate or private key: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // stop accepting connections on SIGTERM, which the main process sends to shut down
+    let handle = axum_server::Handle::new();
+    let shutdown_handle = handle.clone();
+    tokio::spawn(async move {
+        if let Ok(mut sigterm) = signal(SignalKind::terminate()) {
+            sigterm.recv().await;
+            shutdown_handle.graceful_shutdown(Some(std::time::Duration::from_secs(5)));
+        }
+    });
+
+    // run https server, requests know the local address of their connection
     axum_server::bind_rustls(addr, config)
-        .serve(app.into_make_service())
+        .map(|tls| tls.acceptor(lockout::LocalAddrAcceptor))
+        .handle(handle)
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
         .await
         .unwrap();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn script(config_items: &ConfigurationItems) -> Vec<String> {
+        let json = testdata::json(config_items);
+        return generate_script(json, String::from("/usr/sbin/nft"), 443)
+            .unwrap()
+            .lines;
+    }
+
+    // family and name of the tables the lines starting with keyword refer to
+    fn tables<'a>(lines: &'a [String], keyword: &str) -> Vec<(&'a str, &'a str)> {
+        return lines
+            .iter()
+            .filter_map(|l| l.trim().strip_prefix(keyword))
+            .filter_map(|t| {
+                let mut words = t.split_whitespace();
+                return Some((words.next()?, words.next()?));
+            })
+            .collect();
+    }
+
+    #[test]
+    fn scoped_tables_leave_other_tables_alone() {
+        let mut config_items = testdata::configuration();
+        config_items.scoped_tables = true;
+        let lines = script(&config_items);
+        assert!(!lines.iter().any(|l| l.trim().starts_with("flush")));
+        assert_eq!(tables(&lines, "delete table "), OWN_TABLES);
+        for table in tables(&lines, "table ") {
+            assert!(OWN_TABLES.contains(&table), "{:?}", table);
+        }
+
+        // the whole ruleset is replaced otherwise
+        config_items.scoped_tables = false;
+        let lines = script(&config_items);
+        assert!(lines.contains(&String::from("flush ruleset")));
+        assert_eq!(tables(&lines, "delete table ").len(), 0);
+    }
+}
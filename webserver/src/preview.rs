@@ -0,0 +1,263 @@
+use crate::sourcemap::{Origin, Script};
+use crate::{check_session, generate_script, AppState, Configuration};
+use axum::extract::{self, State};
+use axum::http::StatusCode;
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tower_sessions::Session;
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct PreviewRule {
+    // line number in the script, starting at 1
+    pub line: usize,
+    pub text: String,
+    pub rule_id: Option<String>,
+    pub origin: Option<Origin>,
+}
+
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct PreviewChain {
+    pub name: String,
+    // type, hook, priority and policy lines of base chains
+    pub declaration: Vec<String>,
+    pub rules: Vec<PreviewRule>,
+}
+
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct PreviewTable {
+    pub family: String,
+    pub name: String,
+    // names of the sets declared in the table
+    pub sets: Vec<String>,
+    pub chains: Vec<PreviewChain>,
+}
+
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct ChainStatistics {
+    pub family: String,
+    pub table: String,
+    pub chain: String,
+    pub rules: usize,
+    // named sets used by the rules of the chain
+    pub sets: usize,
+    pub counters: usize,
+}
+
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct Preview {
+    pub script: String,
+    pub tables: Vec<PreviewTable>,
+    pub statistics: Vec<ChainStatistics>,
+}
+
+// Leading keyword of the declaration lines of a base chain
+const DECLARATIONS: [&str; 2] = ["type ", "policy "];
+
+// Text of a line without the trailing "# ..." note, comments never occur inside statements
+fn statement(line: &str) -> &str {
+    let trimmed = line.trim();
+    match trimmed.find('#') {
+        Some(pos) => return trimmed[..pos].trim_end(),
+        None => return trimmed,
+    }
+}
+
+// Named sets referenced by a rule, written as @name
+fn set_references(rule: &str) -> Vec<&str> {
+    return rule
+        .split('@')
+        .skip(1)
+        .filter_map(|r| r.split(|c: char| !(c.is_alphanumeric() || c == '_')).next())
+        .filter(|r| r.len() > 0)
+        .collect();
+}
+
+// Structure of a generated script: its tables with their sets and chains. Only the
+// layout produced by generate_script is understood, lines in pre and post text that
+// do not fit it are left out.
+pub fn structure(script: &Script) -> Vec<PreviewTable> {
+    let mut tables: Vec<PreviewTable> = vec![];
+    // nesting of the current line: table, then chain or set
+    let mut in_table = false;
+    let mut chain: Option<PreviewChain> = None;
+    let mut set_depth = 0;
+    for (index, raw) in script.lines.iter().enumerate() {
+        let line = statement(raw);
+        if line.len() == 0 {
+            continue;
+        }
+        let opens = line.matches('{').count();
+        let closes = line.matches('}').count();
+        if set_depth > 0 {
+            set_depth = (set_depth + opens).saturating_sub(closes);
+            continue;
+        }
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if !in_table {
+            if words.len() >= 4 && words[0] == "table" && words[3] == "{" {
+                tables.push(PreviewTable {
+                    family: String::from(words[1]),
+                    name: String::from(words[2]),
+                    ..Default::default()
+                });
+                in_table = true;
+            }
+            continue;
+        }
+        match chain.as_mut() {
+            None if line == "}" => in_table = false,
+            None if words.len() >= 2 && words[0] == "set" => {
+                if let Some(table) = tables.last_mut() {
+                    table.sets.push(String::from(words[1]));
+                }
+                set_depth = opens.saturating_sub(closes);
+            }
+            None if words.len() >= 2 && words[0] == "chain" => {
+                chain = Some(PreviewChain {
+                    name: String::from(words[1]),
+                    ..Default::default()
+                });
+            }
+            None => {}
+            Some(_c) if line == "}" => {
+                if let (Some(table), Some(c)) = (tables.last_mut(), chain.take()) {
+                    table.chains.push(c);
+                }
+            }
+            Some(c) if DECLARATIONS.iter().any(|d| line.starts_with(d)) => {
+                c.declaration.push(String::from(line));
+            }
+            Some(c) => {
+                let origin = script.origin_of(index + 1).cloned();
+                c.rules.push(PreviewRule {
+                    line: index + 1,
+                    text: String::from(line),
+                    rule_id: origin.as_ref().and_then(|o| o.rule_id()),
+                    origin: origin,
+                });
+            }
+        }
+    }
+    return tables;
+}
+
+pub fn statistics(tables: &[PreviewTable]) -> Vec<ChainStatistics> {
+    let mut result: Vec<ChainStatistics> = vec![];
+    for table in tables {
+        for chain in &table.chains {
+            let mut sets: Vec<&str> = chain
+                .rules
+                .iter()
+                .flat_map(|r| set_references(&r.text))
+                .collect();
+            sets.sort();
+            sets.dedup();
+            result.push(ChainStatistics {
+                family: table.family.clone(),
+                table: table.name.clone(),
+                chain: chain.name.clone(),
+                rules: chain.rules.len(),
+                sets: sets.len(),
+                counters: chain
+                    .rules
+                    .iter()
+                    .filter(|r| r.text.split_whitespace().any(|w| w == "counter"))
+                    .count(),
+            });
+        }
+    }
+    return result;
+}
+
+// Generate the script of a configuration without writing or installing anything
+pub async fn preview_configuration(
+    session: Session,
+    State(state): State<Arc<AppState>>,
+    extract::Json(payload): extract::Json<Configuration>,
+) -> Result<Json<Preview>, (StatusCode, String)> {
+    if !check_session(session, &state).await {
+        return Err((StatusCode::UNAUTHORIZED, String::from("Not logged in")));
+    }
+    let settings = state.settings.lock().await.clone();
+    let script = generate_script(
+        payload.json,
+        settings.files.nft.clone(),
+        settings.connection.port,
+    )
+    .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    let tables = structure(&script);
+    return Ok(Json(Preview {
+        script: script.lines.join("\n"),
+        statistics: statistics(&tables),
+        tables: tables,
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdata;
+
+    #[test]
+    fn preview_of_configuration() {
+        let json = testdata::json(&testdata::configuration());
+        let script = generate_script(json, String::from("/usr/sbin/nft"), 443).unwrap();
+        let tables = structure(&script);
+        let names: Vec<(&str, &str)> = tables
+            .iter()
+            .map(|t| (t.family.as_str(), t.name.as_str()))
+            .collect();
+        assert_eq!(
+            names,
+            [
+                ("inet", "filter_inet"),
+                ("inet", "snat_inet"),
+                ("inet", "dnat_inet"),
+                ("netdev", "filter_netdev")
+            ]
+        );
+
+        // set elements and the lines inside set declarations are not rules
+        let filter = &tables[0];
+        assert!(filter.sets.contains(&String::from("syn_rate_limit_ipv4")));
+        assert!(filter.sets.contains(&String::from("lan_dmz_dest_ipv6")));
+        let chains: Vec<&str> = filter.chains.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(
+            chains,
+            ["lan_dmz", "all_input", "all_forward", "all_output"]
+        );
+
+        // the rule of the configuration points back to it and to its line in the script
+        let lan_dmz = &filter.chains[0];
+        assert_eq!(lan_dmz.declaration.len(), 0);
+        let rule = lan_dmz
+            .rules
+            .iter()
+            .find(|r| r.rule_id.as_deref() == Some("nb:filter:lan_dmz:1"))
+            .unwrap();
+        assert!(rule.text.contains("tcp dport { 443 }"));
+        assert_eq!(script.lines[rule.line - 1].trim(), rule.text);
+        let origin = Origin::FilterRule {
+            chain: String::from("lan_dmz"),
+            id: String::from("1"),
+        };
+        assert!(rule.origin == Some(origin));
+
+        let forward = &filter.chains[2];
+        assert_eq!(
+            forward.declaration,
+            ["type filter hook forward priority filter;"]
+        );
+        assert_eq!(forward.rules.len(), 1);
+        assert!(forward.rules[0]
+            .text
+            .starts_with("iifname eth0 oifname eth1 jump lan_dmz"));
+
+        let statistics = statistics(&tables);
+        let lan_dmz = statistics.iter().find(|s| s.chain == "lan_dmz").unwrap();
+        assert_eq!(lan_dmz.rules, filter.chains[0].rules.len());
+        assert_eq!(lan_dmz.sets, 5);
+        assert_eq!(lan_dmz.counters, 0);
+    }
+}
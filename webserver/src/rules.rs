@@ -0,0 +1,590 @@
+use crate::{
+    chain_on_loopback, get_chain, get_interface, icmpv4_types_from_services,
+    icmpv6_types_from_services, ipv4addresses_from_def, ipv6addresses_from_def,
+    tcp_ports_from_services, udp_ports_from_services, ConfigurationItems, FilterRuleData,
+    FilterTableData, NatRuleData,
+};
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+
+// A single nft rule line as generate_script emits it for a filter or nat rule
+#[derive(Clone, Default, Deserialize, Serialize, PartialEq)]
+pub struct RuleMatch {
+    pub family: String,
+    pub protocol: String,
+    pub saddr: Vec<String>,
+    pub daddr: Vec<String>,
+    pub sport: Vec<String>,
+    pub dport: Vec<String>,
+    pub icmptypes: Vec<String>,
+    pub action: String,
+}
+
+// A packet or connection as seen by the filter hooks
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct Flow {
+    pub iif: String,
+    pub oif: String,
+    pub saddr: String,
+    pub daddr: String,
+    pub protocol: String,
+    pub sport: Option<u16>,
+    pub dport: Option<u16>,
+    pub icmptype: Option<u8>,
+}
+
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct Verdict {
+    pub verdict: String,
+    pub chain: String,
+    pub rule: Option<usize>,
+    pub reason: String,
+}
+
+pub fn addresses_from_defs(
+    config_items: &ConfigurationItems,
+    defs: &Vec<String>,
+) -> (Vec<String>, Vec<String>) {
+    let mut ipv4s: Vec<String> = vec![];
+    let mut ipv6s: Vec<String> = vec![];
+    for def in defs.iter() {
+        for ip in ipv4addresses_from_def(config_items, def) {
+            if !ipv4s.contains(&ip) {
+                ipv4s.push(ip);
+            }
+        }
+        for ip in ipv6addresses_from_def(config_items, def) {
+            if !ipv6s.contains(&ip) {
+                ipv6s.push(ip);
+            }
+        }
+    }
+    return (ipv4s, ipv6s);
+}
+
+// same checks generate_script uses to decide whether a rule gets an ipv4 or ipv6 line
+pub fn family_valid(
+    source: &Vec<String>,
+    dest: &Vec<String>,
+    other_source: &Vec<String>,
+    other_dest: &Vec<String>,
+    service_set: bool,
+) -> bool {
+    return (source.len() > 0 && (dest.len() > 0 || other_dest.len() == 0))
+        || (dest.len() > 0 && (source.len() > 0 || other_source.len() == 0))
+        || ((source.len() == 0
+            && dest.len() == 0
+            && other_source.len() == 0
+            && other_dest.len() == 0)
+            && service_set);
+}
+
+// same checks generate_script uses to decide whether a rule gets a tcp or udp line
+fn protocol_valid(
+    source_ports: &Vec<String>,
+    dest_ports: &Vec<String>,
+    other_source_ports: &Vec<String>,
+    other_dest_ports: &Vec<String>,
+) -> bool {
+    return (source_ports.len() > 0 && (dest_ports.len() > 0 || other_dest_ports.len() == 0))
+        || (dest_ports.len() > 0 && (source_ports.len() > 0 || other_source_ports.len() == 0));
+}
+
+// same checks generate_script uses for the translated side of a nat rule
+fn nat_protocol_valid(
+    trans_ports: &Vec<String>,
+    other_trans_ports: &Vec<String>,
+    source_ports: &Vec<String>,
+    dest_ports: &Vec<String>,
+    other_source_ports: &Vec<String>,
+    other_dest_ports: &Vec<String>,
+) -> bool {
+    return trans_ports.len() < 2
+        && (trans_ports.len() == 1 || (other_trans_ports.len() == 0 && trans_ports.len() == 0))
+        && protocol_valid(source_ports, dest_ports, other_source_ports, other_dest_ports);
+}
+
+fn merged_types(source_types: &Vec<String>, dest_types: &Vec<String>) -> Vec<String> {
+    let mut types: Vec<String> = vec![];
+    for t in source_types.iter().chain(dest_types.iter()) {
+        if !types.contains(t) {
+            types.push(t.clone());
+        }
+    }
+    return types;
+}
+
+// Expand a filter rule into the nft rule lines generate_script emits for it.
+// A rule that expands to nothing is never part of the installed ruleset.
+pub fn expand_filter_rule(config_items: &ConfigurationItems, rule: &FilterRuleData) -> Vec<RuleMatch> {
+    let mut matches: Vec<RuleMatch> = vec![];
+    if !rule.active {
+        return matches;
+    }
+    let (source_ipv4s, source_ipv6s) = addresses_from_defs(config_items, &rule.source);
+    let (dest_ipv4s, dest_ipv6s) = addresses_from_defs(config_items, &rule.destination);
+    let source_tcp_ports = tcp_ports_from_services(config_items, &rule.sourceservice);
+    let source_udp_ports = udp_ports_from_services(config_items, &rule.sourceservice);
+    let source_icmpv4_types = icmpv4_types_from_services(&rule.sourceservice);
+    let source_icmpv6_types = icmpv6_types_from_services(&rule.sourceservice);
+    let dest_tcp_ports = tcp_ports_from_services(config_items, &rule.destinationservice);
+    let dest_udp_ports = udp_ports_from_services(config_items, &rule.destinationservice);
+    let dest_icmpv4_types = icmpv4_types_from_services(&rule.destinationservice);
+    let dest_icmpv6_types = icmpv6_types_from_services(&rule.destinationservice);
+
+    let service_set = source_tcp_ports.len() > 0
+        || source_udp_ports.len() > 0
+        || source_icmpv4_types.len() > 0
+        || source_icmpv6_types.len() > 0
+        || dest_tcp_ports.len() > 0
+        || dest_udp_ports.len() > 0
+        || dest_icmpv4_types.len() > 0
+        || dest_icmpv6_types.len() > 0;
+    // icmp rules are only emitted when the icmp rate limiting default is inactive
+    let icmp_rules = config_items
+        .inactive_defaults
+        .contains(&String::from("ICMP"));
+
+    for family in ["ipv4", "ipv6"] {
+        let (saddr, daddr, other_saddr, other_daddr, source_types, dest_types, icmp) =
+            if family == "ipv4" {
+                (
+                    &source_ipv4s,
+                    &dest_ipv4s,
+                    &source_ipv6s,
+                    &dest_ipv6s,
+                    &source_icmpv4_types,
+                    &dest_icmpv4_types,
+                    "icmp",
+                )
+            } else {
+                (
+                    &source_ipv6s,
+                    &dest_ipv6s,
+                    &source_ipv4s,
+                    &dest_ipv4s,
+                    &source_icmpv6_types,
+                    &dest_icmpv6_types,
+                    "icmpv6",
+                )
+            };
+        if !family_valid(saddr, daddr, other_saddr, other_daddr, service_set) {
+            continue;
+        }
+        let base = RuleMatch {
+            family: String::from(family),
+            saddr: saddr.clone(),
+            daddr: daddr.clone(),
+            action: rule.action.clone(),
+            ..Default::default()
+        };
+        if protocol_valid(
+            &source_tcp_ports,
+            &dest_tcp_ports,
+            &source_udp_ports,
+            &dest_udp_ports,
+        ) {
+            matches.push(RuleMatch {
+                protocol: String::from("tcp"),
+                sport: source_tcp_ports.clone(),
+                dport: dest_tcp_ports.clone(),
+                ..base.clone()
+            });
+        }
+        if protocol_valid(
+            &source_udp_ports,
+            &dest_udp_ports,
+            &source_tcp_ports,
+            &dest_tcp_ports,
+        ) {
+            matches.push(RuleMatch {
+                protocol: String::from("udp"),
+                sport: source_udp_ports.clone(),
+                dport: dest_udp_ports.clone(),
+                ..base.clone()
+            });
+        }
+        if icmp_rules && (source_types.len() > 0 || dest_types.len() > 0) {
+            matches.push(RuleMatch {
+                protocol: String::from(icmp),
+                icmptypes: merged_types(source_types, dest_types),
+                ..base.clone()
+            });
+        }
+    }
+    return matches;
+}
+
+// A nat rule line: the match before translation and the address and port it translates to
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct NatMatch {
+    pub rule: RuleMatch,
+    pub address: Option<String>,
+    pub port: Option<String>,
+}
+
+// Expand a nat rule into the snat or dnat lines generate_script emits for it
+pub fn expand_nat_rule(config_items: &ConfigurationItems, rule: &NatRuleData) -> Vec<NatMatch> {
+    let mut matches: Vec<NatMatch> = vec![];
+    if !rule.active {
+        return matches;
+    }
+    let (source_ipv4s, source_ipv6s) = addresses_from_defs(config_items, &rule.source);
+    let (dest_ipv4s, dest_ipv6s) = addresses_from_defs(config_items, &rule.destination);
+    let trans_ipv4s = ipv4addresses_from_def(config_items, &rule.translated);
+    let trans_ipv6s = ipv6addresses_from_def(config_items, &rule.translated);
+    let source_tcp_ports = tcp_ports_from_services(config_items, &rule.sourceservice);
+    let source_udp_ports = udp_ports_from_services(config_items, &rule.sourceservice);
+    let dest_tcp_ports = tcp_ports_from_services(config_items, &rule.destinationservice);
+    let dest_udp_ports = udp_ports_from_services(config_items, &rule.destinationservice);
+    let trans_tcp_ports =
+        tcp_ports_from_services(config_items, &vec![rule.translatedservice.clone()]);
+    let trans_udp_ports =
+        udp_ports_from_services(config_items, &vec![rule.translatedservice.clone()]);
+
+    let service_set = source_tcp_ports.len() > 0
+        || source_udp_ports.len() > 0
+        || dest_tcp_ports.len() > 0
+        || dest_udp_ports.len() > 0;
+
+    for family in ["ipv4", "ipv6"] {
+        let (saddr, daddr, other_saddr, other_daddr, trans) = if family == "ipv4" {
+            (
+                &source_ipv4s,
+                &dest_ipv4s,
+                &source_ipv6s,
+                &dest_ipv6s,
+                &trans_ipv4s,
+            )
+        } else {
+            (
+                &source_ipv6s,
+                &dest_ipv6s,
+                &source_ipv4s,
+                &dest_ipv4s,
+                &trans_ipv6s,
+            )
+        };
+        // generate_script only requires a single translated address for the first term
+        let source_only = saddr.len() > 0 && (daddr.len() > 0 || other_daddr.len() == 0);
+        let dest_only = daddr.len() > 0 && (saddr.len() > 0 || other_saddr.len() == 0);
+        let services_only = saddr.len() == 0
+            && daddr.len() == 0
+            && other_saddr.len() == 0
+            && other_daddr.len() == 0
+            && service_set;
+        if !((trans.len() == 1 && source_only) || dest_only || services_only) {
+            continue;
+        }
+        for protocol in ["tcp", "udp"] {
+            let (sports, dports, other_sports, other_dports, tports, other_tports) =
+                if protocol == "tcp" {
+                    (
+                        &source_tcp_ports,
+                        &dest_tcp_ports,
+                        &source_udp_ports,
+                        &dest_udp_ports,
+                        &trans_tcp_ports,
+                        &trans_udp_ports,
+                    )
+                } else {
+                    (
+                        &source_udp_ports,
+                        &dest_udp_ports,
+                        &source_tcp_ports,
+                        &dest_tcp_ports,
+                        &trans_udp_ports,
+                        &trans_tcp_ports,
+                    )
+                };
+            if !nat_protocol_valid(tports, other_tports, sports, dports, other_sports, other_dports)
+            {
+                continue;
+            }
+            matches.push(NatMatch {
+                rule: RuleMatch {
+                    family: String::from(family),
+                    protocol: String::from(protocol),
+                    saddr: saddr.clone(),
+                    daddr: daddr.clone(),
+                    sport: sports.clone(),
+                    dport: dports.clone(),
+                    ..Default::default()
+                },
+                address: trans.first().cloned().filter(|_a| trans.len() == 1),
+                port: tports.first().cloned(),
+            });
+        }
+    }
+    return matches;
+}
+
+// Expand a nat rule into the accept rules generate_script inserts at the top of
+// the filter chain with the same name (unless the AllowNAT default is inactive).
+// For dnat the accept rule matches the translated destination.
+pub fn expand_nat_accept(
+    config_items: &ConfigurationItems,
+    rule: &NatRuleData,
+    dnat: bool,
+) -> Vec<RuleMatch> {
+    let mut matches: Vec<RuleMatch> = vec![];
+    for nat in expand_nat_rule(config_items, rule) {
+        let mut m = nat.rule;
+        m.action = String::from("accept");
+        if dnat {
+            if let Some(address) = nat.address {
+                m.daddr = vec![address];
+            }
+            if let Some(port) = nat.port {
+                m.dport = vec![port];
+            }
+        }
+        matches.push(m);
+    }
+    return matches;
+}
+
+fn ip_value(ip: IpAddr) -> (bool, u128) {
+    match ip {
+        IpAddr::V4(a) => return (false, u32::from(a) as u128),
+        IpAddr::V6(a) => return (true, u128::from(a)),
+    }
+}
+
+// The first and last address covered by an address from the configuration
+// (address, network or range), together with whether it is an ipv6 address
+pub fn address_range(spec: &str) -> Option<(bool, u128, u128)> {
+    let spec = spec.trim();
+    if let Some((first, last)) = spec.split_once('-') {
+        let (first_v6, first) = ip_value(first.trim().parse::<IpAddr>().ok()?);
+        let (last_v6, last) = ip_value(last.trim().parse::<IpAddr>().ok()?);
+        if first_v6 != last_v6 {
+            return None;
+        }
+        return Some((first_v6, first, last));
+    }
+    let (addr, prefix) = match spec.split_once('/') {
+        Some((a, p)) => (a, Some(p.parse::<u32>().ok()?)),
+        None => (spec, None),
+    };
+    let (v6, value) = ip_value(addr.parse::<IpAddr>().ok()?);
+    let bits = if v6 { 128 } else { 32 };
+    let hostbits = bits - clamp_prefix(prefix, bits);
+    let hostmask: u128 = if hostbits == 128 {
+        u128::MAX
+    } else {
+        (1u128 << hostbits) - 1
+    };
+    return Some((v6, value & !hostmask, value | hostmask));
+}
+
+fn clamp_prefix(prefix: Option<u32>, max: u32) -> u32 {
+    match prefix {
+        Some(p) if p < max => return p,
+        _ => return max,
+    }
+}
+
+// Check whether an address from the configuration (address, network or range) contains ip
+pub fn address_matches(spec: &str, ip: &IpAddr) -> bool {
+    let (v6, value) = ip_value(*ip);
+    match address_range(spec) {
+        Some((range_v6, first, last)) => return range_v6 == v6 && first <= value && value <= last,
+        None => return false,
+    }
+}
+
+fn addresses_match(specs: &Vec<String>, addr: &str) -> bool {
+    if specs.len() == 0 {
+        return true;
+    }
+    let Ok(ip) = addr.parse::<IpAddr>() else {
+        return false;
+    };
+    return specs.iter().any(|s| address_matches(s, &ip));
+}
+
+fn ports_match(ports: &Vec<String>, port: Option<u16>) -> bool {
+    if ports.len() == 0 {
+        return true;
+    }
+    match port {
+        Some(p) => return ports.iter().any(|s| s.parse::<u16>() == Ok(p)),
+        None => return false,
+    }
+}
+
+pub fn flow_family(flow: &Flow) -> String {
+    match flow.saddr.parse::<IpAddr>() {
+        Ok(IpAddr::V6(_)) => return String::from("ipv6"),
+        _ => return String::from("ipv4"),
+    }
+}
+
+pub fn rule_matches(m: &RuleMatch, flow: &Flow) -> bool {
+    if m.family != flow_family(flow) || m.protocol != flow.protocol {
+        return false;
+    }
+    if !addresses_match(&m.saddr, &flow.saddr) || !addresses_match(&m.daddr, &flow.daddr) {
+        return false;
+    }
+    if m.protocol == "icmp" || m.protocol == "icmpv6" {
+        if m.icmptypes.len() == 0 {
+            return true;
+        }
+        match flow.icmptype {
+            Some(t) => return m.icmptypes.contains(&format!("{}", t)),
+            None => return false,
+        }
+    }
+    return ports_match(&m.sport, flow.sport) && ports_match(&m.dport, flow.dport);
+}
+
+fn default_active(config_items: &ConfigurationItems, name: &str) -> bool {
+    return !config_items.inactive_defaults.contains(&String::from(name));
+}
+
+// Evaluate a flow against a single filter chain in the order generate_script emits it.
+// Returns None when the chain does not apply to the flow's interfaces.
+pub fn chain_verdict(
+    config_items: &ConfigurationItems,
+    filtertable: &FilterTableData,
+    flow: &Flow,
+    ct_state: &str,
+    direction: &str,
+) -> Result<Option<Verdict>, String> {
+    let chain = get_chain(config_items, &filtertable.chain)?;
+    if chain.direction != direction {
+        return Ok(None);
+    }
+    if direction != "output" && get_interface(config_items, &chain.iface_in)? != flow.iif {
+        return Ok(None);
+    }
+    if direction != "input" && get_interface(config_items, &chain.iface_out)? != flow.oif {
+        return Ok(None);
+    }
+    let verdict = |verdict: &str, rule: Option<usize>, reason: &str| Verdict {
+        verdict: String::from(verdict),
+        chain: filtertable.chain.clone(),
+        rule: rule,
+        reason: String::from(reason),
+    };
+
+    // accept rules for nat translations are inserted at the top of the chain
+    if default_active(config_items, "AllowNAT") {
+        for (nat, dnat) in [(&config_items.dnat, true), (&config_items.snat, false)] {
+            for nattable in nat.nattables.iter() {
+                if nattable.deleted || nattable.chain != filtertable.chain {
+                    continue;
+                }
+                if (dnat && !chain.dnat) || (!dnat && !chain.snat) {
+                    continue;
+                }
+                for (index, rule) in nattable.rules.iter().enumerate() {
+                    for m in expand_nat_accept(config_items, rule, dnat) {
+                        if rule_matches(&m, flow) {
+                            let reason = if dnat { "dnat" } else { "snat" };
+                            return Ok(Some(verdict("accept", Some(index), reason)));
+                        }
+                    }
+                }
+            }
+        }
+    }
+    if default_active(config_items, "SRCEQDST")
+        && !chain_on_loopback(config_items, &chain)
+        && flow.saddr == flow.daddr
+    {
+        return Ok(Some(verdict("drop", None, "SRCEQDST")));
+    }
+    if ct_state == "established" && default_active(config_items, "CT-Established") {
+        return Ok(Some(verdict("accept", None, "CT-Established")));
+    }
+    if ct_state == "related" && default_active(config_items, "CT-Related") {
+        return Ok(Some(verdict("accept", None, "CT-Related")));
+    }
+    if ct_state == "invalid" && default_active(config_items, "CT-Invalid") {
+        return Ok(Some(verdict("drop", None, "CT-Invalid")));
+    }
+    if chain.filter {
+        for (index, rule) in filtertable.rules.iter().enumerate() {
+            for m in expand_filter_rule(config_items, rule) {
+                if rule_matches(&m, flow) {
+                    return Ok(Some(verdict(&m.action, Some(index), "rule")));
+                }
+            }
+        }
+    }
+    return Ok(Some(verdict(&filtertable.policy, None, "policy")));
+}
+
+// Evaluate a flow against the all_input, all_forward or all_output base chain.
+// A flow that does not jump to any chain is accepted by the base chain. The management
+// rule accepts tcp to management_port in all_input ahead of the jumps, it is left out
+// when the port is not known.
+pub fn filter_verdict(
+    config_items: &ConfigurationItems,
+    flow: &Flow,
+    ct_state: &str,
+    direction: &str,
+    management_port: Option<u16>,
+) -> Result<Verdict, String> {
+    if config_items.management_rule
+        && direction == "input"
+        && flow.protocol == "tcp"
+        && management_port.is_some()
+        && flow.dport == management_port
+    {
+        return Ok(Verdict {
+            verdict: String::from("accept"),
+            chain: String::from("all_input"),
+            rule: None,
+            reason: String::from("management rule"),
+        });
+    }
+    for filtertable in config_items.filters.filtertables.iter() {
+        if filtertable.deleted {
+            continue;
+        }
+        if let Some(v) = chain_verdict(config_items, filtertable, flow, ct_state, direction)? {
+            return Ok(v);
+        }
+    }
+    return Ok(Verdict {
+        verdict: String::from("accept"),
+        chain: format!("all_{}", direction),
+        rule: None,
+        reason: String::from("no chain"),
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdata;
+
+    #[test]
+    fn expand_rule_of_configuration() {
+        let config_items = testdata::configuration();
+        let rule = &config_items.filters.filtertables[0].rules[0];
+        let matches = expand_filter_rule(&config_items, rule);
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0].family, "ipv4");
+        assert_eq!(matches[0].protocol, "tcp");
+        assert_eq!(matches[0].saddr, ["10.0.0.0/24"]);
+        assert_eq!(matches[0].daddr, ["192.0.2.10"]);
+        assert_eq!(matches[0].dport, ["443"]);
+        assert_eq!(matches[0].action, "accept");
+    }
+
+    #[test]
+    fn addresses() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        assert!(address_matches("10.0.0.0/24", &ip("10.0.0.255")));
+        assert!(!address_matches("10.0.0.0/24", &ip("10.0.1.0")));
+        assert!(address_matches("10.0.0.5-10.0.0.9", &ip("10.0.0.9")));
+        assert!(!address_matches("10.0.0.0/8", &ip("::ffff:10.0.0.1")));
+        assert!(address_matches("2001:db8::/32", &ip("2001:db8:1::1")));
+        assert_eq!(address_range("10.0.0.1/33"), Some((false, 0x0a000001, 0x0a000001)));
+    }
+}
@@ -0,0 +1,204 @@
+use crate::sourcemap::Origin;
+use crate::{check_session, helper_call, helper_command, AppState, OWN_TABLES};
+use axum::extract::{self, Path, State};
+use axum::http::StatusCode;
+use axum::Json;
+use protocol::{Counter, Request, Response};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::sync::Arc;
+use tower_sessions::Session;
+
+// A rule of the active ruleset, origin is set when the rule carries the id of a
+// configuration item in its comment
+#[derive(Clone, Deserialize, Serialize)]
+pub struct RuleHandle {
+    pub family: String,
+    pub table: String,
+    pub chain: String,
+    pub handle: u64,
+    pub id: Option<String>,
+    pub origin: Option<Origin>,
+    pub expr: Value,
+}
+
+// A table of the active ruleset, owned tables are the ones the builder replaces
+#[derive(Clone, Deserialize, Serialize)]
+pub struct TableInfo {
+    pub family: String,
+    pub name: String,
+    pub owned: bool,
+}
+
+fn ruleset_objects(json: &str) -> Result<Vec<Value>, String> {
+    let ruleset: Value = match serde_json::from_str(json) {
+        Ok(v) => v,
+        // nft reports errors as plain text
+        Err(_e) => return Err(String::from(json.trim())),
+    };
+    match ruleset["nftables"].as_array() {
+        Some(objects) => return Ok(objects.clone()),
+        None => return Err(String::from("Invalid ruleset listing")),
+    }
+}
+
+// Parse the output of nft -j list ruleset into its tables
+pub fn parse_tables(json: &str) -> Result<Vec<TableInfo>, String> {
+    let mut tables: Vec<TableInfo> = vec![];
+    for object in ruleset_objects(json)?.iter() {
+        let table = &object["table"];
+        if !table.is_object() {
+            continue;
+        }
+        let family = String::from(table["family"].as_str().unwrap_or(""));
+        let name = String::from(table["name"].as_str().unwrap_or(""));
+        tables.push(TableInfo {
+            owned: OWN_TABLES.contains(&(family.as_str(), name.as_str())),
+            family: family,
+            name: name,
+        });
+    }
+    return Ok(tables);
+}
+
+// Parse the output of nft -j -a list ruleset into its rules
+pub fn parse_ruleset(json: &str) -> Result<Vec<RuleHandle>, String> {
+    let objects = ruleset_objects(json)?;
+    let mut rules: Vec<RuleHandle> = vec![];
+    for object in objects.iter() {
+        let rule = &object["rule"];
+        if !rule.is_object() {
+            continue;
+        }
+        let id = rule["comment"].as_str().map(String::from);
+        rules.push(RuleHandle {
+            family: String::from(rule["family"].as_str().unwrap_or("")),
+            table: String::from(rule["table"].as_str().unwrap_or("")),
+            chain: String::from(rule["chain"].as_str().unwrap_or("")),
+            handle: rule["handle"].as_u64().unwrap_or(0),
+            origin: id.as_deref().and_then(Origin::from_rule_id),
+            id: id,
+            expr: rule["expr"].clone(),
+        });
+    }
+    return Ok(rules);
+}
+
+// Read the active ruleset through the main process
+pub async fn active_rules(state: &Arc<AppState>) -> Result<Vec<RuleHandle>, String> {
+    let listing = helper_command(state, Request::ListRuleset).await;
+    return parse_ruleset(&listing);
+}
+
+pub async fn rule_handles(
+    session: Session,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<Vec<RuleHandle>>, (StatusCode, String)> {
+    if !check_session(session, &state).await {
+        return Err((StatusCode::UNAUTHORIZED, String::from("Not logged in")));
+    }
+    match active_rules(&state).await {
+        Ok(rules) => return Ok(Json(rules)),
+        Err(e) => return Err((StatusCode::INTERNAL_SERVER_ERROR, e)),
+    }
+}
+
+// List the tables of the active ruleset and whether they are managed by the builder
+pub async fn table_report(
+    session: Session,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<Vec<TableInfo>>, (StatusCode, String)> {
+    if !check_session(session, &state).await {
+        return Err((StatusCode::UNAUTHORIZED, String::from("Not logged in")));
+    }
+    let listing = helper_command(&state, Request::ListRuleset).await;
+    match parse_tables(&listing) {
+        Ok(tables) => return Ok(Json(tables)),
+        Err(e) => return Err((StatusCode::INTERNAL_SERVER_ERROR, e)),
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct ResetCountersRequest {
+    pub family: String,
+    pub table: String,
+}
+
+pub async fn list_counters(
+    session: Session,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<Vec<Counter>>, (StatusCode, String)> {
+    if !check_session(session, &state).await {
+        return Err((StatusCode::UNAUTHORIZED, String::from("Not logged in")));
+    }
+    match helper_call(&state, Request::ListCounters).await? {
+        Response::Counters { counters } => return Ok(Json(counters)),
+        r => return Err((StatusCode::INTERNAL_SERVER_ERROR, r.to_string())),
+    }
+}
+
+pub async fn reset_counters(
+    session: Session,
+    State(state): State<Arc<AppState>>,
+    extract::Json(payload): extract::Json<ResetCountersRequest>,
+) -> Result<String, (StatusCode, String)> {
+    if !check_session(session, &state).await {
+        return Err((StatusCode::UNAUTHORIZED, String::from("Not logged in")));
+    }
+    let request = Request::ResetCounters {
+        family: payload.family,
+        table: payload.table,
+    };
+    return Ok(helper_call(&state, request).await?.to_string());
+}
+
+pub async fn list_set_elements(
+    session: Session,
+    Path((family, table, set)): Path<(String, String, String)>,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<Vec<String>>, (StatusCode, String)> {
+    if !check_session(session, &state).await {
+        return Err((StatusCode::UNAUTHORIZED, String::from("Not logged in")));
+    }
+    let request = Request::ListSetElements { family, table, set };
+    match helper_call(&state, request).await? {
+        Response::SetElements { elements } => return Ok(Json(elements)),
+        r => return Err((StatusCode::INTERNAL_SERVER_ERROR, r.to_string())),
+    }
+}
+
+pub async fn add_set_elements(
+    session: Session,
+    Path((family, table, set)): Path<(String, String, String)>,
+    State(state): State<Arc<AppState>>,
+    extract::Json(elements): extract::Json<Vec<String>>,
+) -> Result<String, (StatusCode, String)> {
+    if !check_session(session, &state).await {
+        return Err((StatusCode::UNAUTHORIZED, String::from("Not logged in")));
+    }
+    let request = Request::AddSetElements {
+        family,
+        table,
+        set,
+        elements,
+    };
+    return Ok(helper_call(&state, request).await?.to_string());
+}
+
+pub async fn delete_set_elements(
+    session: Session,
+    Path((family, table, set)): Path<(String, String, String)>,
+    State(state): State<Arc<AppState>>,
+    extract::Json(elements): extract::Json<Vec<String>>,
+) -> Result<String, (StatusCode, String)> {
+    if !check_session(session, &state).await {
+        return Err((StatusCode::UNAUTHORIZED, String::from("Not logged in")));
+    }
+    let request = Request::DeleteSetElements {
+        family,
+        table,
+        set,
+        elements,
+    };
+    return Ok(helper_call(&state, request).await?.to_string());
+}
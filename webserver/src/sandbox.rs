@@ -0,0 +1,73 @@
+use crate::validate;
+use crate::{check_session, generate_script, helper_call, AppState};
+use axum::extract::{self, State};
+use axum::http::StatusCode;
+use axum::Json;
+use protocol::{Probe, Request, Response, SandboxReport};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tower_sessions::Session;
+
+#[derive(Deserialize, Serialize)]
+pub struct VerifyRequest {
+    pub json: String,
+    // interfaces may be given by configured or system name
+    pub probes: Vec<Probe>,
+}
+
+// Load the configuration in throwaway network namespaces with its interfaces and report
+// which probes pass. Nothing is installed.
+pub async fn verify_configuration(
+    session: Session,
+    State(state): State<Arc<AppState>>,
+    extract::Json(payload): extract::Json<VerifyRequest>,
+) -> Result<Json<SandboxReport>, (StatusCode, String)> {
+    if !check_session(session, &state).await {
+        return Err((StatusCode::UNAUTHORIZED, String::from("Not logged in")));
+    }
+    // the interfaces are created in the sandbox, they need not exist on the system
+    let (config_items, report) = validate::validate_json(&payload.json, None);
+    let Some(config_items) = config_items.filter(|_c| report.valid) else {
+        let mut messages = vec![String::from("Validation failed")];
+        messages.extend(report.messages());
+        return Err((StatusCode::BAD_REQUEST, messages.join("\n")));
+    };
+    let system_name = |name: &String| match config_items.interfaces.get(name) {
+        Some(iface) => iface.systemname.clone(),
+        None => name.clone(),
+    };
+    let mut interfaces: Vec<String> = config_items
+        .interfaces
+        .values()
+        .filter(|i| !i.loopback && i.systemname.len() > 0)
+        .map(|i| i.systemname.clone())
+        .collect();
+    interfaces.sort();
+    interfaces.dedup();
+    let probes = payload
+        .probes
+        .iter()
+        .map(|p| Probe {
+            iif: system_name(&p.iif),
+            oif: system_name(&p.oif),
+            ..p.clone()
+        })
+        .collect();
+
+    let settings = state.settings.lock().await.clone();
+    let script = generate_script(
+        payload.json,
+        settings.files.nft.clone(),
+        settings.connection.port,
+    )
+    .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    let request = Request::Verify {
+        script: script.lines.join("\n"),
+        interfaces: interfaces,
+        probes: probes,
+    };
+    match helper_call(&state, request).await? {
+        Response::Sandbox { report } => return Ok(Json(report)),
+        r => return Err((StatusCode::INTERNAL_SERVER_ERROR, r.to_string())),
+    }
+}
@@ -0,0 +1,393 @@
+use crate::rules::{expand_nat_rule, filter_verdict, rule_matches, Flow, NatMatch};
+use crate::{check_session, get_chain, AppState, ConfigurationItems, NatData};
+use axum::extract::{self, State};
+use axum::http::StatusCode;
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::sync::Arc;
+use tower_sessions::Session;
+
+// A packet described by the user. Interfaces may be given by configured or system name,
+// an empty iif means the packet is sent by the host itself, an empty oif that it is
+// delivered to the host.
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct Packet {
+    #[serde(default)]
+    pub iif: String,
+    #[serde(default)]
+    pub oif: String,
+    pub saddr: String,
+    pub daddr: String,
+    pub protocol: String,
+    #[serde(default)]
+    pub sport: Option<u16>,
+    #[serde(default)]
+    pub dport: Option<u16>,
+    #[serde(default)]
+    pub icmptype: Option<u8>,
+    #[serde(default)]
+    pub ct_state: String,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Translation {
+    pub chain: String,
+    pub rule: usize,
+    pub address: String,
+    pub port: Option<u16>,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct SimulationResult {
+    pub direction: String,
+    pub verdict: String,
+    pub chain: String,
+    pub rule: Option<usize>,
+    pub reason: String,
+    pub dnat: Option<Translation>,
+    pub snat: Option<Translation>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct SimulationRequest {
+    pub json: String,
+    pub packet: Packet,
+}
+
+// map a configured interface name to its system name, system names are kept as they are
+fn system_interface(config_items: &ConfigurationItems, name: &String) -> String {
+    match config_items.interfaces.get(name) {
+        Some(iface) => return iface.systemname.clone(),
+        None => return name.clone(),
+    }
+}
+
+// nat rules match on the interface names of the chain as they are configured
+fn nat_interfaces_match(
+    config_items: &ConfigurationItems,
+    chain: &String,
+    iif: &String,
+    oif: &String,
+) -> Result<bool, String> {
+    let chain = get_chain(config_items, chain)?;
+    let iif_name = config_items
+        .interfaces
+        .get(&chain.iface_in)
+        .map(|i| &i.systemname);
+    let oif_name = config_items
+        .interfaces
+        .get(&chain.iface_out)
+        .map(|i| &i.systemname);
+    if chain.iface_in != "-" && chain.iface_in != *iif && iif_name != Some(iif) {
+        return Ok(false);
+    }
+    if chain.iface_out != "-" && chain.iface_out != *oif && oif_name != Some(oif) {
+        return Ok(false);
+    }
+    return Ok(true);
+}
+
+// Find the first nat rule that matches the flow
+fn nat_lookup(
+    config_items: &ConfigurationItems,
+    nat: &NatData,
+    dnat: bool,
+    flow: &Flow,
+) -> Result<Option<(String, usize, NatMatch)>, String> {
+    for nattable in nat.nattables.iter() {
+        if nattable.deleted || nattable.rules.len() == 0 {
+            continue;
+        }
+        let chain = get_chain(config_items, &nattable.chain)?;
+        if (dnat && !chain.dnat) || (!dnat && !chain.snat) {
+            continue;
+        }
+        if !nat_interfaces_match(config_items, &nattable.chain, &flow.iif, &flow.oif)? {
+            continue;
+        }
+        for (index, rule) in nattable.rules.iter().enumerate() {
+            for m in expand_nat_rule(config_items, rule) {
+                if rule_matches(&m.rule, flow) {
+                    return Ok(Some((nattable.chain.clone(), index, m)));
+                }
+            }
+        }
+    }
+    return Ok(None);
+}
+
+// Translate an address of a nat rule to the address the packet gets, networks are not
+// supported as translation targets so the network address is used
+fn translated_address(address: &String) -> String {
+    let addr = address.split('/').next().unwrap_or("");
+    match addr.parse::<IpAddr>() {
+        Ok(ip) => return ip.to_string(),
+        Err(_e) => return String::from(addr),
+    }
+}
+
+// Follow a packet through prerouting nat, the filter hooks and postrouting nat
+pub fn simulate(
+    config_items: &ConfigurationItems,
+    packet: &Packet,
+    management_port: Option<u16>,
+) -> Result<SimulationResult, String> {
+    let ct_state = if packet.ct_state.len() == 0 {
+        String::from("new")
+    } else {
+        packet.ct_state.to_lowercase()
+    };
+    if !["new", "established", "related", "invalid"].contains(&ct_state.as_str()) {
+        return Err(format!("Invalid ct state {}", packet.ct_state));
+    }
+    if packet.saddr.parse::<IpAddr>().is_err() || packet.daddr.parse::<IpAddr>().is_err() {
+        return Err(String::from("Source and destination must be ip addresses"));
+    }
+    let mut flow = Flow {
+        iif: system_interface(config_items, &packet.iif),
+        oif: system_interface(config_items, &packet.oif),
+        saddr: packet.saddr.clone(),
+        daddr: packet.daddr.clone(),
+        protocol: packet.protocol.to_lowercase(),
+        sport: packet.sport,
+        dport: packet.dport,
+        icmptype: packet.icmptype,
+    };
+    let direction = if flow.iif.len() == 0 {
+        "output"
+    } else if flow.oif.len() == 0 {
+        "input"
+    } else {
+        "forward"
+    };
+    let mut result = SimulationResult {
+        direction: String::from(direction),
+        verdict: String::from(""),
+        chain: String::from(""),
+        rule: None,
+        reason: String::from(""),
+        dnat: None,
+        snat: None,
+    };
+
+    // nat rules are only consulted for the first packet of a connection,
+    // the dnat chains hook into prerouting where the output interface is not known yet
+    if ct_state == "new" && direction != "output" {
+        let prerouting = Flow {
+            oif: String::from(""),
+            ..flow.clone()
+        };
+        if let Some((chain, index, m)) = nat_lookup(config_items, &config_items.dnat, true, &prerouting)? {
+            let translation = Translation {
+                chain: chain,
+                rule: index,
+                address: m.address.as_ref().map(translated_address).unwrap_or(flow.daddr.clone()),
+                port: m.port.and_then(|p| p.parse().ok()).or(flow.dport),
+            };
+            flow.daddr = translation.address.clone();
+            flow.dport = translation.port;
+            result.dnat = Some(translation);
+        }
+    }
+
+    let verdict = filter_verdict(config_items, &flow, &ct_state, direction, management_port)?;
+    result.verdict = verdict.verdict;
+    result.chain = verdict.chain;
+    result.rule = verdict.rule;
+    result.reason = verdict.reason;
+    if result.verdict != "accept" {
+        return Ok(result);
+    }
+
+    if ct_state == "new" && direction != "input" {
+        if let Some((chain, index, m)) = nat_lookup(config_items, &config_items.snat, false, &flow)? {
+            result.snat = Some(Translation {
+                chain: chain,
+                rule: index,
+                address: m.address.as_ref().map(translated_address).unwrap_or(flow.saddr.clone()),
+                port: m.port.and_then(|p| p.parse().ok()).or(flow.sport),
+            });
+        }
+    }
+    return Ok(result);
+}
+
+pub async fn simulate_packet(
+    session: Session,
+    State(state): State<Arc<AppState>>,
+    extract::Json(payload): extract::Json<SimulationRequest>,
+) -> Result<Json<SimulationResult>, (StatusCode, String)> {
+    if !check_session(session, &state).await {
+        return Err((StatusCode::UNAUTHORIZED, String::from("Not logged in")));
+    }
+    let config_items: ConfigurationItems = match serde_json::from_str(&payload.json) {
+        Ok(c) => c,
+        Err(e) => return Err((StatusCode::BAD_REQUEST, format!("Invalid configuration: {}", e))),
+    };
+    let port = state.settings.lock().await.connection.port;
+    match simulate(&config_items, &payload.packet, Some(port)) {
+        Ok(result) => return Ok(Json(result)),
+        Err(e) => return Err((StatusCode::BAD_REQUEST, e)),
+    }
+}
+
+// Command line interface:
+// webserver simulate <configuration file> saddr=<ip> daddr=<ip> protocol=<proto> [iif=..] [oif=..]
+//     [sport=..] [dport=..] [icmptype=..] [state=new|established|related|invalid]
+//     [management_port=..]
+// The management rule is only simulated with the port of the webserver as management_port
+pub fn cli(args: &[String]) -> i32 {
+    if args.len() < 2 {
+        eprintln!(
+            "Usage: webserver simulate <configuration file> saddr=<ip> daddr=<ip> protocol=<tcp|udp|icmp|icmpv6> [iif=<interface>] [oif=<interface>] [sport=<port>] [dport=<port>] [icmptype=<type>] [state=<ct state>] [management_port=<port>]"
+        );
+        return 2;
+    }
+    let json = match std::fs::read_to_string(&args[0]) {
+        Ok(j) => j,
+        Err(e) => {
+            eprintln!("Could not read configuration file {}: {}", args[0], e);
+            return 2;
+        }
+    };
+    let config_items: ConfigurationItems = match serde_json::from_str(&json) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("Invalid configuration: {}", e);
+            return 2;
+        }
+    };
+    let mut packet = Packet::default();
+    let mut management_port: Option<u16> = None;
+    for arg in args[1..].iter() {
+        let Some((key, value)) = arg.split_once('=') else {
+            eprintln!("Invalid argument {}", arg);
+            return 2;
+        };
+        let port = value.parse::<u16>().ok();
+        let icmptype = value.parse::<u8>().ok();
+        match key {
+            "sport" | "dport" | "management_port" if port.is_none() => {
+                eprintln!("Invalid port {}", value);
+                return 2;
+            }
+            "icmptype" if icmptype.is_none() => {
+                eprintln!("Invalid icmp type {}", value);
+                return 2;
+            }
+            "iif" => packet.iif = String::from(value),
+            "oif" => packet.oif = String::from(value),
+            "saddr" => packet.saddr = String::from(value),
+            "daddr" => packet.daddr = String::from(value),
+            "protocol" => packet.protocol = String::from(value),
+            "sport" => packet.sport = port,
+            "dport" => packet.dport = port,
+            "icmptype" => packet.icmptype = icmptype,
+            "state" => packet.ct_state = String::from(value),
+            "management_port" => management_port = port,
+            _ => {
+                eprintln!("Unknown argument {}", key);
+                return 2;
+            }
+        }
+    }
+    match simulate(&config_items, &packet, management_port) {
+        Ok(result) => {
+            println!("{}", serde_json::to_string_pretty(&result).unwrap());
+            if result.verdict == "accept" {
+                return 0;
+            }
+            return 1;
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            return 2;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdata;
+
+    fn packet(daddr: &str, dport: u16, ct_state: &str) -> Packet {
+        return Packet {
+            iif: String::from("lan"),
+            oif: String::from("dmz"),
+            saddr: String::from("10.0.0.2"),
+            daddr: String::from(daddr),
+            protocol: String::from("tcp"),
+            sport: Some(40000),
+            dport: Some(dport),
+            ct_state: String::from(ct_state),
+            ..Default::default()
+        };
+    }
+
+    #[test]
+    fn verdicts() {
+        let config_items = testdata::configuration();
+        let result = simulate(&config_items, &packet("192.0.2.10", 443, "new"), None).unwrap();
+        assert_eq!(result.direction, "forward");
+        assert_eq!(result.verdict, "accept");
+        assert_eq!(result.chain, "lan_dmz");
+        assert_eq!(result.rule, Some(0));
+
+        // no rule for the database, the policy of the chain applies
+        let result = simulate(&config_items, &packet("192.0.2.20", 5432, "new"), None).unwrap();
+        assert_eq!(result.verdict, "drop");
+        assert_eq!(result.rule, None);
+
+        let result = simulate(&config_items, &packet("192.0.2.10", 22, "new"), None).unwrap();
+        assert_eq!(result.verdict, "drop");
+
+        let mut invalid = packet("192.0.2.10", 443, "new");
+        invalid.daddr = String::from("web");
+        assert!(simulate(&config_items, &invalid, None).is_err());
+    }
+
+    #[test]
+    fn management_rule() {
+        let mut config_items = testdata::configuration();
+        testdata::drop_input(&mut config_items, "lan_in", "lan");
+        let mut webserver = packet("10.0.0.1", 443, "new");
+        webserver.oif = String::from("");
+        let result = simulate(&config_items, &webserver, Some(443)).unwrap();
+        assert_eq!(result.direction, "input");
+        assert_eq!(result.verdict, "drop");
+
+        // the management rule only accepts the port of the webserver, when it is known
+        config_items.management_rule = true;
+        let result = simulate(&config_items, &webserver, Some(443)).unwrap();
+        assert_eq!(result.verdict, "accept");
+        assert_eq!(result.chain, "all_input");
+        assert_eq!(result.reason, "management rule");
+        assert_eq!(simulate(&config_items, &webserver, Some(8443)).unwrap().verdict, "drop");
+        assert_eq!(simulate(&config_items, &webserver, None).unwrap().verdict, "drop");
+    }
+
+    #[test]
+    fn cli_rejects_invalid_ports() {
+        let mut path = std::env::temp_dir();
+        path.push(format!("simulator-cli-{}.json", std::process::id()));
+        std::fs::write(&path, testdata::json(&testdata::configuration())).unwrap();
+        let args = |dport: &str| {
+            return [
+                path.to_string_lossy().to_string(),
+                String::from("iif=lan"),
+                String::from("oif=dmz"),
+                String::from("saddr=10.0.0.2"),
+                String::from("daddr=192.0.2.10"),
+                String::from("protocol=tcp"),
+                format!("dport={}", dport),
+            ];
+        };
+        assert_eq!(cli(&args("443")), 0);
+        assert_eq!(cli(&args("22")), 1);
+        assert_eq!(cli(&args("https")), 2);
+        assert_eq!(cli(&args("65536")), 2);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,303 @@
+use serde::{Deserialize, Serialize};
+
+// The part of the configuration a generated script line comes from, filter and nat rules
+// by the id stored with the rule, so that inserting or removing rules does not change it
+#[derive(Clone, Deserialize, Serialize, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Origin {
+    Generated,
+    Pre { line: usize },
+    Post { line: usize },
+    Default { name: String, chain: Option<String> },
+    Chain { chain: String },
+    FilterRule { chain: String, id: String },
+    Policy { chain: String },
+    Jump { chain: String },
+    Snat { chain: String, id: String },
+    Dnat { chain: String, id: String },
+}
+
+// Rule ids are stored in the nft comment of generated rules, e.g. "nb:filter:wan_in:3"
+const RULE_ID_PREFIX: &str = "nb";
+
+impl Origin {
+    // Stable id of the configuration item a rule is generated from, lines that
+    // are not rules of the configuration (chain headers, user snippets) have none
+    pub fn rule_id(&self) -> Option<String> {
+        let id = match self {
+            Origin::Default { name, chain: Some(chain) } => format!("default:{}:{}", name, chain),
+            Origin::Default { name, chain: None } => format!("default:{}", name),
+            Origin::FilterRule { chain, id } => format!("filter:{}:{}", chain, id),
+            Origin::Policy { chain } => format!("policy:{}", chain),
+            Origin::Jump { chain } => format!("jump:{}", chain),
+            Origin::Snat { chain, id } => format!("snat:{}:{}", chain, id),
+            Origin::Dnat { chain, id } => format!("dnat:{}:{}", chain, id),
+            _ => return None,
+        };
+        return Some(format!("{}:{}", RULE_ID_PREFIX, id));
+    }
+
+    // Parse a rule id back into the origin it was created from. Default names and rule
+    // ids never contain ':', chain names may.
+    pub fn from_rule_id(id: &str) -> Option<Origin> {
+        let (prefix, rest) = id.split_once(':')?;
+        if prefix != RULE_ID_PREFIX {
+            return None;
+        }
+        let (kind, rest) = rest.split_once(':')?;
+        if rest.len() == 0 {
+            return None;
+        }
+        let rule = || {
+            return rest
+                .rsplit_once(':')
+                .filter(|(chain, id)| chain.len() > 0 && valid_rule_id(id))
+                .map(|(chain, id)| (String::from(chain), String::from(id)));
+        };
+        match kind {
+            "default" => {
+                let (name, chain) = match rest.split_once(':') {
+                    Some((name, chain)) => (name, Some(String::from(chain))),
+                    None => (rest, None),
+                };
+                return Some(Origin::Default {
+                    name: String::from(name),
+                    chain: chain,
+                });
+            }
+            "filter" => {
+                let (chain, id) = rule()?;
+                return Some(Origin::FilterRule { chain, id });
+            }
+            "policy" => return Some(Origin::Policy { chain: String::from(rest) }),
+            "jump" => return Some(Origin::Jump { chain: String::from(rest) }),
+            "snat" => {
+                let (chain, id) = rule()?;
+                return Some(Origin::Snat { chain, id });
+            }
+            "dnat" => {
+                let (chain, id) = rule()?;
+                return Some(Origin::Dnat { chain, id });
+            }
+            _ => return None,
+        }
+    }
+}
+
+// Rule ids are written into the quoted nft comment and must not contain ':'
+fn valid_rule_id(id: &str) -> bool {
+    return id.len() > 0
+        && id.len() <= 32
+        && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+}
+
+// Give the rules of a table that have no id, an invalid one or the id of an earlier rule
+// the next free number, the ids of all other rules stay as they are
+pub fn assign_rule_ids(mut ids: Vec<&mut String>) {
+    let mut next = ids
+        .iter()
+        .filter_map(|id| id.parse::<u64>().ok())
+        .max()
+        .unwrap_or(0)
+        + 1;
+    let mut seen: Vec<String> = vec![];
+    for id in ids.iter_mut() {
+        if !valid_rule_id(id) || seen.contains(id) {
+            **id = next.to_string();
+            next += 1;
+        }
+        seen.push(id.to_string());
+    }
+}
+
+// Check whether a script line is a rule statement and not part of a table, chain
+// or set declaration
+fn is_rule(line: &str) -> bool {
+    let l = line.trim();
+    return !(l.len() == 0
+        || l.starts_with('#')
+        || l.starts_with('}')
+        || l.ends_with('{')
+        || l.starts_with("set ")
+        || l.starts_with("type ")
+        || l.starts_with("timeout ")
+        || l.starts_with("flags "));
+}
+
+// Generated script lines together with the origin of every line
+pub struct Script {
+    pub lines: Vec<String>,
+    pub origins: Vec<Origin>,
+    // origin given to lines pushed from now on
+    pub origin: Origin,
+}
+
+impl Script {
+    pub fn new() -> Script {
+        return Script {
+            lines: vec![],
+            origins: vec![],
+            origin: Origin::Generated,
+        };
+    }
+
+    pub fn push(&mut self, line: String) {
+        self.lines.push(line);
+        self.origins.push(self.origin.clone());
+    }
+
+    pub fn insert(&mut self, index: usize, line: String, origin: Origin) {
+        self.lines.insert(index, line);
+        self.origins.insert(index, origin);
+    }
+
+    // Add the rule id as nft comment to every generated rule, in front of the
+    // "# comment" that nft discards when loading the script
+    pub fn add_rule_ids(&mut self) {
+        for (line, origin) in self.lines.iter_mut().zip(self.origins.iter()) {
+            let Some(id) = origin.rule_id() else {
+                continue;
+            };
+            if !is_rule(line) {
+                continue;
+            }
+            let (statement, remark) = match line.find('#') {
+                Some(pos) => (line[..pos].trim_end().to_string(), format!(" {}", &line[pos..])),
+                None => (line.trim_end().to_string(), String::from("")),
+            };
+            *line = format!("{} comment \"{}\"{}", statement, id, remark);
+        }
+    }
+
+    // origin of a 1-based line number as used in nft error messages
+    pub fn origin_of(&self, line: usize) -> Option<&Origin> {
+        if line == 0 {
+            return None;
+        }
+        return self.origins.get(line - 1);
+    }
+}
+
+// An nft error or warning mapped back to the configuration
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Diagnostic {
+    pub line: usize,
+    pub columns: String,
+    pub severity: String,
+    pub message: String,
+    pub source: String,
+    pub origin: Option<Origin>,
+}
+
+// Parse nft messages of the form "<file>:<line>:<columns>: Error: <message>",
+// only lines of the script file itself can be mapped, not those of included files
+pub fn diagnostics(script: &Script, file: &str, stderr: &str) -> Vec<Diagnostic> {
+    let mut result: Vec<Diagnostic> = vec![];
+    for l in stderr.lines() {
+        let mut found: Option<(usize, &str)> = None;
+        for severity in ["Error", "Warning"] {
+            if let Some(pos) = l.find(&format!(": {}: ", severity)) {
+                found = Some((pos, severity));
+                break;
+            }
+        }
+        let Some((pos, severity)) = found else {
+            continue;
+        };
+        let message = &l[pos + severity.len() + 4..];
+        let location: Vec<&str> = l[..pos].rsplitn(3, ':').collect();
+        if location.len() != 3 {
+            continue;
+        }
+        let Ok(line) = location[1].parse::<usize>() else {
+            continue;
+        };
+        let own = location[2] == file;
+        result.push(Diagnostic {
+            line: line,
+            columns: String::from(location[0]),
+            severity: severity.to_lowercase(),
+            message: String::from(message),
+            source: match own {
+                true => script.lines.get(line.wrapping_sub(1)).cloned().unwrap_or_default(),
+                false => String::from(location[2]),
+            },
+            origin: match own {
+                true => script.origin_of(line).cloned(),
+                false => None,
+            },
+        });
+    }
+    return result;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rule_ids_round_trip() {
+        let origins = [
+            Origin::FilterRule { chain: String::from("wan:in"), id: String::from("7") },
+            Origin::Snat { chain: String::from("lan_wan"), id: String::from("2") },
+            Origin::Default { name: String::from("ICMP"), chain: Some(String::from("a:b")) },
+            Origin::Default { name: String::from("ICMP"), chain: None },
+            Origin::Policy { chain: String::from("x:y") },
+        ];
+        for origin in origins {
+            let id = origin.rule_id().unwrap();
+            assert!(Origin::from_rule_id(&id) == Some(origin));
+        }
+        assert!(Origin::from_rule_id("nb:filter:wan_in").is_none());
+        assert!(Origin::from_rule_id("xx:policy:wan_in").is_none());
+    }
+
+    #[test]
+    fn assign_rule_ids_keeps_existing_ids() {
+        let mut ids = vec![
+            String::from("3"),
+            String::from(""),
+            String::from("3"),
+            String::from("a:b"),
+            String::from("1"),
+        ];
+        assign_rule_ids(ids.iter_mut().collect());
+        assert_eq!(ids, ["3", "4", "5", "6", "1"]);
+    }
+
+    #[test]
+    fn diagnostics_of_nft_errors() {
+        let rule = Origin::FilterRule { chain: String::from("lan_dmz"), id: String::from("1") };
+        let mut script = Script::new();
+        script.push(String::from("table inet filter_inet {"));
+        script.push(String::from("    chain lan_dmz {"));
+        script.origin = rule.clone();
+        script.push(String::from("        ip daddr 192.0.2.10 tcp dport 4430-443 accept"));
+        script.origin = Origin::Generated;
+        script.push(String::from("    }"));
+        script.push(String::from("}"));
+        script.push(String::from("include \"/etc/nftables.d/*.nft\""));
+
+        // captured from nft -c -f, the carets and the source lines are skipped
+        let stderr = "/etc/nftables.test:3:37-44: Error: Range negative size
+        ip daddr 192.0.2.10 tcp dport 4430-443 accept
+                                    ^^^^^^^^
+In file included from /etc/nftables.test:6:1-33:
+/etc/nftables.d/local.nft:2:9-13: Warning: table name is too long
+";
+        let result = diagnostics(&script, "/etc/nftables.test", stderr);
+        assert_eq!(result.len(), 2);
+        assert_eq!(result[0].line, 3);
+        assert_eq!(result[0].columns, "37-44");
+        assert_eq!(result[0].severity, "error");
+        assert_eq!(result[0].message, "Range negative size");
+        assert_eq!(result[0].source, script.lines[2]);
+        assert!(result[0].origin == Some(rule));
+
+        // lines of included files are not lines of the script
+        assert_eq!(result[1].line, 2);
+        assert_eq!(result[1].severity, "warning");
+        assert_eq!(result[1].source, "/etc/nftables.d/local.nft");
+        assert!(result[1].origin.is_none());
+    }
+}
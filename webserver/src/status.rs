@@ -0,0 +1,50 @@
+use crate::drift::DriftReport;
+use crate::{check_session, helper_request, AppState};
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::Json;
+use protocol::{HelperStatus, Request, Response};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tower_sessions::Session;
+
+#[derive(Deserialize, Serialize)]
+pub struct Status {
+    // minutes between scheduled drift checks, 0 when not scheduled
+    pub drift_interval: u64,
+    pub drift: Option<DriftReport>,
+    // unix time at which the last install is rolled back unless it is confirmed
+    pub confirm_deadline: Option<i64>,
+    // state of the main process, None when it did not respond
+    pub helper: Option<HelperStatus>,
+}
+
+pub async fn status(
+    session: Session,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<Status>, StatusCode> {
+    if !check_session(session, &state).await {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+    let helper = match helper_request(&state, Request::Status).await {
+        Ok(Response::Status { status }) => Some(status),
+        _ => None,
+    };
+    let now = time::OffsetDateTime::now_utc().unix_timestamp();
+    // the timer of the main process rolled back an install that was not confirmed
+    if helper.as_ref().is_some_and(|h| !h.confirm_pending) {
+        let mut deadline = state.confirm_deadline.lock().await;
+        if deadline.is_some_and(|d| d <= now) {
+            *deadline = None;
+            *state.pending_install.lock().await = None;
+        }
+    }
+    let confirm_deadline = state.confirm_deadline.lock().await.filter(|d| *d > now);
+    let drift = state.drift.lock().await;
+    return Ok(Json(Status {
+        drift_interval: drift.interval,
+        drift: drift.report.clone(),
+        confirm_deadline: confirm_deadline,
+        helper: helper,
+    }));
+}
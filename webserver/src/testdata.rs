@@ -0,0 +1,89 @@
+use crate::{ChainData, ConfigurationItems, FilterRuleData, FilterTableData};
+
+// A small configuration for the tests: the office network on lan may reach the web
+// server in the dmz with https, everything else from lan to the dmz is dropped
+const CONFIGURATION: &str = r#"{
+    "interfaces": {
+        "lan": {"systemname": "eth0", "addresses": "", "loopback": false},
+        "dmz": {"systemname": "eth1", "addresses": "", "loopback": false}
+    },
+    "hosts": {
+        "pc1": {"ipv4": ["10.0.0.2"], "ipv6": []},
+        "web": {"ipv4": ["192.0.2.10"], "ipv6": ["2001:db8::10"]},
+        "db": {"ipv4": ["192.0.2.20"], "ipv6": []}
+    },
+    "hostgroups": {},
+    "ipv4networks": {"office": "10.0.0.0/24"},
+    "ipv6networks": {},
+    "services": {
+        "https": {"port": 443, "protocol": "TCP", "default": false},
+        "pg": {"port": 5432, "protocol": "TCP", "default": false}
+    },
+    "chains": {
+        "lan_dmz": {"filter": true, "snat": false, "dnat": false, "iface_in": "lan",
+            "iface_out": "dmz", "direction": "forward", "policy": "drop"}
+    },
+    "inactive_defaults": [],
+    "filters": {
+        "filtertables": [{"chain": "lan_dmz", "policy": "drop", "deleted": false, "rules": [
+            {"source": ["office"], "sourceservice": [], "destination": ["web"],
+                "destinationservice": ["https"], "action": "accept", "comment": "",
+                "active": true}
+        ]}],
+        "dragpos": []
+    },
+    "pre": "",
+    "post": "",
+    "snat": {"nattables": [], "dragpos": []},
+    "dnat": {"nattables": [], "dragpos": []},
+    "logging": "",
+    "checksdragpos": {"top": 0, "left": 0}
+}"#;
+
+pub fn configuration() -> ConfigurationItems {
+    return serde_json::from_str(CONFIGURATION).unwrap();
+}
+
+pub fn json(config_items: &ConfigurationItems) -> String {
+    return serde_json::to_string(config_items).unwrap();
+}
+
+// An active filter rule from names of addresses and services
+pub fn rule(
+    source: &[&str],
+    destination: &[&str],
+    service: &[&str],
+    action: &str,
+) -> FilterRuleData {
+    let names = |n: &[&str]| n.iter().map(|s| String::from(*s)).collect();
+    return FilterRuleData {
+        source: names(source),
+        sourceservice: vec![],
+        destination: names(destination),
+        destinationservice: names(service),
+        action: String::from(action),
+        comment: String::from(""),
+        active: true,
+        id: String::from(""),
+    };
+}
+
+// An input chain that drops everything the firewall itself receives on iface
+pub fn drop_input(config_items: &mut ConfigurationItems, chain: &str, iface: &str) {
+    config_items.chains.insert(
+        String::from(chain),
+        ChainData {
+            filter: true,
+            iface_in: String::from(iface),
+            direction: String::from("input"),
+            policy: String::from("drop"),
+            ..Default::default()
+        },
+    );
+    config_items.filters.filtertables.push(FilterTableData {
+        chain: String::from(chain),
+        policy: String::from("drop"),
+        rules: vec![],
+        deleted: false,
+    });
+}
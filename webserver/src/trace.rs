@@ -0,0 +1,244 @@
+use crate::sourcemap::Origin;
+use crate::{check_session, helper_command, AppState};
+use axum::extract::{self, State};
+use axum::http::StatusCode;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use futures_util::stream::{self, Stream};
+use protocol::{Request, TraceMatch};
+use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::Duration;
+use tower_sessions::Session;
+
+const DEFAULT_TRACE_SECONDS: u64 = 60;
+
+// The flow to trace, fields that are not given match any packet
+#[derive(Default, Deserialize, Serialize)]
+pub struct TraceRequest {
+    #[serde(default)]
+    pub iif: String,
+    #[serde(default)]
+    pub saddr: String,
+    #[serde(default)]
+    pub daddr: String,
+    #[serde(default)]
+    pub protocol: String,
+    #[serde(default)]
+    pub sport: Option<u16>,
+    #[serde(default)]
+    pub dport: Option<u16>,
+    #[serde(default)]
+    pub seconds: Option<u64>,
+}
+
+// One line of nft monitor trace output. kind is "packet", "rule", "verdict" or "policy",
+// or "error" with the message in detail for errors of nft monitor.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct TraceEvent {
+    pub id: String,
+    pub family: String,
+    pub table: String,
+    pub chain: String,
+    pub kind: String,
+    pub detail: String,
+    pub verdict: Option<String>,
+    pub rule_id: Option<String>,
+    pub origin: Option<Origin>,
+}
+
+// Parse lines like
+// trace id 6a2c0b1f inet filter_inet wan_in rule tcp dport 22 accept comment "nb:filter:wan_in:3" (verdict accept)
+// and the errors the main process reports as "error: <message>"
+pub fn parse_trace_line(line: &str) -> Option<TraceEvent> {
+    if let Some(message) = line.strip_prefix("error: ") {
+        return Some(TraceEvent {
+            id: String::new(),
+            family: String::new(),
+            table: String::new(),
+            chain: String::new(),
+            kind: String::from("error"),
+            detail: String::from(message),
+            verdict: None,
+            rule_id: None,
+            origin: None,
+        });
+    }
+    let parts: Vec<&str> = line.trim().splitn(7, ' ').collect();
+    if parts.len() != 7 || parts[0] != "trace" || parts[1] != "id" {
+        return None;
+    }
+    let rest = parts[6];
+    let (kind, detail) = rest.split_once(' ').unwrap_or((rest, ""));
+    let mut event = TraceEvent {
+        id: String::from(parts[2]),
+        family: String::from(parts[3]),
+        table: String::from(parts[4]),
+        chain: String::from(parts[5]),
+        kind: String::from(kind.trim_end_matches(':')),
+        detail: String::from(detail),
+        verdict: None,
+        rule_id: None,
+        origin: None,
+    };
+    match event.kind.as_str() {
+        "rule" => {
+            if let Some(pos) = detail.rfind(" (verdict ") {
+                event.detail = String::from(&detail[..pos]);
+                event.verdict = Some(String::from(detail[pos + 10..].trim_end_matches(')')));
+            }
+            if let Some(pos) = event.detail.find("comment \"") {
+                let comment = &event.detail[pos + 9..];
+                event.rule_id = comment.split('"').next().map(String::from);
+                event.origin = event.rule_id.as_deref().and_then(Origin::from_rule_id);
+            }
+        }
+        "verdict" | "policy" => event.verdict = Some(String::from(detail)),
+        _ => {}
+    }
+    return Some(event);
+}
+
+pub async fn start_trace(
+    session: Session,
+    State(state): State<Arc<AppState>>,
+    extract::Json(payload): extract::Json<TraceRequest>,
+) -> Result<String, (StatusCode, String)> {
+    if !check_session(session, &state).await {
+        return Err((StatusCode::UNAUTHORIZED, String::from("Not logged in")));
+    }
+    // empty fields match any packet
+    let optional = |value: &String| match value.len() {
+        0 => None,
+        _ => Some(value.clone()),
+    };
+    let request = Request::TraceStart {
+        seconds: payload.seconds.unwrap_or(DEFAULT_TRACE_SECONDS),
+        trace: TraceMatch {
+            iif: optional(&payload.iif),
+            saddr: optional(&payload.saddr),
+            daddr: optional(&payload.daddr),
+            protocol: optional(&payload.protocol.to_lowercase()),
+            sport: payload.sport,
+            dport: payload.dport,
+        },
+    };
+    let response = helper_command(&state, request).await;
+    if response != "OK" {
+        return Err((StatusCode::BAD_REQUEST, response));
+    }
+    return Ok(response);
+}
+
+pub async fn stop_trace(
+    session: Session,
+    State(state): State<Arc<AppState>>,
+) -> Result<String, (StatusCode, String)> {
+    if !check_session(session, &state).await {
+        return Err((StatusCode::UNAUTHORIZED, String::from("Not logged in")));
+    }
+    let response = helper_command(&state, Request::TraceStop).await;
+    if response != "OK" {
+        return Err((StatusCode::INTERNAL_SERVER_ERROR, response));
+    }
+    return Ok(response);
+}
+
+// Stream the trace as server sent events: "trace" events with a list of
+// decoded lines, and a final "end" event when the trace has stopped
+pub async fn trace_events(
+    session: Session,
+    State(state): State<Arc<AppState>>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, StatusCode> {
+    if !check_session(session, &state).await {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+    let events = stream::unfold((state, false), |(state, finished)| async move {
+        if finished {
+            return None;
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let response = helper_command(&state, Request::TraceRead).await;
+        let mut lines = response.lines();
+        let running = lines.next() == Some("RUNNING");
+        let decoded: Vec<TraceEvent> = lines.filter_map(parse_trace_line).collect();
+        let event = if decoded.len() == 0 && !running {
+            Event::default().event("end").data("")
+        } else {
+            Event::default()
+                .event("trace")
+                .data(serde_json::to_string(&decoded).unwrap())
+        };
+        // send the remaining lines before ending the stream
+        Some((Ok(event), (state, decoded.len() == 0 && !running)))
+    });
+    return Ok(Sse::new(events).keep_alive(KeepAlive::default()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // captured with nft monitor trace
+    const PACKET: &str = r#"trace id 6a2c0b1f inet filter_inet all_forward packet: iif "eth0" oif "eth1" ether saddr 52:54:00:12:34:56 ether daddr 52:54:00:ab:cd:ef ip saddr 10.0.0.2 ip daddr 192.0.2.10 ip dscp cs0 ip ecn not-ect ip ttl 63 ip id 54321 ip length 60 tcp sport 40000 tcp dport 443 tcp flags == syn tcp window 64240"#;
+    const JUMP: &str = r#"trace id 6a2c0b1f inet filter_inet all_forward rule iifname "eth0" oifname "eth1" jump lan_dmz (verdict jump lan_dmz)"#;
+    const RULE: &str = r#"trace id 6a2c0b1f inet filter_inet lan_dmz rule ip saddr 10.0.0.0/24 ip daddr 192.0.2.10 tcp dport 443 counter packets 12 bytes 720 accept comment "nb:filter:lan_dmz:1" (verdict accept)"#;
+    const POLICY: &str = "trace id 7d1e3a90 inet filter_inet lan_dmz policy drop";
+    const VERDICT: &str =
+        "trace id 7d1e3a90 inet nftablesbuilder_trace prerouting verdict continue";
+
+    #[test]
+    fn packet_line() {
+        let event = parse_trace_line(PACKET).unwrap();
+        assert_eq!(event.id, "6a2c0b1f");
+        assert_eq!(event.family, "inet");
+        assert_eq!(event.table, "filter_inet");
+        assert_eq!(event.chain, "all_forward");
+        assert_eq!(event.kind, "packet");
+        assert!(event.detail.starts_with("iif \"eth0\" oif \"eth1\""));
+        assert_eq!(event.verdict, None);
+    }
+
+    #[test]
+    fn rule_lines() {
+        let event = parse_trace_line(JUMP).unwrap();
+        assert_eq!(event.kind, "rule");
+        assert_eq!(
+            event.detail,
+            "iifname \"eth0\" oifname \"eth1\" jump lan_dmz"
+        );
+        assert_eq!(event.verdict.as_deref(), Some("jump lan_dmz"));
+        assert_eq!(event.rule_id, None);
+
+        // the rule is found by the id in its comment
+        let event = parse_trace_line(RULE).unwrap();
+        assert_eq!(event.chain, "lan_dmz");
+        assert_eq!(event.verdict.as_deref(), Some("accept"));
+        assert_eq!(event.rule_id.as_deref(), Some("nb:filter:lan_dmz:1"));
+        let origin = Origin::FilterRule {
+            chain: String::from("lan_dmz"),
+            id: String::from("1"),
+        };
+        assert!(event.origin == Some(origin));
+    }
+
+    #[test]
+    fn policy_and_verdict_lines() {
+        let event = parse_trace_line(POLICY).unwrap();
+        assert_eq!(event.kind, "policy");
+        assert_eq!(event.verdict.as_deref(), Some("drop"));
+        let event = parse_trace_line(VERDICT).unwrap();
+        assert_eq!(event.kind, "verdict");
+        assert_eq!(event.table, "nftablesbuilder_trace");
+        assert_eq!(event.verdict.as_deref(), Some("continue"));
+    }
+
+    #[test]
+    fn errors_and_other_lines() {
+        let event = parse_trace_line("error: Error: Operation not permitted").unwrap();
+        assert_eq!(event.kind, "error");
+        assert_eq!(event.detail, "Error: Operation not permitted");
+        assert!(parse_trace_line("RUNNING").is_none());
+        assert!(parse_trace_line("trace id 6a2c0b1f inet").is_none());
+    }
+}
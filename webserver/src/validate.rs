@@ -0,0 +1,581 @@
+use crate::rules::{address_range, addresses_from_defs};
+use crate::{
+    check_session, icmpv4_types_from_services, icmpv6_types_from_services,
+    ipv4addresses_from_def, ipv6addresses_from_def, AppState, Configuration,
+    ConfigurationItems, NatData,
+};
+use axum::extract::{self, State};
+use axum::http::StatusCode;
+use axum::Json;
+use network_interface::{NetworkInterface, NetworkInterfaceConfig};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tower_sessions::Session;
+
+#[derive(Clone, Copy, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Clone, Copy, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum IssueCode {
+    InvalidJson,
+    NoInterfaces,
+    UnknownSystemInterface,
+    UndefinedInterface,
+    UndefinedChain,
+    InvalidAddress,
+    UndefinedAddress,
+    UndefinedService,
+    UndefinedTranslation,
+    AddressFamilyMismatch,
+    MultipleTranslations,
+    MissingTranslation,
+}
+
+// A problem in the configuration, path points at the offending value
+// (for example filters.filtertables[0].rules[2].source[1])
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Issue {
+    pub severity: Severity,
+    pub code: IssueCode,
+    pub path: String,
+    pub message: String,
+}
+
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct ValidationReport {
+    pub valid: bool,
+    pub errors: Vec<Issue>,
+    pub warnings: Vec<Issue>,
+}
+
+impl ValidationReport {
+    fn error(&mut self, code: IssueCode, path: String, message: String) {
+        self.errors.push(Issue {
+            severity: Severity::Error,
+            code: code,
+            path: path,
+            message: message,
+        });
+    }
+
+    fn warning(&mut self, code: IssueCode, path: String, message: String) {
+        self.warnings.push(Issue {
+            severity: Severity::Warning,
+            code: code,
+            path: path,
+            message: message,
+        });
+    }
+
+    // all messages, errors first, as shown in the install result
+    pub fn messages(&self) -> Vec<String> {
+        let mut messages: Vec<String> = vec![];
+        for issue in self.errors.iter().chain(self.warnings.iter()) {
+            messages.push(format!("{}: {}", issue.path, issue.message));
+        }
+        return messages;
+    }
+}
+
+// the names of the interfaces present on the system
+pub fn system_interfaces() -> Vec<String> {
+    let mut names: Vec<String> = vec![];
+    if let Ok(interfaces) = NetworkInterface::show() {
+        for itf in interfaces.iter() {
+            if !names.contains(&itf.name) {
+                names.push(itf.name.clone());
+            }
+        }
+    }
+    return names;
+}
+
+fn address_defined(config_items: &ConfigurationItems, def: &String) -> bool {
+    return ipv4addresses_from_def(config_items, def).len() > 0
+        || ipv6addresses_from_def(config_items, def).len() > 0;
+}
+
+fn service_defined(config_items: &ConfigurationItems, def: &String, icmp: bool) -> bool {
+    if config_items.services.contains_key(def) {
+        return true;
+    }
+    let svc = vec![def.clone()];
+    return icmp
+        && (icmpv4_types_from_services(&svc).len() > 0
+            || icmpv6_types_from_services(&svc).len() > 0);
+}
+
+fn check_addresses(config_items: &ConfigurationItems, report: &mut ValidationReport) {
+    let mut names: Vec<&String> = config_items.hosts.keys().collect();
+    names.sort();
+    for name in names {
+        let host = &config_items.hosts[name];
+        for (family, ips) in [("ipv4", &host.ipv4), ("ipv6", &host.ipv6)] {
+            for (index, ip) in ips.iter().enumerate() {
+                let valid = match address_range(ip) {
+                    Some((v6, _first, _last)) => v6 == (family == "ipv6"),
+                    None => false,
+                };
+                if !valid {
+                    report.error(
+                        IssueCode::InvalidAddress,
+                        format!("hosts.{}.{}[{}]", name, family, index),
+                        format!("Host {} has an invalid {} address {}.", name, family, ip),
+                    );
+                }
+            }
+        }
+    }
+    for (family, networks) in [
+        ("ipv4", &config_items.ipv4networks),
+        ("ipv6", &config_items.ipv6networks),
+    ] {
+        let mut names: Vec<&String> = networks.keys().collect();
+        names.sort();
+        for name in names {
+            let valid = match address_range(&networks[name]) {
+                Some((v6, _first, _last)) => v6 == (family == "ipv6"),
+                None => false,
+            };
+            if !valid {
+                report.error(
+                    IssueCode::InvalidAddress,
+                    format!("{}networks.{}", family, name),
+                    format!(
+                        "Network {} has an invalid {} network {}.",
+                        name, family, networks[name]
+                    ),
+                );
+            }
+        }
+    }
+}
+
+fn check_interfaces(
+    config_items: &ConfigurationItems,
+    system: Option<&Vec<String>>,
+    report: &mut ValidationReport,
+) {
+    if config_items.interfaces.len() == 0 {
+        report.error(
+            IssueCode::NoInterfaces,
+            String::from("interfaces"),
+            String::from("No interfaces defined."),
+        );
+    }
+    if let Some(system) = system {
+        let mut names: Vec<&String> = config_items.interfaces.keys().collect();
+        names.sort();
+        for name in names {
+            let systemname = &config_items.interfaces[name].systemname;
+            if !system.contains(systemname) {
+                report.error(
+                    IssueCode::UnknownSystemInterface,
+                    format!("interfaces.{}.systemname", name),
+                    format!("Interface {} not found.", systemname),
+                );
+            }
+        }
+    }
+    let mut names: Vec<&String> = config_items.chains.keys().collect();
+    names.sort();
+    for name in names {
+        let chain = &config_items.chains[name];
+        for (field, iface) in [("iface_in", &chain.iface_in), ("iface_out", &chain.iface_out)] {
+            if iface != "-" && !config_items.interfaces.contains_key(iface) {
+                report.error(
+                    IssueCode::UndefinedInterface,
+                    format!("chains.{}.{}", name, field),
+                    format!("Chain {} uses undefined interface {}.", name, iface),
+                );
+            }
+        }
+    }
+}
+
+fn check_filter_rules(config_items: &ConfigurationItems, report: &mut ValidationReport) {
+    for (t, table) in config_items.filters.filtertables.iter().enumerate() {
+        if table.deleted {
+            continue;
+        }
+        let tablepath = format!("filters.filtertables[{}]", t);
+        if !config_items.chains.contains_key(&table.chain) {
+            report.error(
+                IssueCode::UndefinedChain,
+                format!("{}.chain", tablepath),
+                format!("Filter table uses undefined chain {}.", table.chain),
+            );
+        }
+        for (index, rule) in table.rules.iter().enumerate() {
+            if !rule.active {
+                continue;
+            }
+            let rulepath = format!("{}.rules[{}]", tablepath, index);
+            for (field, defs, what) in [
+                ("source", &rule.source, "source"),
+                ("destination", &rule.destination, "destination"),
+            ] {
+                for (d, def) in defs.iter().enumerate() {
+                    if !address_defined(config_items, def) {
+                        report.error(
+                            IssueCode::UndefinedAddress,
+                            format!("{}.{}[{}]", rulepath, field, d),
+                            format!(
+                                "Rule {} in chain {} has an undefined or empty {} definition named {}.",
+                                index + 1,
+                                table.chain,
+                                what,
+                                def
+                            ),
+                        );
+                    }
+                }
+            }
+            for (field, defs, what) in [
+                ("sourceservice", &rule.sourceservice, "source"),
+                ("destinationservice", &rule.destinationservice, "destination"),
+            ] {
+                for (d, def) in defs.iter().enumerate() {
+                    if !service_defined(config_items, def, true) {
+                        report.error(
+                            IssueCode::UndefinedService,
+                            format!("{}.{}[{}]", rulepath, field, d),
+                            format!(
+                                "Rule {} in chain {} has an undefined {} service named {}.",
+                                index + 1,
+                                table.chain,
+                                what,
+                                def
+                            ),
+                        );
+                    }
+                }
+            }
+            let (ipv4s, ipv6s) = addresses_from_defs(config_items, &rule.source);
+            let (ipv4d, ipv6d) = addresses_from_defs(config_items, &rule.destination);
+            if ipv4s.len() > 0 && ipv6s.len() == 0 && ipv6d.len() > 0 && ipv4d.len() == 0 {
+                report.error(
+                    IssueCode::AddressFamilyMismatch,
+                    rulepath.clone(),
+                    format!(
+                        "Rule {} in chain {} has IPv4 source addresses but only IPv6 destination addresses.",
+                        index + 1,
+                        table.chain
+                    ),
+                );
+            }
+            if ipv6s.len() > 0 && ipv4s.len() == 0 && ipv4d.len() > 0 && ipv6d.len() == 0 {
+                report.error(
+                    IssueCode::AddressFamilyMismatch,
+                    rulepath.clone(),
+                    format!(
+                        "Rule {} in chain {} has IPv6 source addresses but only IPv4 destination addresses.",
+                        index + 1,
+                        table.chain
+                    ),
+                );
+            }
+        }
+    }
+}
+
+fn check_nat_rules(
+    config_items: &ConfigurationItems,
+    nat: &NatData,
+    kind: &str,
+    report: &mut ValidationReport,
+) {
+    let section = kind.to_lowercase();
+    let translated_what = if kind == "SNAT" { "source" } else { "destination" };
+    for (t, table) in nat.nattables.iter().enumerate() {
+        if table.deleted {
+            continue;
+        }
+        let tablepath = format!("{}.nattables[{}]", section, t);
+        if !config_items.chains.contains_key(&table.chain) {
+            report.error(
+                IssueCode::UndefinedChain,
+                format!("{}.chain", tablepath),
+                format!("{} table uses undefined chain {}.", kind, table.chain),
+            );
+        }
+        for (index, rule) in table.rules.iter().enumerate() {
+            if !rule.active {
+                continue;
+            }
+            let rulepath = format!("{}.rules[{}]", tablepath, index);
+            let prefix = format!("Rule {} in {} chain {}", index + 1, kind, table.chain);
+            for (field, defs, what) in [
+                ("source", &rule.source, "source"),
+                ("destination", &rule.destination, "destination"),
+            ] {
+                for (d, def) in defs.iter().enumerate() {
+                    if !address_defined(config_items, def) {
+                        report.error(
+                            IssueCode::UndefinedAddress,
+                            format!("{}.{}[{}]", rulepath, field, d),
+                            format!(
+                                "{} has an undefined or empty {} definition named {}.",
+                                prefix, what, def
+                            ),
+                        );
+                    }
+                }
+            }
+            if !address_defined(config_items, &rule.translated) {
+                report.error(
+                    IssueCode::UndefinedTranslation,
+                    format!("{}.translated", rulepath),
+                    format!(
+                        "{} has an undefined or empty translated {} definition named {}.",
+                        prefix, translated_what, rule.translated
+                    ),
+                );
+            }
+            for (field, defs, what) in [
+                ("sourceservice", &rule.sourceservice, "source"),
+                ("destinationservice", &rule.destinationservice, "destination"),
+            ] {
+                for (d, def) in defs.iter().enumerate() {
+                    if !service_defined(config_items, def, false) {
+                        report.error(
+                            IssueCode::UndefinedService,
+                            format!("{}.{}[{}]", rulepath, field, d),
+                            format!("{} has an undefined {} service named {}.", prefix, what, def),
+                        );
+                    }
+                }
+            }
+            if rule.translatedservice != ""
+                && !service_defined(config_items, &rule.translatedservice, false)
+            {
+                report.error(
+                    IssueCode::UndefinedService,
+                    format!("{}.translatedservice", rulepath),
+                    format!(
+                        "{} has an undefined translated service named {}.",
+                        prefix, rule.translatedservice
+                    ),
+                );
+            }
+
+            let (ipv4s, ipv6s) = addresses_from_defs(config_items, &rule.source);
+            let (ipv4d, ipv6d) = addresses_from_defs(config_items, &rule.destination);
+            let ipv4t = ipv4addresses_from_def(config_items, &rule.translated);
+            let ipv6t = ipv6addresses_from_def(config_items, &rule.translated);
+            let translated = format!("{}.translated", rulepath);
+            for (family, trans) in [("IPv4", &ipv4t), ("IPv6", &ipv6t)] {
+                if trans.len() > 1 {
+                    report.error(
+                        IssueCode::MultipleTranslations,
+                        translated.clone(),
+                        format!(
+                            "{} has multiple translated {} addresses. Only a single address per type is allowed.",
+                            prefix, family
+                        ),
+                    );
+                }
+            }
+            for (family, other, own_s, other_s, own_d, other_d, own_t) in [
+                ("IPv4", "IPv6", &ipv4s, &ipv6s, &ipv4d, &ipv6d, &ipv4t),
+                ("IPv6", "IPv4", &ipv6s, &ipv4s, &ipv6d, &ipv4d, &ipv6t),
+            ] {
+                if own_s.len() > 0 && other_s.len() == 0 {
+                    // pure source of this family
+                    if other_d.len() > 0 && own_d.len() == 0 {
+                        report.error(
+                            IssueCode::AddressFamilyMismatch,
+                            rulepath.clone(),
+                            format!(
+                                "{} has only {} source addresses but only {} destination addresses.",
+                                prefix, family, other
+                            ),
+                        );
+                    }
+                    if own_t.len() == 0 {
+                        report.error(
+                            IssueCode::MissingTranslation,
+                            translated.clone(),
+                            format!(
+                                "{} has only {} source addresses but no translated {} address.",
+                                prefix, family, family
+                            ),
+                        );
+                    }
+                }
+                if own_d.len() > 0 && other_d.len() == 0 && own_t.len() == 0 {
+                    report.error(
+                        IssueCode::MissingTranslation,
+                        translated.clone(),
+                        format!(
+                            "{} has only {} destination addresses but no translated {} address.",
+                            prefix, family, family
+                        ),
+                    );
+                }
+                if own_s.len() > 0 && own_t.len() == 0 {
+                    report.warning(
+                        IssueCode::MissingTranslation,
+                        translated.clone(),
+                        format!(
+                            "{} has {} source addresses but no translated {} address.",
+                            prefix, family, family
+                        ),
+                    );
+                }
+                if own_d.len() > 0 && own_t.len() == 0 {
+                    report.warning(
+                        IssueCode::MissingTranslation,
+                        translated.clone(),
+                        format!(
+                            "{} has {} destination addresses but no translated {} address.",
+                            prefix, family, family
+                        ),
+                    );
+                }
+            }
+        }
+    }
+}
+
+// Flow tests have to use defined interfaces, addresses and services
+fn check_flow_tests(config_items: &ConfigurationItems, report: &mut ValidationReport) {
+    for (t, test) in config_items.tests.iter().enumerate() {
+        for (field, iface) in [("iif", &test.iif), ("oif", &test.oif)] {
+            if iface.len() > 0 && !config_items.interfaces.contains_key(iface) {
+                report.error(
+                    IssueCode::UndefinedInterface,
+                    format!("tests[{}].{}", t, field),
+                    format!("Flow test {} uses undefined interface {}.", test.name, iface),
+                );
+            }
+        }
+        for (field, def) in [("source", &test.source), ("destination", &test.destination)] {
+            if address_range(def).is_none() && !address_defined(config_items, def) {
+                report.error(
+                    IssueCode::UndefinedAddress,
+                    format!("tests[{}].{}", t, field),
+                    format!("Flow test {} uses undefined address {}.", test.name, def),
+                );
+            }
+        }
+        let protocols = ["tcp", "udp", "icmp", "icmpv6"];
+        if test.service.len() == 0 && !protocols.contains(&test.protocol.to_lowercase().as_str()) {
+            report.error(
+                IssueCode::UndefinedService,
+                format!("tests[{}].protocol", t),
+                format!("Flow test {} needs a service or a protocol.", test.name),
+            );
+        }
+        if test.service.len() > 0 && !service_defined(config_items, &test.service, true) {
+            report.error(
+                IssueCode::UndefinedService,
+                format!("tests[{}].service", t),
+                format!("Flow test {} uses undefined service {}.", test.name, test.service),
+            );
+        }
+    }
+}
+
+// Run all configuration checks. The system interface check is skipped when
+// system is None, for example when validating a configuration for another host.
+pub fn validate(config_items: &ConfigurationItems, system: Option<&Vec<String>>) -> ValidationReport {
+    let mut report = ValidationReport::default();
+    check_interfaces(config_items, system, &mut report);
+    check_addresses(config_items, &mut report);
+    check_filter_rules(config_items, &mut report);
+    check_nat_rules(config_items, &config_items.snat, "SNAT", &mut report);
+    check_nat_rules(config_items, &config_items.dnat, "DNAT", &mut report);
+    check_flow_tests(config_items, &mut report);
+    report.valid = report.errors.len() == 0;
+    return report;
+}
+
+// Parse and validate the json of a configuration
+pub fn validate_json(json: &String, system: Option<&Vec<String>>) -> (Option<ConfigurationItems>, ValidationReport) {
+    match serde_json::from_str::<ConfigurationItems>(json) {
+        Ok(config_items) => {
+            let report = validate(&config_items, system);
+            return (Some(config_items), report);
+        }
+        Err(e) => {
+            let mut report = ValidationReport::default();
+            report.error(
+                IssueCode::InvalidJson,
+                String::from(""),
+                format!("Invalid configuration: {}", e),
+            );
+            return (None, report);
+        }
+    }
+}
+
+pub async fn validate_configuration(
+    session: Session,
+    State(state): State<Arc<AppState>>,
+    extract::Json(payload): extract::Json<Configuration>,
+) -> Result<Json<ValidationReport>, (StatusCode, String)> {
+    if !check_session(session, &state).await {
+        return Err((StatusCode::UNAUTHORIZED, String::from("Not logged in")));
+    }
+    let system = system_interfaces();
+    let (_config_items, report) = validate_json(&payload.json, Some(&system));
+    return Ok(Json(report));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdata::{self, rule};
+
+    fn codes(issues: &Vec<Issue>) -> Vec<(IssueCode, &str)> {
+        return issues.iter().map(|i| (i.code, i.path.as_str())).collect();
+    }
+
+    #[test]
+    fn valid_configuration() {
+        let config_items = testdata::configuration();
+        let system = vec![String::from("eth0"), String::from("eth1")];
+        let report = validate(&config_items, Some(&system));
+        assert!(report.valid);
+        assert_eq!(report.errors.len(), 0);
+    }
+
+    #[test]
+    fn errors_point_at_the_value() {
+        let mut config_items = testdata::configuration();
+        config_items.filters.filtertables[0]
+            .rules
+            .push(rule(&["nas"], &["web"], &["smb"], "accept"));
+        config_items.chains.get_mut("lan_dmz").unwrap().iface_out = String::from("wan");
+        let system = vec![String::from("eth0")];
+        let report = validate(&config_items, Some(&system));
+        assert!(!report.valid);
+        let errors = codes(&report.errors);
+        for expected in [
+            (IssueCode::UnknownSystemInterface, "interfaces.dmz.systemname"),
+            (IssueCode::UndefinedInterface, "chains.lan_dmz.iface_out"),
+            (IssueCode::UndefinedAddress, "filters.filtertables[0].rules[1].source[0]"),
+            (IssueCode::UndefinedService, "filters.filtertables[0].rules[1].destinationservice[0]"),
+        ] {
+            assert!(errors.contains(&expected), "missing {}", expected.1);
+        }
+
+        // the system interfaces are not checked without a system
+        let report = validate(&config_items, None);
+        assert!(!codes(&report.errors).iter().any(|(c, _p)| *c == IssueCode::UnknownSystemInterface));
+    }
+
+    #[test]
+    fn invalid_json() {
+        let (config_items, report) = validate_json(&String::from("{"), None);
+        assert!(config_items.is_none());
+        assert!(!report.valid);
+        assert!(codes(&report.errors) == [(IssueCode::InvalidJson, "")]);
+    }
+}